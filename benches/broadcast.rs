@@ -0,0 +1,88 @@
+//! Broadcast cost at 10k subscribers, run with `cargo bench --bench
+//! broadcast`.
+//!
+//! Each round broadcasts one message and has every recipient put its frame
+//! in an output buffer, either encoding its own copy of the message as it
+//! used to or sharing the one frame encoded for the broadcast.
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use wocket::codec::{self, opcode};
+use wocket::connections::{Connections, Instruction, Outgoing, Registration, Selector};
+use wocket::testing::PEER;
+use wocket::ulid::Ulid;
+
+const SUBSCRIBERS: usize = 10_000;
+const ROUNDS: usize = 50;
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let connections = Arc::new(Connections::default());
+    let mut subscribers: Vec<_> = (0..SUBSCRIBERS)
+        .map(|_| connections.register(PEER, Ulid::new()))
+        .collect();
+    for subscriber in &subscribers {
+        connections.label(subscriber.id(), "room=lobby").unwrap();
+    }
+    let lobby = Selector::parse("room=lobby").unwrap();
+
+    for size in [64, 1 << 10, 16 << 10] {
+        let message = vec![7; size];
+
+        let mut round = |share: bool| {
+            runtime.block_on(async {
+                let start = Instant::now();
+                for _ in 0..ROUNDS {
+                    assert_eq!(connections.broadcast(&lobby, &message), SUBSCRIBERS);
+                    deliver(&mut subscribers, share).await;
+                }
+                start.elapsed() / ROUNDS as u32
+            })
+        };
+
+        round(true);
+        let copied = round(false);
+        let shared = round(true);
+        report(size, copied, shared);
+    }
+}
+
+/// Takes the broadcast from every subscriber's outbox and writes its frame
+/// to an output buffer, like a connection would.
+async fn deliver(subscribers: &mut [Registration], share: bool) {
+    let mut output = Vec::new();
+
+    for subscriber in subscribers {
+        let Instruction::Send(outgoing) = subscriber.instruction().await else {
+            unreachable!("nothing kicks the subscribers");
+        };
+
+        output.clear();
+        if share {
+            output.extend_from_slice(outgoing.frame());
+        } else {
+            encode(&outgoing, &mut output);
+        }
+        black_box(&output);
+    }
+}
+
+/// A frame from the recipient's own copy of the message.
+fn encode(outgoing: &Outgoing, output: &mut Vec<u8>) {
+    let message = outgoing.message().to_vec();
+    codec::write_frame(true, opcode::BINARY, &message, None, output);
+}
+
+fn report(size: usize, copied: Duration, shared: Duration) {
+    println!(
+        "{size:>6} bytes to {SUBSCRIBERS}  copied {:>9.2?}  shared {:>9.2?}  ({:+.0}%)",
+        copied,
+        shared,
+        (copied.as_secs_f64() / shared.as_secs_f64() - 1.0) * 100.0,
+    );
+}
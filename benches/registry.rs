@@ -0,0 +1,56 @@
+//! Registry churn with 100k connections open, run with `cargo bench --bench
+//! registry`.
+//!
+//! Several threads open connections, send each a message and close them
+//! again as fast as they can, against the default sharded registry and
+//! against the same registry with a single shard, as it was before.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use wocket::connections::{Connections, SHARDS};
+use wocket::testing::PEER;
+use wocket::ulid::Ulid;
+
+const OPEN: usize = 100_000;
+const JOINS_PER_THREAD: usize = 50_000;
+
+fn main() {
+    let threads = thread::available_parallelism().map_or(4, |n| n.get().max(4));
+
+    for shards in [1, SHARDS] {
+        let connections = Arc::new(Connections::with_shards(shards));
+        let open: Vec<_> = (0..OPEN)
+            .map(|_| connections.register(PEER, Ulid::new()))
+            .collect();
+
+        let took = churn(threads, || {
+            let registration = connections.register(PEER, Ulid::new());
+            connections.send(registration.id(), b"welcome");
+        });
+
+        let joins = (threads * JOINS_PER_THREAD) as f64;
+        println!(
+            "{shards:>2} shards, {threads} threads, {OPEN} open  {:>9.0} joins/sec  ({took:.2?})",
+            joins / took.as_secs_f64(),
+        );
+        drop(open);
+    }
+}
+
+/// Runs `join` over and over on each of `threads` threads, and returns how
+/// long they took between them.
+fn churn(threads: usize, join: impl Fn() + Sync) -> Duration {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                for _ in 0..JOINS_PER_THREAD {
+                    join();
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
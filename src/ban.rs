@@ -0,0 +1,125 @@
+//! Banned peer addresses, checked as soon as a connection is accepted and
+//! kept in a `BanStore` so they survive restarts.
+
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::policy::{AcceptPolicy, Verdict};
+
+/// Where bans are kept between runs.
+pub trait BanStore: Send + Sync {
+    /// Every address banned so far.
+    fn load(&self) -> io::Result<Vec<IpAddr>>;
+
+    /// Records a new ban.
+    fn add(&self, ip: IpAddr) -> io::Result<()>;
+}
+
+/// Keeps bans in a file, one address per line. A missing file has no bans.
+pub struct BanFile(pub PathBuf);
+
+impl BanStore for BanFile {
+    fn load(&self) -> io::Result<Vec<IpAddr>> {
+        let contents = match fs::read_to_string(&self.0) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                line.parse().map_err(|_| {
+                    io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid address in {}: {line}", self.0.display()),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    fn add(&self, ip: IpAddr) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.0)?;
+        writeln!(file, "{ip}")
+    }
+}
+
+/// The banned addresses, as an accept policy that refuses them.
+pub struct Bans {
+    banned: RwLock<HashSet<IpAddr>>,
+    store: Option<Box<dyn BanStore>>,
+}
+
+impl Bans {
+    /// Starts with the bans already in `store`, if there is one.
+    pub fn new(store: Option<Box<dyn BanStore>>) -> io::Result<Self> {
+        let banned = match &store {
+            Some(store) => store.load()?,
+            None => vec![],
+        };
+
+        Ok(Bans {
+            banned: RwLock::new(banned.iter().map(IpAddr::to_canonical).collect()),
+            store,
+        })
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.read().unwrap().contains(&ip.to_canonical())
+    }
+
+    /// Bans `ip` and records it in the store. It stays banned for this run
+    /// even if the store fails.
+    pub fn ban(&self, ip: IpAddr) -> io::Result<()> {
+        let ip = ip.to_canonical();
+        if !self.banned.write().unwrap().insert(ip) {
+            return Ok(());
+        }
+
+        match &self.store {
+            Some(store) => store.add(ip),
+            None => Ok(()),
+        }
+    }
+}
+
+impl AcceptPolicy for Bans {
+    fn check(&self, peer: SocketAddr) -> Verdict {
+        if self.is_banned(peer.ip()) {
+            Verdict::Reject
+        } else {
+            Verdict::Accept
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("wocket-bans-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+
+        let bans = Bans::new(Some(Box::new(BanFile(path.clone())))).unwrap();
+        assert!(!bans.is_banned(ip));
+        bans.ban(ip).unwrap();
+        bans.ban(ip).unwrap();
+
+        let bans = Bans::new(Some(Box::new(BanFile(path.clone())))).unwrap();
+        assert!(bans.is_banned(ip));
+        assert!(bans.is_banned("::ffff:192.0.2.7".parse().unwrap()));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "192.0.2.7\n");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
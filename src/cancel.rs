@@ -0,0 +1,89 @@
+//! Cancellation tokens, for tying background work to a connection.
+//!
+//! Every registered connection has a token that is cancelled once its
+//! task ends, however it ends, so work spawned on its behalf with
+//! `CancellationToken::spawn` stops with it instead of having to be
+//! tracked and aborted by hand.
+
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Cancelled once, by whichever clone gets there first. Clones share it.
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    cancelled: Arc<watch::Sender<bool>>,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken {
+            cancelled: Arc::new(watch::Sender::new(false)),
+        }
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.cancelled.borrow()
+    }
+
+    /// Resolves once the token is cancelled, straight away if it already
+    /// is.
+    pub async fn cancelled(&self) {
+        // This token keeps the sender alive, so waiting can't fail
+        let _ = self.cancelled.subscribe().wait_for(|&on| on).await;
+    }
+
+    /// Runs `future` until it finishes or the token is cancelled, in which
+    /// case it is dropped and this returns `None`.
+    pub async fn run_until_cancelled<F: Future>(&self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            biased;
+            _ = self.cancelled() => None,
+            output = future => Some(output),
+        }
+    }
+
+    /// Spawns `future` on the runtime, to be dropped once the token is
+    /// cancelled. Has to be called from within the runtime.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        let token = self.clone();
+        tokio::spawn(async move { token.run_until_cancelled(future).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cancelling_stops_spawned_work() {
+        let token = CancellationToken::new();
+        let forever = token.spawn(std::future::pending::<()>());
+        let done = token.spawn(async { 7 });
+        assert_eq!(done.await.unwrap(), Some(7));
+
+        token.clone().cancel();
+        assert!(token.is_cancelled());
+        assert_eq!(forever.await.unwrap(), None);
+
+        // Later work doesn't start at all
+        let late = token.run_until_cancelled(async { 7 }).await;
+        assert_eq!(late, None);
+    }
+}
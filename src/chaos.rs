@@ -0,0 +1,177 @@
+//! Fault injection, for testing how clients cope with a misbehaving
+//! server.
+//!
+//! Each message the server is about to echo rolls for at most one fault:
+//! a random delay, being dropped, the connection being reset, being cut off
+//! part way through its frame, or having a byte of its frame corrupted.
+
+use std::time::Duration;
+
+use crate::codec::{MaskRng, SeededRng};
+
+/// How likely each fault is, per message. They add up to at most 1, and
+/// all zero turns fault injection off.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Faults {
+    pub delay: f64,
+    pub drop: f64,
+    pub reset: f64,
+    pub cut: f64,
+    pub corrupt: f64,
+}
+
+impl Faults {
+    /// Parses a comma separated list of `fault=probability`, e.g.
+    /// `delay=0.2,drop=0.05`. Faults left out never happen.
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let mut faults = Faults::default();
+
+        for entry in list
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let invalid = || format!("invalid fault: {entry}");
+
+            let (name, probability) = entry.split_once('=').ok_or_else(invalid)?;
+            let probability: f64 = probability.trim().parse().map_err(|_| invalid())?;
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(invalid());
+            }
+
+            let slot = match name.trim() {
+                "delay" => &mut faults.delay,
+                "drop" => &mut faults.drop,
+                "reset" => &mut faults.reset,
+                "cut" => &mut faults.cut,
+                "corrupt" => &mut faults.corrupt,
+                _ => return Err(invalid()),
+            };
+            *slot = probability;
+        }
+
+        if faults.total() > 1.0 {
+            return Err(format!("fault probabilities add up to more than 1: {list}"));
+        }
+        Ok(faults)
+    }
+
+    pub fn is_off(&self) -> bool {
+        self.total() == 0.0
+    }
+
+    fn total(&self) -> f64 {
+        self.delay + self.drop + self.reset + self.cut + self.corrupt
+    }
+}
+
+/// What to do to a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Send it after this long.
+    Delay(Duration),
+
+    /// Don't send it.
+    Drop,
+
+    /// Reset the connection instead of sending it.
+    Reset,
+
+    /// Send the first `n` bytes of its frame, then drop the connection.
+    Cut(usize),
+
+    /// Send its frame with the byte at this index XORed with the mask.
+    Corrupt(usize, u8),
+}
+
+/// Rolls for the faults on one connection's messages.
+#[derive(Debug)]
+pub struct Chaos {
+    faults: Faults,
+    max_delay: Duration,
+    rng: SeededRng,
+}
+
+impl Chaos {
+    /// Seeding with the same value gives the same faults in the same order.
+    pub fn new(faults: Faults, max_delay: Duration, seed: u64) -> Self {
+        Chaos {
+            faults,
+            max_delay,
+            rng: SeededRng::new(seed),
+        }
+    }
+
+    /// The fault for the next message, if any. `frame_len` is the length
+    /// of its encoded frame.
+    pub fn roll(&mut self, frame_len: usize) -> Option<Fault> {
+        let roll = self.unit();
+        let faults = self.faults;
+
+        let mut below = 0.0;
+        let mut hit = |probability: f64| {
+            below += probability;
+            roll < below
+        };
+
+        if hit(faults.delay) {
+            Some(Fault::Delay(self.max_delay.mul_f64(self.unit())))
+        } else if hit(faults.drop) {
+            Some(Fault::Drop)
+        } else if hit(faults.reset) {
+            Some(Fault::Reset)
+        } else if hit(faults.cut) {
+            Some(Fault::Cut(self.below(frame_len)))
+        } else if hit(faults.corrupt) {
+            let index = self.below(frame_len);
+            let mask = self.next() as u8 | 1;
+            Some(Fault::Corrupt(index, mask))
+        } else {
+            None
+        }
+    }
+
+    fn next(&mut self) -> u32 {
+        u32::from_be_bytes(self.rng.next_mask())
+    }
+
+    /// A number in `[0, 1)`.
+    fn unit(&mut self) -> f64 {
+        f64::from(self.next()) / (f64::from(u32::MAX) + 1.0)
+    }
+
+    /// A number in `[0, n)`, or 0 if `n` is.
+    fn below(&mut self, n: usize) -> usize {
+        (self.unit() * n as f64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faults_come_at_their_rates() {
+        assert!(Faults::parse("drop=0.7,reset=0.5").is_err());
+        assert!(Faults::parse("sneeze=0.1").is_err());
+        assert!(Faults::parse("").unwrap().is_off());
+
+        let faults = Faults::parse("delay=0.25, drop=0.25").unwrap();
+        let mut chaos = Chaos::new(faults, Duration::from_secs(1), 7);
+
+        let (mut delayed, mut dropped) = (0, 0);
+        for _ in 0..10_000 {
+            match chaos.roll(100) {
+                Some(Fault::Delay(delay)) => {
+                    assert!(delay < Duration::from_secs(1));
+                    delayed += 1;
+                }
+                Some(Fault::Drop) => dropped += 1,
+                None => {}
+                Some(other) => panic!("unexpected {other:?}"),
+            }
+        }
+        assert!((2_300..2_700).contains(&delayed), "{delayed}");
+        assert!((2_300..2_700).contains(&dropped), "{dropped}");
+    }
+}
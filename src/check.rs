@@ -0,0 +1,109 @@
+//! The `wocket check` self-test: everything the server does at startup
+//! that can fail, without serving anything, so a bad deploy fails in CI
+//! rather than when the server is restarted.
+
+use std::fmt;
+use std::net::TcpListener;
+
+use crate::config::Config;
+use crate::runtime;
+use crate::server::Server;
+
+/// The outcome of each step, in order. Steps after a failed one that
+/// needed it are left out.
+#[derive(Debug, Default)]
+pub struct Report {
+    results: Vec<(&'static str, Result<String, String>)>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|(_, result)| result.is_ok())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (step, result) in &self.results {
+            match result {
+                Ok(detail) => writeln!(f, "OK    {step}: {detail}")?,
+                Err(why) => writeln!(f, "FAIL  {step}: {why}")?,
+            }
+        }
+
+        match self.passed() {
+            true => write!(f, "\nReady to start"),
+            false => write!(f, "\nWould not start"),
+        }
+    }
+}
+
+/// Reads the config from the environment, and tries everything starting
+/// the server with it would do.
+pub fn run() -> Report {
+    let mut report = Report::default();
+
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            report.results.push(("config", Err(err)));
+            return report;
+        }
+    };
+    report.results.push(("config", Ok(String::from("valid"))));
+
+    // Loads the IP filter, blocked patterns, GeoIP table and bans
+    let files = Server::from_config(config.clone());
+    let files = files
+        .map(|_| String::from("loaded"))
+        .map_err(|err| err.to_string());
+    report.results.push(("files", files));
+
+    if let Some(root) = &config.static_root {
+        let root = match root.metadata() {
+            Ok(_) => Ok(root.display().to_string()),
+            Err(err) => Err(format!("{}: {err}", root.display())),
+        };
+        report.results.push(("static root", root));
+    }
+
+    let bind = if config.takeover {
+        Ok(String::from("skipped, the listener is taken over"))
+    } else if config.failover_role.is_some() {
+        Ok(String::from(
+            "skipped, the port is shared with the failover pair",
+        ))
+    } else {
+        match TcpListener::bind(&config.addr) {
+            Ok(_) => Ok(format!("{} is free", config.addr)),
+            Err(err) => Err(format!("{}: {err}", config.addr)),
+        }
+    };
+    report.results.push(("bind", bind));
+
+    let runtime = runtime::build(&config)
+        .map(|_| String::from("built"))
+        .map_err(|err| err.to_string());
+    report.results.push(("runtime", runtime));
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn any_failure_fails_the_check() {
+        let mut report = Report::default();
+        report.results.push(("config", Ok(String::from("valid"))));
+        assert!(report.passed());
+
+        report.results.push(("bind", Err(String::from("in use"))));
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_string(),
+            "OK    config: valid\nFAIL  bind: in use\n\nWould not start"
+        );
+    }
+}
@@ -0,0 +1,191 @@
+//! Close status codes: the ones RFC 6455 defines, and names applications
+//! register for their own codes, so logs and metrics don't show bare
+//! numbers.
+
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::sync::RwLock;
+
+/// Codes reserved for applications to define.
+pub const APPLICATION_CODES: RangeInclusive<u16> = 4000..=4999;
+
+/// Whether an endpoint may send `code` in a close frame. RFC 6455 keeps
+/// some codes for reporting a close locally, and leaves others unassigned.
+pub fn can_send(code: u16) -> bool {
+    crate::codec::is_valid_close_code(code)
+}
+
+/// A close status code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    Normal,
+    GoingAway,
+    ProtocolError,
+    UnsupportedData,
+
+    /// The close frame had no status code. Never sent.
+    NoStatus,
+
+    /// The connection ended without a close frame. Never sent.
+    Abnormal,
+
+    InvalidPayload,
+    PolicyViolation,
+    MessageTooBig,
+    MandatoryExtension,
+    InternalError,
+    TryAgainLater,
+
+    /// One of the `APPLICATION_CODES`.
+    Application(u16),
+
+    /// Anything else, e.g. codes registered with IANA after RFC 6455.
+    Other(u16),
+}
+
+impl CloseCode {
+    /// A short snake_case name for the codes RFC 6455 defines, e.g. for
+    /// metric labels.
+    pub fn name(self) -> Option<&'static str> {
+        Some(match self {
+            CloseCode::Normal => "normal",
+            CloseCode::GoingAway => "going_away",
+            CloseCode::ProtocolError => "protocol_error",
+            CloseCode::UnsupportedData => "unsupported_data",
+            CloseCode::NoStatus => "no_status",
+            CloseCode::Abnormal => "abnormal",
+            CloseCode::InvalidPayload => "invalid_payload",
+            CloseCode::PolicyViolation => "policy_violation",
+            CloseCode::MessageTooBig => "message_too_big",
+            CloseCode::MandatoryExtension => "mandatory_extension",
+            CloseCode::InternalError => "internal_error",
+            CloseCode::TryAgainLater => "try_again_later",
+            CloseCode::Application(_) | CloseCode::Other(_) => return None,
+        })
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::UnsupportedData,
+            1005 => CloseCode::NoStatus,
+            1006 => CloseCode::Abnormal,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1010 => CloseCode::MandatoryExtension,
+            1011 => CloseCode::InternalError,
+            1013 => CloseCode::TryAgainLater,
+            code if APPLICATION_CODES.contains(&code) => CloseCode::Application(code),
+            code => CloseCode::Other(code),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> Self {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::UnsupportedData => 1003,
+            CloseCode::NoStatus => 1005,
+            CloseCode::Abnormal => 1006,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::MandatoryExtension => 1010,
+            CloseCode::InternalError => 1011,
+            CloseCode::TryAgainLater => 1013,
+            CloseCode::Application(code) | CloseCode::Other(code) => code,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Registered {
+    name: String,
+    description: String,
+}
+
+/// Names and descriptions of application close codes.
+#[derive(Debug, Default)]
+pub struct CloseCodes {
+    registered: RwLock<BTreeMap<u16, Registered>>,
+}
+
+impl CloseCodes {
+    /// Names an application code. Fails if `code` isn't one of the
+    /// `APPLICATION_CODES`, or already has a name.
+    pub fn register(&self, code: u16, name: &str, description: &str) -> Result<(), String> {
+        if !APPLICATION_CODES.contains(&code) {
+            return Err(format!("{code} isn't an application close code"));
+        }
+
+        let mut registered = self.registered.write().unwrap();
+        if let Some(existing) = registered.get(&code) {
+            return Err(format!("{code} is already registered as {}", existing.name));
+        }
+
+        registered.insert(
+            code,
+            Registered {
+                name: String::from(name),
+                description: String::from(description),
+            },
+        );
+        Ok(())
+    }
+
+    /// The name of `code`: the RFC's name for standard codes, the
+    /// registered one for application codes, and otherwise the number.
+    pub fn name(&self, code: u16) -> String {
+        if let Some(name) = CloseCode::from(code).name() {
+            return String::from(name);
+        }
+
+        match self.registered.read().unwrap().get(&code) {
+            Some(registered) => registered.name.clone(),
+            None => code.to_string(),
+        }
+    }
+
+    /// What a registered application code means.
+    pub fn description(&self, code: u16) -> Option<String> {
+        let registered = self.registered.read().unwrap();
+        registered.get(&code).map(|code| code.description.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_for_standard_and_registered_codes() {
+        let codes = CloseCodes::default();
+        codes
+            .register(4401, "credentials_expired", "the client's token expired")
+            .unwrap();
+
+        assert!(codes.register(4401, "again", "").is_err());
+        assert!(codes.register(1000, "mine", "").is_err());
+
+        assert_eq!(codes.name(1008), "policy_violation");
+        assert_eq!(codes.name(4401), "credentials_expired");
+        assert_eq!(codes.name(4402), "4402");
+        assert_eq!(
+            codes.description(4401).as_deref(),
+            Some("the client's token expired")
+        );
+
+        assert_eq!(CloseCode::from(4401), CloseCode::Application(4401));
+        assert_eq!(u16::from(CloseCode::TryAgainLater), 1013);
+        assert!(can_send(1011) && can_send(4000));
+        assert!(!can_send(1006) && !can_send(2000));
+    }
+}
@@ -0,0 +1,777 @@
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::chaos::Faults;
+use crate::close;
+use crate::codec::OversizedControl;
+use crate::connections::OUTBOX_CAPACITY;
+use crate::inspect::{OnDeadline, OnMatch};
+use crate::ipfilter::{Cidr, IpFilter};
+use crate::metrics::Label;
+use crate::recover::ErrorResponse;
+
+/// Settings for the server, shared by every connection.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address the listener binds to.
+    pub addr: String,
+
+    /// Unix socket the running server hands its listener over on, see
+    /// `handoff`.
+    pub handoff_socket: Option<PathBuf>,
+
+    /// Take the listener from the server running at `handoff_socket`
+    /// instead of binding `addr`.
+    pub takeover: bool,
+
+    /// Which half of a failover pair this server is, if it is one, and the
+    /// Unix socket the primary sends heartbeats on, see `failover`.
+    pub failover_role: Option<FailoverRole>,
+    pub failover_socket: Option<PathBuf>,
+
+    /// How often the primary sends a heartbeat, and how long the standby
+    /// goes without one before it takes over.
+    pub failover_heartbeat: Duration,
+    pub failover_after: Duration,
+
+    /// Initial size in bytes of a connection's read buffer. Pooled buffers
+    /// are allocated with this capacity.
+    pub read_buffer_size: usize,
+
+    /// How the read buffer grows when a frame doesn't fit in it.
+    pub read_buffer_growth: Growth,
+
+    /// Most bytes a connection may buffer while waiting for the rest of a
+    /// frame, and the largest message it may send after reassembling
+    /// fragments. Connections that go over are closed.
+    pub max_buffered_bytes: usize,
+
+    /// Maximum number of idle buffers kept around for reuse.
+    pub pool_capacity: usize,
+
+    /// Outgoing frames are batched into a single write until the batch
+    /// reaches this many bytes.
+    pub write_batch_size: usize,
+
+    /// Longest a batched frame may wait for more frames to join it before
+    /// being written. Zero writes everything produced by a read right away.
+    pub write_batch_latency: Duration,
+
+    /// Most bytes per second written to each connection. Zero means no
+    /// limit.
+    pub max_send_rate: usize,
+
+    /// Most bytes written to a connection at once under `max_send_rate`,
+    /// and how far ahead of the rate an idle connection can get.
+    pub send_burst: usize,
+
+    /// A connection lets other tasks on its worker run after handling this
+    /// many frames in a row. Zero never yields early.
+    pub frames_per_yield: usize,
+
+    /// Runtime worker threads. Zero starts one per core.
+    pub worker_threads: usize,
+
+    /// Most threads the runtime starts for blocking work, on top of the
+    /// workers.
+    pub max_blocking_threads: usize,
+
+    /// Name given to every runtime thread.
+    pub thread_name: String,
+
+    /// Pin each worker thread to its own core. Only on Linux.
+    pub pin_workers: bool,
+
+    /// Print the size of every message received and sent.
+    pub log_messages: bool,
+
+    /// Of each connection's messages, print one in this many, and at most
+    /// `log_max_per_sec` a second. 0 doesn't cap them.
+    pub log_sample_every: u64,
+    pub log_max_per_sec: u64,
+
+    /// Whether pings are answered straight away. Without that, interceptors
+    /// see each ping and decide what goes in the pong.
+    pub auto_pong: bool,
+
+    /// What happens to pings and pongs longer than 125 bytes.
+    pub oversized_control: OversizedControl,
+
+    /// Peers that may connect. Empty allows everyone not denied.
+    pub ip_allow: Vec<Cidr>,
+
+    /// Peers that are refused as soon as they connect.
+    pub ip_deny: Vec<Cidr>,
+
+    /// File with more allow/deny rules, see `IpFilter::load`.
+    pub ip_filter_file: Option<String>,
+
+    /// How often to check `ip_filter_file` for changes. Zero turns reloading
+    /// off.
+    pub ip_filter_reload: Duration,
+
+    /// CSV table of `<cidr>,<country>` ranges used to tag peers with their
+    /// country, see `GeoTable`.
+    pub geoip_table: Option<String>,
+
+    /// Country codes whose peers are refused. Needs `geoip_table`.
+    pub blocked_countries: Vec<String>,
+
+    /// File of byte patterns, one per line, that inbound messages are
+    /// scanned for.
+    pub blocked_patterns: Option<String>,
+
+    /// Key messages are signed with in both directions. Inbound messages
+    /// without a valid signature are dropped.
+    pub envelope_key: Option<Vec<u8>>,
+
+    /// What happens to a message containing a blocked pattern.
+    pub on_blocked_pattern: OnMatch,
+
+    /// How long the inspectors may take over one message, and what happens
+    /// to it if they take longer. Zero means no deadline.
+    pub inspect_deadline: Duration,
+    pub on_inspect_deadline: OnDeadline,
+
+    /// File or directory served to plain GET requests that don't ask for a
+    /// WebSocket. Without one, they get a 426 Upgrade Required.
+    pub static_root: Option<PathBuf>,
+
+    /// Whether connections stay open after answering a plain GET, for the
+    /// client's next request, unless it asks for them to be closed.
+    pub http_keep_alive: bool,
+
+    /// Extra headers added to the 101 response of every accepted upgrade.
+    pub response_headers: Vec<(String, String)>,
+
+    /// Whether the 101 response repeats the client's `traceparent`.
+    pub echo_trace_context: bool,
+
+    /// Paths WebSocket connections may be opened on. Empty allows any path.
+    pub paths: Vec<String>,
+
+    /// Origins allowed to open connections. Empty allows any origin.
+    pub allowed_origins: Vec<String>,
+
+    /// How likely each message echoed is to be hit by a fault, for testing
+    /// clients against a misbehaving server. All zero by default.
+    pub chaos: Faults,
+
+    /// Longest delay a `delay` fault adds.
+    pub chaos_max_delay: Duration,
+
+    /// Seeds the faults, so a run can be repeated.
+    pub chaos_seed: u64,
+
+    /// Subprotocols the server speaks, in order of preference.
+    pub subprotocols: Vec<String>,
+
+    /// Whether clients can ask, with the `wocket.batch.v1` subprotocol, for
+    /// the messages echoed from one read to be packed into one batch.
+    pub message_batching: bool,
+
+    /// File banned addresses are kept in, one per line, so bans survive
+    /// restarts.
+    pub ban_file: Option<PathBuf>,
+
+    /// Most WebSocket connections open at once. Upgrades over the limit are
+    /// turned away with a 503. Zero means no limit.
+    pub max_connections: usize,
+
+    /// Most WebSocket connections open at once on particular paths, on top
+    /// of `max_connections`. Upgrades over a path's limit get a 503.
+    pub route_limits: Vec<(String, usize)>,
+
+    /// Most bytes all connections may buffer between them. Near the cap
+    /// upgrades get a 503, and over it the connections buffering the most
+    /// are closed. Zero means no limit.
+    pub max_memory: usize,
+
+    /// Resident memory the process should stay under, in bytes. Getting
+    /// close to it defers upgrades, then shrinks buffers, then closes
+    /// connections idle for `idle_close_after`. Zero means no limit. Only
+    /// on Linux.
+    pub soft_memory_limit: usize,
+    pub idle_close_after: Duration,
+
+    /// How long clients turned away by `max_connections`, `route_limits`,
+    /// `max_memory` or `soft_memory_limit` are told to wait.
+    pub retry_after: Duration,
+
+    /// What clients see when their connection's task panics.
+    pub internal_error: ErrorResponse,
+
+    /// Path plain GET requests can probe for readiness, e.g. from a load
+    /// balancer. It answers 200, or 503 while draining.
+    pub ready_path: Option<String>,
+
+    /// Path plain GET requests can fetch metrics from, in the Prometheus
+    /// text format.
+    pub metrics_path: Option<String>,
+
+    /// What closed connections are counted by in the metrics.
+    pub metric_labels: Vec<Label>,
+
+    /// Most combinations of `metric_labels` counted separately. Closes
+    /// with a new combination past this are counted under `other`.
+    pub metric_max_series: usize,
+
+    /// Binary message sent to every open connection when draining starts,
+    /// e.g. to tell clients to reconnect elsewhere.
+    pub drain_message: Option<Vec<u8>>,
+
+    /// How long connections stay open after draining starts before they
+    /// are closed with 1001 (Going Away).
+    pub drain_timeout: Duration,
+
+    /// How long open connections get to finish the close handshake after
+    /// SIGTERM or SIGINT before the server exits anyway.
+    pub shutdown_timeout: Duration,
+
+    /// Most new TCP connections accepted per second, so a reconnect storm
+    /// after a restart is let in gradually. Zero means no limit.
+    pub max_accept_rate: usize,
+
+    /// Most connections accepted at once under `max_accept_rate`.
+    pub accept_burst: usize,
+
+    /// When connections are closed for a shutdown or a drain, each is first
+    /// sent `retry-after=<secs>`, with the delays spread evenly over this
+    /// long, so clients that honour it don't all reconnect at once. Zero
+    /// sends nothing.
+    pub reconnect_spread: Duration,
+
+    /// A connection with more than this many messages waiting in its
+    /// outbox for `slow_consumer_after` is reported as a slow consumer.
+    /// Zero never reports any.
+    pub slow_consumer_depth: usize,
+
+    pub slow_consumer_after: Duration,
+
+    /// How often each connection is pinged to measure its round-trip time.
+    /// Zero never pings.
+    pub rtt_interval: Duration,
+}
+
+/// Which half of a failover pair a server is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverRole {
+    Primary,
+
+    /// Refuses upgrades until the primary stops sending heartbeats.
+    Standby,
+}
+
+/// Growth policy for a connection's read buffer.
+#[derive(Debug, Clone, Copy)]
+pub enum Growth {
+    /// Double the buffer each time it fills up.
+    Double,
+
+    /// Grow the buffer by a fixed number of bytes each time it fills up.
+    Linear(usize),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addr: String::from("127.0.0.1:8080"),
+            handoff_socket: None,
+            takeover: false,
+            failover_role: None,
+            failover_socket: None,
+            failover_heartbeat: Duration::from_millis(500),
+            failover_after: Duration::from_secs(2),
+            read_buffer_size: 1024,
+            read_buffer_growth: Growth::Double,
+            max_buffered_bytes: 1 << 20,
+            pool_capacity: 1024,
+            write_batch_size: 64 * 1024,
+            write_batch_latency: Duration::ZERO,
+            max_send_rate: 0,
+            send_burst: 64 * 1024,
+            frames_per_yield: 64,
+            worker_threads: 0,
+            max_blocking_threads: 512,
+            thread_name: String::from("tokio-runtime-worker"),
+            pin_workers: false,
+            log_messages: false,
+            log_sample_every: 1,
+            log_max_per_sec: 0,
+            auto_pong: true,
+            oversized_control: OversizedControl::Reject,
+            ip_allow: Vec::new(),
+            ip_deny: Vec::new(),
+            ip_filter_file: None,
+            ip_filter_reload: Duration::ZERO,
+            geoip_table: None,
+            blocked_countries: Vec::new(),
+            blocked_patterns: None,
+            envelope_key: None,
+            on_blocked_pattern: OnMatch::Close,
+            inspect_deadline: Duration::ZERO,
+            on_inspect_deadline: OnDeadline::Close,
+            static_root: None,
+            http_keep_alive: false,
+            response_headers: Vec::new(),
+            echo_trace_context: false,
+            paths: Vec::new(),
+            allowed_origins: Vec::new(),
+            chaos: Faults::default(),
+            chaos_max_delay: Duration::from_secs(1),
+            chaos_seed: 0,
+            subprotocols: Vec::new(),
+            message_batching: false,
+            ban_file: None,
+            max_connections: 0,
+            route_limits: Vec::new(),
+            max_memory: 0,
+            soft_memory_limit: 0,
+            idle_close_after: Duration::from_secs(30),
+            retry_after: Duration::from_secs(5),
+            internal_error: ErrorResponse::default(),
+            ready_path: None,
+            metrics_path: None,
+            metric_labels: vec![Label::Code],
+            metric_max_series: 100,
+            drain_message: None,
+            drain_timeout: Duration::from_secs(30),
+            shutdown_timeout: Duration::from_secs(10),
+            max_accept_rate: 0,
+            accept_burst: 100,
+            reconnect_spread: Duration::ZERO,
+            slow_consumer_depth: 0,
+            slow_consumer_after: Duration::from_secs(10),
+            rtt_interval: Duration::ZERO,
+        }
+    }
+}
+
+impl Config {
+    /// Builds a config from the defaults, overridden by any `WOCKET_*`
+    /// environment variables that are set.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = Config::default();
+
+        if let Ok(addr) = env::var("WOCKET_ADDR") {
+            config.addr = addr;
+        }
+
+        if let Ok(path) = env::var("WOCKET_HANDOFF_SOCKET") {
+            config.handoff_socket = Some(PathBuf::from(path));
+        }
+
+        if let Some(takeover) = parse_var("WOCKET_TAKEOVER")? {
+            config.takeover = takeover;
+        }
+
+        if config.takeover && config.handoff_socket.is_none() {
+            return Err(String::from("WOCKET_TAKEOVER needs WOCKET_HANDOFF_SOCKET"));
+        }
+
+        if let Ok(role) = env::var("WOCKET_FAILOVER_ROLE") {
+            config.failover_role = match role.as_str() {
+                "primary" => Some(FailoverRole::Primary),
+                "standby" => Some(FailoverRole::Standby),
+                _ => return Err(format!("invalid WOCKET_FAILOVER_ROLE: {role}")),
+            };
+        }
+
+        if let Ok(path) = env::var("WOCKET_FAILOVER_SOCKET") {
+            config.failover_socket = Some(PathBuf::from(path));
+        }
+
+        if let Some(ms) = parse_var("WOCKET_FAILOVER_HEARTBEAT_MS")? {
+            config.failover_heartbeat = Duration::from_millis(ms);
+        }
+
+        if let Some(ms) = parse_var("WOCKET_FAILOVER_AFTER_MS")? {
+            config.failover_after = Duration::from_millis(ms);
+        }
+
+        if config.failover_role.is_some() {
+            if config.failover_socket.is_none() {
+                return Err(String::from(
+                    "WOCKET_FAILOVER_ROLE needs WOCKET_FAILOVER_SOCKET",
+                ));
+            }
+            if config.takeover {
+                return Err(String::from(
+                    "WOCKET_FAILOVER_ROLE can't be used with WOCKET_TAKEOVER",
+                ));
+            }
+            if config.failover_heartbeat.is_zero()
+                || config.failover_after <= config.failover_heartbeat
+            {
+                return Err(String::from(
+                    "WOCKET_FAILOVER_AFTER_MS must be longer than WOCKET_FAILOVER_HEARTBEAT_MS",
+                ));
+            }
+        }
+
+        if let Some(size) = parse_var("WOCKET_READ_BUFFER_SIZE")? {
+            config.read_buffer_size = size;
+        }
+
+        if let Ok(growth) = env::var("WOCKET_READ_BUFFER_GROWTH") {
+            config.read_buffer_growth = match growth.as_str() {
+                "double" => Growth::Double,
+                n => match n.parse() {
+                    Ok(n) if n > 0 => Growth::Linear(n),
+                    _ => return Err(format!("invalid WOCKET_READ_BUFFER_GROWTH: {growth}")),
+                },
+            };
+        }
+
+        if let Some(max) = parse_var("WOCKET_MAX_BUFFERED_BYTES")? {
+            config.max_buffered_bytes = max;
+        }
+
+        if let Some(capacity) = parse_var("WOCKET_POOL_CAPACITY")? {
+            config.pool_capacity = capacity;
+        }
+
+        if let Some(size) = parse_var("WOCKET_WRITE_BATCH_SIZE")? {
+            config.write_batch_size = size;
+        }
+
+        if let Some(ms) = parse_var("WOCKET_WRITE_BATCH_LATENCY_MS")? {
+            config.write_batch_latency = Duration::from_millis(ms);
+        }
+
+        if let Some(rate) = parse_var("WOCKET_MAX_SEND_RATE")? {
+            config.max_send_rate = rate;
+        }
+
+        if let Some(burst) = parse_var("WOCKET_SEND_BURST")? {
+            if burst == 0 {
+                return Err(String::from("WOCKET_SEND_BURST must be at least 1"));
+            }
+            config.send_burst = burst;
+        }
+
+        if let Some(frames) = parse_var("WOCKET_FRAMES_PER_YIELD")? {
+            config.frames_per_yield = frames;
+        }
+
+        if let Some(threads) = parse_var("WOCKET_WORKER_THREADS")? {
+            config.worker_threads = threads;
+        }
+
+        if let Some(threads) = parse_var::<usize>("WOCKET_MAX_BLOCKING_THREADS")? {
+            if threads == 0 {
+                return Err(String::from(
+                    "WOCKET_MAX_BLOCKING_THREADS must be at least 1",
+                ));
+            }
+            config.max_blocking_threads = threads;
+        }
+
+        if let Ok(name) = env::var("WOCKET_THREAD_NAME") {
+            config.thread_name = name;
+        }
+
+        if let Some(pin) = parse_var("WOCKET_PIN_WORKERS")? {
+            config.pin_workers = pin;
+        }
+
+        if let Some(log) = parse_var("WOCKET_LOG_MESSAGES")? {
+            config.log_messages = log;
+        }
+
+        if let Some(every) = parse_var("WOCKET_LOG_SAMPLE_EVERY")? {
+            if every == 0 {
+                return Err(String::from("WOCKET_LOG_SAMPLE_EVERY must be at least 1"));
+            }
+            config.log_sample_every = every;
+        }
+
+        if let Some(max) = parse_var("WOCKET_LOG_MAX_PER_SEC")? {
+            config.log_max_per_sec = max;
+        }
+
+        if let Some(auto_pong) = parse_var("WOCKET_AUTO_PONG")? {
+            config.auto_pong = auto_pong;
+        }
+
+        if let Ok(policy) = env::var("WOCKET_OVERSIZED_CONTROL") {
+            let invalid = || format!("invalid WOCKET_OVERSIZED_CONTROL: {policy}");
+            let max = |max: &str| max.parse().map_err(|_| invalid());
+
+            config.oversized_control = match policy.split_once('=') {
+                None if policy == "reject" => OversizedControl::Reject,
+                Some(("truncate", bytes)) => OversizedControl::Truncate(max(bytes)?),
+                Some(("allow", bytes)) => OversizedControl::Allow(max(bytes)?),
+                _ => return Err(invalid()),
+            };
+        }
+
+        if let Ok(list) = env::var("WOCKET_IP_ALLOW") {
+            config.ip_allow = IpFilter::parse_list(&list)?;
+        }
+
+        if let Ok(list) = env::var("WOCKET_IP_DENY") {
+            config.ip_deny = IpFilter::parse_list(&list)?;
+        }
+
+        if let Ok(path) = env::var("WOCKET_IP_FILTER_FILE") {
+            config.ip_filter_file = Some(path);
+        }
+
+        if let Some(secs) = parse_var("WOCKET_IP_FILTER_RELOAD_SECS")? {
+            config.ip_filter_reload = Duration::from_secs(secs);
+        }
+
+        if let Ok(path) = env::var("WOCKET_GEOIP_TABLE") {
+            config.geoip_table = Some(path);
+        }
+
+        if let Ok(list) = env::var("WOCKET_BLOCK_COUNTRIES") {
+            config.blocked_countries = parse_list(&list)
+                .map(|country| country.to_uppercase())
+                .collect();
+        }
+
+        if let Ok(path) = env::var("WOCKET_BLOCKED_PATTERNS") {
+            config.blocked_patterns = Some(path);
+        }
+
+        if let Ok(key) = env::var("WOCKET_ENVELOPE_KEY") {
+            config.envelope_key = Some(key.into_bytes());
+        }
+
+        if let Ok(action) = env::var("WOCKET_ON_BLOCKED_PATTERN") {
+            config.on_blocked_pattern = match action.as_str() {
+                "drop" => OnMatch::Drop,
+                "flag" => OnMatch::Flag,
+                "close" => OnMatch::Close,
+                _ => return Err(format!("invalid WOCKET_ON_BLOCKED_PATTERN: {action}")),
+            };
+        }
+
+        if let Some(ms) = parse_var("WOCKET_INSPECT_DEADLINE_MS")? {
+            config.inspect_deadline = Duration::from_millis(ms);
+        }
+
+        if let Ok(action) = env::var("WOCKET_ON_INSPECT_DEADLINE") {
+            config.on_inspect_deadline = match action.as_str() {
+                "busy" => OnDeadline::Busy,
+                "close" => OnDeadline::Close,
+                _ => return Err(format!("invalid WOCKET_ON_INSPECT_DEADLINE: {action}")),
+            };
+        }
+
+        if let Ok(path) = env::var("WOCKET_STATIC") {
+            config.static_root = Some(PathBuf::from(path));
+        }
+
+        if let Some(keep_alive) = parse_var("WOCKET_HTTP_KEEP_ALIVE")? {
+            config.http_keep_alive = keep_alive;
+        }
+
+        if let Ok(headers) = env::var("WOCKET_RESPONSE_HEADERS") {
+            config.response_headers = parse_headers(&headers)?;
+        }
+
+        if let Some(echo) = parse_var("WOCKET_ECHO_TRACE_CONTEXT")? {
+            config.echo_trace_context = echo;
+        }
+
+        if let Ok(list) = env::var("WOCKET_PATHS") {
+            config.paths = parse_list(&list).collect();
+        }
+
+        if let Ok(list) = env::var("WOCKET_ALLOWED_ORIGINS") {
+            config.allowed_origins = parse_list(&list).collect();
+        }
+
+        if let Ok(list) = env::var("WOCKET_CHAOS") {
+            config.chaos = Faults::parse(&list)?;
+        }
+
+        if let Some(ms) = parse_var("WOCKET_CHAOS_MAX_DELAY_MS")? {
+            config.chaos_max_delay = Duration::from_millis(ms);
+        }
+
+        if let Some(seed) = parse_var("WOCKET_CHAOS_SEED")? {
+            config.chaos_seed = seed;
+        }
+
+        if let Ok(list) = env::var("WOCKET_SUBPROTOCOLS") {
+            config.subprotocols = parse_list(&list).collect();
+        }
+
+        if let Some(batching) = parse_var("WOCKET_MESSAGE_BATCHING")? {
+            config.message_batching = batching;
+        }
+
+        if let Ok(path) = env::var("WOCKET_BAN_FILE") {
+            config.ban_file = Some(PathBuf::from(path));
+        }
+
+        if let Some(max) = parse_var("WOCKET_MAX_CONNECTIONS")? {
+            config.max_connections = max;
+        }
+
+        if let Ok(list) = env::var("WOCKET_ROUTE_LIMITS") {
+            config.route_limits = parse_list(&list)
+                .map(|limit| match limit.split_once('=') {
+                    Some((path, max)) => match max.trim().parse() {
+                        Ok(max) => Ok((String::from(path.trim()), max)),
+                        Err(_) => Err(format!("invalid limit in WOCKET_ROUTE_LIMITS: {limit}")),
+                    },
+                    None => Err(format!("invalid limit in WOCKET_ROUTE_LIMITS: {limit}")),
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        if let Some(max) = parse_var("WOCKET_MAX_MEMORY")? {
+            config.max_memory = max;
+        }
+
+        if let Some(limit) = parse_var("WOCKET_SOFT_MEMORY_LIMIT")? {
+            config.soft_memory_limit = limit;
+        }
+
+        if let Some(secs) = parse_var("WOCKET_IDLE_CLOSE_SECS")? {
+            config.idle_close_after = Duration::from_secs(secs);
+        }
+
+        if let Some(secs) = parse_var("WOCKET_RETRY_AFTER_SECS")? {
+            config.retry_after = Duration::from_secs(secs);
+        }
+
+        if let Some(code) = parse_var("WOCKET_INTERNAL_ERROR_CODE")? {
+            if !close::can_send(code) {
+                return Err(format!("WOCKET_INTERNAL_ERROR_CODE can't be sent: {code}"));
+            }
+            config.internal_error.code = code;
+        }
+
+        if let Ok(reason) = env::var("WOCKET_INTERNAL_ERROR_REASON") {
+            config.internal_error.reason = reason;
+        }
+
+        if let Ok(message) = env::var("WOCKET_INTERNAL_ERROR_MESSAGE") {
+            config.internal_error.message = Some(message).filter(|message| !message.is_empty());
+        }
+
+        if let Ok(path) = env::var("WOCKET_READY_PATH") {
+            config.ready_path = Some(path);
+        }
+
+        if let Ok(path) = env::var("WOCKET_METRICS_PATH") {
+            config.metrics_path = Some(path);
+        }
+
+        if let Ok(labels) = env::var("WOCKET_METRIC_LABELS") {
+            config.metric_labels = Label::parse_list(&labels)?;
+        }
+
+        if let Some(max) = parse_var("WOCKET_METRIC_MAX_SERIES")? {
+            config.metric_max_series = max;
+        }
+
+        if let Ok(message) = env::var("WOCKET_DRAIN_MESSAGE") {
+            config.drain_message = Some(message.into_bytes());
+        }
+
+        if let Some(secs) = parse_var("WOCKET_DRAIN_TIMEOUT_SECS")? {
+            config.drain_timeout = Duration::from_secs(secs);
+        }
+
+        if let Some(secs) = parse_var("WOCKET_SHUTDOWN_TIMEOUT_SECS")? {
+            config.shutdown_timeout = Duration::from_secs(secs);
+        }
+
+        if let Some(rate) = parse_var("WOCKET_MAX_ACCEPT_RATE")? {
+            config.max_accept_rate = rate;
+        }
+
+        if let Some(burst) = parse_var::<usize>("WOCKET_ACCEPT_BURST")? {
+            if burst == 0 {
+                return Err(String::from("WOCKET_ACCEPT_BURST must be at least 1"));
+            }
+            config.accept_burst = burst;
+        }
+
+        if let Some(secs) = parse_var("WOCKET_RECONNECT_SPREAD_SECS")? {
+            config.reconnect_spread = Duration::from_secs(secs);
+        }
+
+        if let Some(depth) = parse_var::<usize>("WOCKET_SLOW_CONSUMER_DEPTH")? {
+            if depth >= OUTBOX_CAPACITY {
+                return Err(format!(
+                    "WOCKET_SLOW_CONSUMER_DEPTH must be less than {OUTBOX_CAPACITY}"
+                ));
+            }
+            config.slow_consumer_depth = depth;
+        }
+
+        if let Some(secs) = parse_var("WOCKET_SLOW_CONSUMER_SECS")? {
+            config.slow_consumer_after = Duration::from_secs(secs);
+        }
+
+        if let Some(secs) = parse_var("WOCKET_RTT_INTERVAL_SECS")? {
+            config.rtt_interval = Duration::from_secs(secs);
+        }
+
+        Ok(config)
+    }
+
+    /// Builds the IP filter from the configured lists plus the rules in
+    /// `ip_filter_file`, if there is one.
+    pub fn ip_filter(&self) -> Result<IpFilter, String> {
+        let mut filter = match &self.ip_filter_file {
+            Some(path) => IpFilter::load(path)?,
+            None => IpFilter::default(),
+        };
+
+        filter.allow.extend_from_slice(&self.ip_allow);
+        filter.deny.extend_from_slice(&self.ip_deny);
+
+        Ok(filter)
+    }
+}
+
+/// Splits a comma separated list, skipping empty items.
+fn parse_list(list: &str) -> impl Iterator<Item = String> + '_ {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+}
+
+/// Parses `Name: value` headers, one per line.
+fn parse_headers(headers: &str) -> Result<Vec<(String, String)>, String> {
+    let is_token = |name: &str| {
+        !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+    };
+
+    headers
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.split_once(':') {
+            Some((name, value)) if is_token(name.trim()) && !value.contains('\r') => {
+                Ok((String::from(name.trim()), String::from(value.trim())))
+            }
+            _ => Err(format!("invalid header in WOCKET_RESPONSE_HEADERS: {line}")),
+        })
+        .collect()
+}
+
+fn parse_var<T: FromStr>(name: &str) -> Result<Option<T>, String> {
+    match env::var(name) {
+        Err(_) => Ok(None),
+        Ok(value) => match value.parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(_) => Err(format!("invalid {name}: {value}")),
+        },
+    }
+}
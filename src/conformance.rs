@@ -0,0 +1,394 @@
+//! A battery of RFC 6455 edge cases to run against any echo server, for a
+//! quick check of how it handles the corners of the protocol.
+//!
+//! Each case opens its own connection with the `sync` client, writes frames
+//! straight to the socket, some of them deliberately broken, and checks
+//! what comes back. Servers should echo well-formed binary messages, and
+//! fail the connection on broken frames with the status code the RFC
+//! calls for. Dropping the connection without a close frame still fails
+//! it, but less politely, so that is reported as non-strict rather than a
+//! failure, the way Autobahn does.
+
+use std::fmt;
+use std::io::{ErrorKind, Write};
+use std::thread;
+use std::time::Duration;
+
+use crate::codec::{opcode, write_frame, write_frame_with_rsv};
+use crate::sync::{self, WebSocket};
+use crate::{Error, Message};
+
+/// Longest a case waits for each thing it expects from the server.
+pub const CASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The masking key on every frame the cases send.
+const MASK: Option<[u8; 4]> = Some([0x37, 0xfa, 0x21, 0x3d]);
+
+/// How a case turned out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+
+    /// The server failed the connection as it should, but dropped it rather
+    /// than sending a close frame.
+    NonStrict(String),
+
+    Fail(String),
+}
+
+/// The outcome of each case, in the order they ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub results: Vec<(&'static str, Outcome)>,
+}
+
+impl Report {
+    /// Whether no case failed outright.
+    pub fn passed(&self) -> bool {
+        !self
+            .results
+            .iter()
+            .any(|(_, outcome)| matches!(outcome, Outcome::Fail(_)))
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut counts = [0; 3];
+
+        for (name, outcome) in &self.results {
+            match outcome {
+                Outcome::Pass => {
+                    counts[0] += 1;
+                    writeln!(f, "PASS        {name}")?;
+                }
+                Outcome::NonStrict(why) => {
+                    counts[1] += 1;
+                    writeln!(f, "NON-STRICT  {name}: {why}")?;
+                }
+                Outcome::Fail(why) => {
+                    counts[2] += 1;
+                    writeln!(f, "FAIL        {name}: {why}")?;
+                }
+            }
+        }
+
+        let [passed, non_strict, failed] = counts;
+        write!(
+            f,
+            "\n{passed} passed, {non_strict} non-strict, {failed} failed"
+        )
+    }
+}
+
+/// What the server should do with a case's frames.
+enum Expect {
+    /// Send back these messages, in order.
+    Messages(Vec<Message>),
+
+    /// Fail the connection with one of these status codes.
+    Close(&'static [u16]),
+}
+
+struct Case {
+    name: &'static str,
+
+    /// Written one after another; a pause between them makes sure they
+    /// arrive in separate reads.
+    writes: Vec<Vec<u8>>,
+
+    expect: Expect,
+}
+
+/// Runs every case against the echo server at `url`, a `ws://` URL.
+pub fn run(url: &str) -> Report {
+    let results = cases()
+        .into_iter()
+        .map(|case| (case.name, run_case(url, &case)))
+        .collect();
+
+    Report { results }
+}
+
+fn run_case(url: &str, case: &Case) -> Outcome {
+    let mut socket = match sync::connect(url) {
+        Ok(socket) => socket,
+        Err(err) => return Outcome::Fail(format!("couldn't connect: {err}")),
+    };
+    if let Err(err) = socket.get_ref().set_read_timeout(Some(CASE_TIMEOUT)) {
+        return Outcome::Fail(format!("couldn't set a timeout: {err}"));
+    }
+
+    for (i, write) in case.writes.iter().enumerate() {
+        if i > 0 {
+            thread::sleep(Duration::from_millis(20));
+        }
+        if let Err(err) = socket.get_ref().write_all(write) {
+            return Outcome::Fail(format!("write failed: {err}"));
+        }
+    }
+
+    match &case.expect {
+        Expect::Messages(expected) => {
+            for message in expected {
+                match socket.read() {
+                    Ok(received) if received == *message => {}
+                    Ok(received) => {
+                        return Outcome::Fail(format!(
+                            "expected {}, got {}",
+                            describe(message),
+                            describe(&received)
+                        ))
+                    }
+                    Err(err) => return Outcome::Fail(read_failure(&err)),
+                }
+            }
+            let _ = socket.close(1000, "");
+            Outcome::Pass
+        }
+        Expect::Close(codes) => expect_close(&mut socket, codes),
+    }
+}
+
+fn expect_close(socket: &mut WebSocket, codes: &[u16]) -> Outcome {
+    loop {
+        match socket.read() {
+            Ok(Message::Close(Some(frame))) if codes.contains(&frame.code) => return Outcome::Pass,
+            Ok(Message::Close(frame)) => {
+                let code = frame.map_or(String::from("no status"), |frame| frame.code.to_string());
+                return Outcome::Fail(format!("closed with {code}, expected {codes:?}"));
+            }
+            // Pongs, or echoes of anything before the broken frame
+            Ok(_) => continue,
+            Err(Error::Io(err)) if err.kind() != ErrorKind::WouldBlock => {
+                return Outcome::NonStrict(format!("dropped the connection: {err}"));
+            }
+            Err(err) => return Outcome::Fail(read_failure(&err)),
+        }
+    }
+}
+
+fn read_failure(err: &Error) -> String {
+    match err {
+        Error::Io(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            format!("no answer within {CASE_TIMEOUT:?}")
+        }
+        err => err.to_string(),
+    }
+}
+
+fn describe(message: &Message) -> String {
+    match message {
+        Message::Binary(data) => format!("a {} byte binary message", data.len()),
+        Message::Text(text) => format!("a {} byte text message", text.len()),
+        Message::Ping(_) => String::from("a ping"),
+        Message::Pong(_) => String::from("a pong"),
+        Message::Close(_) => String::from("a close"),
+    }
+}
+
+fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::new();
+    write_frame(fin, opcode, payload, MASK, &mut frame);
+    frame
+}
+
+fn close_frame(payload: &[u8]) -> Vec<u8> {
+    frame(true, opcode::CLOSE, payload)
+}
+
+fn binary(data: &[u8]) -> Message {
+    Message::Binary(data.to_vec())
+}
+
+fn cases() -> Vec<Case> {
+    let hello = frame(true, opcode::BINARY, b"Hello");
+
+    let mut unmasked = Vec::new();
+    write_frame(true, opcode::BINARY, b"Hello", None, &mut unmasked);
+
+    let mut rsv = Vec::new();
+    write_frame_with_rsv(true, 0b100, opcode::BINARY, b"Hello", MASK, &mut rsv);
+
+    // A 64 bit length with the top bit set, which the RFC forbids
+    let mut huge = vec![0x82, 0xff];
+    huge.extend_from_slice(&u64::MAX.to_be_bytes());
+
+    // One TiB, a valid length no server should try to buffer
+    let mut too_big = vec![0x82, 0xff];
+    too_big.extend_from_slice(&(1u64 << 40).to_be_bytes());
+    too_big.extend_from_slice(&MASK.unwrap());
+
+    vec![
+        Case {
+            name: "binary message is echoed",
+            writes: vec![hello.clone()],
+            expect: Expect::Messages(vec![binary(b"Hello")]),
+        },
+        Case {
+            name: "empty message is echoed",
+            writes: vec![frame(true, opcode::BINARY, b"")],
+            expect: Expect::Messages(vec![binary(b"")]),
+        },
+        Case {
+            name: "64 KiB message is echoed",
+            writes: vec![frame(true, opcode::BINARY, &[0xfe; 65536])],
+            expect: Expect::Messages(vec![binary(&[0xfe; 65536])]),
+        },
+        Case {
+            name: "fragmented message is echoed whole",
+            writes: vec![
+                frame(false, opcode::BINARY, b"Hel"),
+                frame(false, opcode::CONTINUATION, b""),
+                frame(true, opcode::CONTINUATION, b"lo"),
+            ],
+            expect: Expect::Messages(vec![binary(b"Hello")]),
+        },
+        Case {
+            name: "frame split across reads",
+            writes: hello.iter().map(|&byte| vec![byte]).collect(),
+            expect: Expect::Messages(vec![binary(b"Hello")]),
+        },
+        Case {
+            name: "ping is answered with its payload",
+            writes: vec![frame(true, opcode::PING, b"are you there")],
+            expect: Expect::Messages(vec![Message::Pong(b"are you there".to_vec())]),
+        },
+        Case {
+            name: "ping between fragments is answered first",
+            writes: vec![
+                frame(false, opcode::BINARY, b"Hel"),
+                frame(true, opcode::PING, b"ping"),
+                frame(true, opcode::CONTINUATION, b"lo"),
+            ],
+            expect: Expect::Messages(vec![Message::Pong(b"ping".to_vec()), binary(b"Hello")]),
+        },
+        Case {
+            name: "close is answered with a close",
+            writes: vec![close_frame(&1000u16.to_be_bytes())],
+            expect: Expect::Close(&[1000]),
+        },
+        Case {
+            name: "reserved data opcode",
+            writes: vec![frame(true, 0x3, b"Hello")],
+            expect: Expect::Close(&[1002]),
+        },
+        Case {
+            name: "reserved control opcode",
+            writes: vec![frame(true, 0xb, b"")],
+            expect: Expect::Close(&[1002]),
+        },
+        Case {
+            name: "RSV bit without an extension",
+            writes: vec![rsv],
+            expect: Expect::Close(&[1002]),
+        },
+        Case {
+            name: "unmasked client frame",
+            writes: vec![unmasked],
+            expect: Expect::Close(&[1002]),
+        },
+        Case {
+            name: "continuation with nothing to continue",
+            writes: vec![frame(true, opcode::CONTINUATION, b"lo")],
+            expect: Expect::Close(&[1002]),
+        },
+        Case {
+            name: "new message before the last one finished",
+            writes: vec![
+                frame(false, opcode::BINARY, b"Hel"),
+                frame(true, opcode::BINARY, b"lo"),
+            ],
+            expect: Expect::Close(&[1002]),
+        },
+        Case {
+            name: "fragmented ping",
+            writes: vec![frame(false, opcode::PING, b"ping")],
+            expect: Expect::Close(&[1002]),
+        },
+        Case {
+            name: "ping over 125 bytes",
+            writes: vec![frame(true, opcode::PING, &[0; 126])],
+            expect: Expect::Close(&[1002]),
+        },
+        Case {
+            name: "64 bit length with the top bit set",
+            writes: vec![huge],
+            expect: Expect::Close(&[1002, 1009]),
+        },
+        Case {
+            name: "1 TiB length",
+            writes: vec![too_big],
+            expect: Expect::Close(&[1009]),
+        },
+        Case {
+            name: "invalid UTF-8 text",
+            writes: vec![frame(
+                true,
+                opcode::TEXT,
+                b"\xce\xba\xe1\xbd\xb9\xcf\x83\xed\xa0\x80",
+            )],
+            expect: Expect::Close(&[1007]),
+        },
+        Case {
+            name: "close with a 1 byte payload",
+            writes: vec![close_frame(&[0x03])],
+            expect: Expect::Close(&[1002]),
+        },
+        Case {
+            name: "close with a reserved status code",
+            writes: vec![close_frame(&1005u16.to_be_bytes())],
+            expect: Expect::Close(&[1002]),
+        },
+        Case {
+            name: "close with an invalid UTF-8 reason",
+            writes: vec![close_frame(b"\x03\xe8\xff\xfe")],
+            expect: Expect::Close(&[1007]),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    use crate::config::Config;
+    use crate::server::{self, Server};
+
+    #[test]
+    fn battery_against_wocket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            let config = Config {
+                max_buffered_bytes: 1 << 20,
+                ..Config::default()
+            };
+            let server = std::sync::Arc::new(Server::from_config(config).unwrap());
+            loop {
+                let (socket, peer) = listener.accept().await.unwrap();
+                tokio::spawn(server::handle_client(
+                    socket,
+                    peer,
+                    Vec::new(),
+                    server.clone(),
+                ));
+            }
+        });
+
+        let report = run(&format!("ws://{addr}/"));
+        let not_passed: Vec<_> = report
+            .results
+            .iter()
+            .filter(|(_, outcome)| *outcome != Outcome::Pass)
+            .collect();
+        assert!(not_passed.is_empty(), "{not_passed:?}");
+        assert!(report.passed());
+    }
+}
@@ -0,0 +1,546 @@
+//! A registry of open WebSocket connections, so they can be closed, or sent
+//! messages, from outside the task running them.
+//!
+//! Connections can be labelled, e.g. `region=eu` or `beta`, and messages
+//! sent to every connection matching a selector like `region=eu AND
+//! tier=pro`. Each label keeps the set of connections that have it, so a
+//! selector only looks at connections with its rarest label rather than at
+//! every connection.
+//!
+//! Connections are spread over `SHARDS` maps by ID, each with its own lock,
+//! so connections opening and closing on different workers rarely wait for
+//! each other. The label index has a lock of its own, taken after a
+//! shard's when both are needed.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+use crate::cancel::CancellationToken;
+use crate::codec::{self, opcode, CloseFrame};
+use crate::ulid::Ulid;
+
+/// Identifies a connection for as long as it is open. IDs aren't reused.
+pub type ConnectionId = u64;
+
+/// How many messages can be waiting to go out to one connection before
+/// more are dropped.
+pub const OUTBOX_CAPACITY: usize = 64;
+
+/// How many maps the connections are spread over by default.
+pub const SHARDS: usize = 64;
+
+/// The open connections, by ID.
+#[derive(Debug)]
+pub struct Connections {
+    next_id: AtomicU64,
+    shards: Box<[Mutex<Shard>]>,
+
+    /// The connections with each label.
+    labelled: Mutex<HashMap<String, HashSet<ConnectionId>>>,
+}
+
+type Shard = HashMap<ConnectionId, Entry>;
+
+impl Default for Connections {
+    fn default() -> Self {
+        Connections::with_shards(SHARDS)
+    }
+}
+
+/// What is known about an open connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub peer: SocketAddr,
+
+    /// The ID it was given when it was accepted, unique across servers.
+    pub request_id: Ulid,
+
+    /// The smoothed round-trip time to the peer, once it has answered a
+    /// ping.
+    pub rtt: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: ConnectionInfo,
+    kick: oneshot::Sender<CloseFrame>,
+    outbox: mpsc::Sender<Arc<Outgoing>>,
+
+    /// When each message in the outbox was queued, oldest first.
+    queued: VecDeque<Instant>,
+
+    /// The connection's labels, by key.
+    labels: BTreeMap<String, String>,
+
+    cancel: CancellationToken,
+}
+
+fn unindex(labelled: &mut HashMap<String, HashSet<ConnectionId>>, label: &str, id: ConnectionId) {
+    if let Some(ids) = labelled.get_mut(label) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            labelled.remove(label);
+        }
+    }
+}
+
+/// A message queued for connections. A broadcast queues the same one for
+/// every recipient, so its frame is only encoded once, by whichever
+/// connection sends it first.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Outgoing {
+    message: Vec<u8>,
+    frame: OnceLock<Vec<u8>>,
+}
+
+impl Outgoing {
+    pub fn new(message: Vec<u8>) -> Self {
+        Outgoing {
+            message,
+            frame: OnceLock::new(),
+        }
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// The message as a binary frame from a server, which is the same for
+    /// every connection that hasn't negotiated an extension.
+    pub fn frame(&self) -> &[u8] {
+        self.frame.get_or_init(|| {
+            let mut frame = Vec::with_capacity(self.message.len() + 10);
+            codec::write_frame(true, opcode::BINARY, &self.message, None, &mut frame);
+            frame
+        })
+    }
+}
+
+/// Messages waiting in a connection's outbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backlog {
+    pub peer: SocketAddr,
+    pub request_id: Ulid,
+    pub depth: usize,
+
+    /// When the oldest of them was queued.
+    pub oldest: Instant,
+}
+
+/// Which connections a broadcast goes to: those with all of its labels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selector {
+    labels: Vec<String>,
+}
+
+impl Selector {
+    /// Parses labels joined by `AND`, e.g. `region=eu AND tier=pro`.
+    pub fn parse(selector: &str) -> Result<Self, String> {
+        let mut labels = Vec::new();
+        for term in selector.split(" AND ") {
+            let term = term.trim();
+            if label_key(term).is_none() {
+                return Err(format!("{term:?} isn't a label"));
+            }
+            labels.push(String::from(term));
+        }
+
+        Ok(Selector { labels })
+    }
+}
+
+/// The key of `label`: the part before `=`, or all of it if there is no
+/// value. `None` if it is empty, or has spaces in it.
+fn label_key(label: &str) -> Option<&str> {
+    let key = label.split_once('=').map_or(label, |(key, _)| key);
+    if key.is_empty() || label.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(key)
+}
+
+impl Connections {
+    /// A registry spread over `shards` maps instead of `SHARDS`, e.g. one
+    /// to compare against.
+    pub fn with_shards(shards: usize) -> Self {
+        Connections {
+            next_id: AtomicU64::new(0),
+            shards: (0..shards.max(1)).map(|_| Mutex::default()).collect(),
+            labelled: Mutex::default(),
+        }
+    }
+
+    /// Adds a connection from `peer`, accepted as `request_id`. It is
+    /// removed when the returned registration is dropped.
+    pub fn register(self: &Arc<Self>, peer: SocketAddr, request_id: Ulid) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (kick, kicked) = oneshot::channel();
+        let (outbox, outgoing) = mpsc::channel(OUTBOX_CAPACITY);
+        let cancel = CancellationToken::new();
+
+        let entry = Entry {
+            info: ConnectionInfo {
+                peer,
+                request_id,
+                rtt: None,
+            },
+            kick,
+            outbox,
+            queued: VecDeque::new(),
+            labels: BTreeMap::new(),
+            cancel: cancel.clone(),
+        };
+        self.shard(id).insert(id, entry);
+
+        Registration {
+            connections: Arc::clone(self),
+            id,
+            kicked: Some(kicked),
+            outgoing,
+            cancel,
+        }
+    }
+
+    /// The open connections and what is known about them.
+    pub fn list(&self) -> Vec<(ConnectionId, ConnectionInfo)> {
+        let mut list = Vec::new();
+        for shard in self.shards.iter() {
+            let entries = shard.lock().unwrap();
+            list.extend(entries.iter().map(|(&id, entry)| (id, entry.info)));
+        }
+        list
+    }
+
+    /// The connections with messages waiting to go out to them.
+    pub fn backlogs(&self) -> Vec<(ConnectionId, Backlog)> {
+        let mut backlogs = Vec::new();
+        for shard in self.shards.iter() {
+            let entries = shard.lock().unwrap();
+            backlogs.extend(entries.iter().filter_map(|(&id, entry)| {
+                let backlog = Backlog {
+                    peer: entry.info.peer,
+                    request_id: entry.info.request_id,
+                    depth: entry.queued.len(),
+                    oldest: *entry.queued.front()?,
+                };
+                Some((id, backlog))
+            }));
+        }
+        backlogs
+    }
+
+    /// Labels connection `id`, e.g. with `region=eu`, replacing any label
+    /// with the same key. Fails if there is no such connection, or `label`
+    /// isn't a label.
+    pub fn label(&self, id: ConnectionId, label: &str) -> Result<(), String> {
+        let key = label_key(label).ok_or_else(|| format!("{label:?} isn't a label"))?;
+
+        let mut shard = self.shard(id);
+        let entry = shard
+            .get_mut(&id)
+            .ok_or_else(|| format!("connection {id} isn't open"))?;
+
+        let mut labelled = self.labelled.lock().unwrap();
+        if let Some(old) = entry.labels.insert(String::from(key), String::from(label)) {
+            unindex(&mut labelled, &old, id);
+        }
+        labelled.entry(String::from(label)).or_default().insert(id);
+        Ok(())
+    }
+
+    /// Removes the label with `key` from connection `id`. Returns `false`
+    /// if it didn't have one.
+    pub fn unlabel(&self, id: ConnectionId, key: &str) -> bool {
+        let mut shard = self.shard(id);
+        let removed = shard
+            .get_mut(&id)
+            .and_then(|entry| entry.labels.remove(key));
+
+        match removed {
+            Some(label) => {
+                unindex(&mut self.labelled.lock().unwrap(), &label, id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The labels of connection `id`, sorted by key.
+    pub fn labels(&self, id: ConnectionId) -> Vec<String> {
+        match self.shard(id).get(&id) {
+            Some(entry) => entry.labels.values().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The connections matching `selector`.
+    pub fn select(&self, selector: &Selector) -> Vec<ConnectionId> {
+        let labelled = self.labelled.lock().unwrap();
+
+        let mut sets = Vec::with_capacity(selector.labels.len());
+        for label in &selector.labels {
+            match labelled.get(label) {
+                Some(ids) => sets.push(ids),
+                None => return Vec::new(),
+            }
+        }
+
+        // Only the rarest label's connections need checking against the rest
+        sets.sort_by_key(|ids| ids.len());
+        let Some((rarest, rest)) = sets.split_first() else {
+            return Vec::new();
+        };
+        rarest
+            .iter()
+            .filter(|id| rest.iter().all(|ids| ids.contains(id)))
+            .copied()
+            .collect()
+    }
+
+    /// Queues `message` to be sent to connection `id`. Returns `false` if
+    /// there is no such connection, or too many messages are already
+    /// waiting for it.
+    pub fn send(&self, id: ConnectionId, message: &[u8]) -> bool {
+        self.queue(id, &Arc::new(Outgoing::new(message.to_vec())))
+    }
+
+    /// Queues `message` for every connection matching `selector`. Returns
+    /// how many it was queued for. They all share the one copy of it.
+    pub fn broadcast(&self, selector: &Selector, message: &[u8]) -> usize {
+        let outgoing = Arc::new(Outgoing::new(message.to_vec()));
+        let ids = self.select(selector);
+        ids.into_iter()
+            .filter(|&id| self.queue(id, &outgoing))
+            .count()
+    }
+
+    fn queue(&self, id: ConnectionId, outgoing: &Arc<Outgoing>) -> bool {
+        let mut shard = self.shard(id);
+        let Some(entry) = shard.get_mut(&id) else {
+            return false;
+        };
+
+        let queued = entry.outbox.try_send(Arc::clone(outgoing)).is_ok();
+        if queued {
+            entry.queued.push_back(Instant::now());
+        }
+        queued
+    }
+
+    /// The token cancelled once connection `id` has ended, for tying work
+    /// done on its behalf to it. `None` once it has been kicked or closed.
+    pub fn cancellation_token(&self, id: ConnectionId) -> Option<CancellationToken> {
+        let shard = self.shard(id);
+        shard.get(&id).map(|entry| entry.cancel.clone())
+    }
+
+    /// Tells a connection to close with `code` and `reason`. Returns
+    /// `false` if there is no such connection, or it has already been told.
+    pub fn kick(&self, id: ConnectionId, code: u16, reason: &str) -> bool {
+        let Some(entry) = self.remove(id) else {
+            return false;
+        };
+
+        let frame = CloseFrame {
+            code,
+            reason: String::from(reason),
+        };
+        entry.kick.send(frame).is_ok()
+    }
+
+    fn shard(&self, id: ConnectionId) -> MutexGuard<'_, Shard> {
+        self.shards[id as usize % self.shards.len()].lock().unwrap()
+    }
+
+    fn remove(&self, id: ConnectionId) -> Option<Entry> {
+        let mut shard = self.shard(id);
+        let entry = shard.remove(&id)?;
+
+        if !entry.labels.is_empty() {
+            let mut labelled = self.labelled.lock().unwrap();
+            for label in entry.labels.values() {
+                unindex(&mut labelled, label, id);
+            }
+        }
+        Some(entry)
+    }
+}
+
+/// A connection's place in the registry.
+#[derive(Debug)]
+pub struct Registration {
+    connections: Arc<Connections>,
+    id: ConnectionId,
+    kicked: Option<oneshot::Receiver<CloseFrame>>,
+    outgoing: mpsc::Receiver<Arc<Outgoing>>,
+
+    /// Cancelled when this is dropped, i.e. when the connection's task is
+    /// done with it.
+    cancel: CancellationToken,
+}
+
+impl Registration {
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    /// Records the connection's latest smoothed round-trip time.
+    pub fn set_rtt(&self, rtt: Option<Duration>) {
+        if let Some(entry) = self.connections.shard(self.id).get_mut(&self.id) {
+            entry.info.rtt = rtt;
+        }
+    }
+
+    /// Resolves with the close frame once the connection is kicked. Only
+    /// resolves once; after that it never does.
+    pub async fn kicked(&mut self) -> CloseFrame {
+        kicked(&mut self.kicked).await
+    }
+
+    /// Resolves with the next thing the registry has for the connection:
+    /// a message to send, or the close frame once it is kicked. Messages
+    /// stop once it has been kicked.
+    pub async fn instruction(&mut self) -> Instruction {
+        tokio::select! {
+            frame = kicked(&mut self.kicked) => Instruction::Close(frame),
+            Some(message) = self.outgoing.recv() => {
+                if let Some(entry) = self.connections.shard(self.id).get_mut(&self.id) {
+                    entry.queued.pop_front();
+                }
+                Instruction::Send(message)
+            }
+        }
+    }
+}
+
+/// What the registry tells a connection to do.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Send(Arc<Outgoing>),
+    Close(CloseFrame),
+}
+
+async fn kicked(slot: &mut Option<oneshot::Receiver<CloseFrame>>) -> CloseFrame {
+    if let Some(kicked) = slot {
+        if let Ok(frame) = kicked.await {
+            *slot = None;
+            return frame;
+        }
+        *slot = None;
+    }
+
+    std::future::pending().await
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.remove(self.id);
+        self.cancel.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::PEER;
+
+    #[tokio::test]
+    async fn kicked_once() {
+        let connections = Arc::new(Connections::default());
+        let request_id = Ulid::new();
+        let mut registration = connections.register(PEER, request_id);
+        let id = registration.id();
+
+        let info = ConnectionInfo {
+            peer: PEER,
+            request_id,
+            rtt: None,
+        };
+        assert_eq!(connections.list(), vec![(id, info)]);
+        assert!(connections.kick(id, 4000, "bye"));
+        assert!(!connections.kick(id, 4000, "bye"));
+        assert_eq!(registration.kicked().await.code, 4000);
+
+        // Kicked connections are still running until they are dropped
+        let token = registration.cancellation_token().clone();
+        assert!(connections.cancellation_token(id).is_none());
+        assert!(!token.is_cancelled());
+
+        drop(registration);
+        assert!(connections.list().is_empty());
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn every_shard_is_listed() {
+        let connections = Arc::new(Connections::with_shards(3));
+        let registrations: Vec<_> = (0..10)
+            .map(|_| connections.register(PEER, Ulid::new()))
+            .collect();
+        assert_eq!(connections.list().len(), 10);
+
+        assert!(connections.kick(registrations[4].id(), 4000, "bye"));
+        drop(registrations);
+        assert!(connections.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn broadcast_to_labelled_connections() {
+        let connections = Arc::new(Connections::default());
+        let mut eu_pro = connections.register(PEER, Ulid::new());
+        let eu_free = connections.register(PEER, Ulid::new());
+        let mut us_pro = connections.register(PEER, Ulid::new());
+
+        connections.label(eu_pro.id(), "region=us").unwrap();
+        connections.label(eu_pro.id(), "region=eu").unwrap();
+        connections.label(eu_pro.id(), "tier=pro").unwrap();
+        connections.label(eu_free.id(), "region=eu").unwrap();
+        connections.label(us_pro.id(), "region=us").unwrap();
+        connections.label(us_pro.id(), "tier=pro").unwrap();
+        assert!(connections.label(eu_pro.id(), "no spaces").is_err());
+        assert_eq!(connections.labels(eu_pro.id()), ["region=eu", "tier=pro"]);
+
+        let selector = Selector::parse("region=eu AND tier=pro").unwrap();
+        assert_eq!(connections.select(&selector), [eu_pro.id()]);
+        assert_eq!(connections.broadcast(&selector, b"hello"), 1);
+        let backlogs = connections.backlogs();
+        assert_eq!(backlogs.len(), 1);
+        assert_eq!((backlogs[0].0, backlogs[0].1.depth), (eu_pro.id(), 1));
+        let Instruction::Send(outgoing) = eu_pro.instruction().await else {
+            panic!("expected a message");
+        };
+        assert_eq!(outgoing.message(), b"hello");
+        assert_eq!(outgoing.frame(), [0x82, 5, b'h', b'e', b'l', b'l', b'o']);
+        assert!(connections.backlogs().is_empty());
+
+        // Recipients share the message, and the frame encoded for it
+        let selector = Selector::parse("tier=pro").unwrap();
+        assert_eq!(connections.broadcast(&selector, b"pro tip"), 2);
+        let (Instruction::Send(first), Instruction::Send(second)) =
+            (eu_pro.instruction().await, us_pro.instruction().await)
+        else {
+            panic!("expected messages");
+        };
+        assert!(Arc::ptr_eq(&first, &second));
+
+        assert!(connections.unlabel(us_pro.id(), "region"));
+        let selector = Selector::parse("region=us").unwrap();
+        assert!(connections.select(&selector).is_empty());
+
+        drop(eu_pro);
+        let selector = Selector::parse("tier=pro").unwrap();
+        assert_eq!(connections.select(&selector), [us_pro.id()]);
+        assert!(Selector::parse("region=eu AND").is_err());
+    }
+}
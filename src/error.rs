@@ -0,0 +1,61 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// Errors from the `sync` and `testing` clients.
+#[derive(Debug)]
+pub enum Error {
+    /// The connection has been closed, by either side, so nothing more can
+    /// be sent or received.
+    Closed,
+
+    /// The opening handshake failed or was refused.
+    Handshake(String),
+
+    /// The peer broke the protocol. A close frame saying why has been sent.
+    Protocol(&'static str),
+
+    /// The message can't be sent, e.g. a ping payload is too long.
+    InvalidMessage(&'static str),
+
+    /// Nothing, not even a pong, came from the peer for this long, so the
+    /// link is taken to be dead and the stream has been shut down.
+    Disconnected(Duration),
+
+    /// The connection is down and its offline queue is full, see
+    /// `reconnect::Overflow::Reject`.
+    QueueFull,
+
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Closed => f.write_str("connection is closed"),
+            Error::Handshake(err) => write!(f, "handshake failed: {err}"),
+            Error::Protocol(err) => write!(f, "protocol error: {err}"),
+            Error::InvalidMessage(err) => write!(f, "invalid message: {err}"),
+            Error::Disconnected(silent) => write!(f, "peer silent for {silent:?}"),
+            Error::QueueFull => f.write_str("offline queue is full"),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
@@ -0,0 +1,68 @@
+//! Connection lifecycle events, for audit and analytics code to subscribe
+//! to with `Server::subscribe` instead of wrapping the server.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::connections::ConnectionId;
+use crate::handshake::Rejection;
+use crate::trace::TraceContext;
+use crate::ulid::Ulid;
+
+/// How many events a subscriber can fall behind by before it starts
+/// missing them.
+pub const CAPACITY: usize = 1024;
+
+/// Every event about a connection carries the `request_id` it was given
+/// when it was accepted, the same one its log lines and 101 response have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+    /// A WebSocket upgrade went through. `trace` is the trace context the
+    /// client sent with it, for joining up traces, and `tags` are what the
+    /// accept policies tagged the peer with, e.g. `country=DE`.
+    ConnectionOpened {
+        id: ConnectionId,
+        peer: SocketAddr,
+        request_id: Ulid,
+        path: String,
+        trace: Option<TraceContext>,
+        tags: Vec<String>,
+    },
+
+    /// An upgraded connection has ended. `code` is the first close status
+    /// sent or received, if there was one. The byte counts include the
+    /// handshake.
+    ConnectionClosed {
+        id: ConnectionId,
+        peer: SocketAddr,
+        request_id: Ulid,
+        code: Option<u16>,
+        duration: Duration,
+        bytes_received: u64,
+        bytes_sent: u64,
+    },
+
+    HandshakeRejected {
+        peer: SocketAddr,
+        request_id: Ulid,
+        reason: Rejection,
+    },
+
+    /// More than `slow_consumer_depth` messages have been waiting for a
+    /// connection for `slow_consumer_after`. Sent once each time it
+    /// happens.
+    SlowConsumer {
+        id: ConnectionId,
+        peer: SocketAddr,
+        request_id: Ulid,
+        depth: usize,
+        oldest: Duration,
+    },
+
+    /// Reading from or writing to a connection failed, and it was dropped.
+    Error {
+        peer: SocketAddr,
+        request_id: Ulid,
+        message: String,
+    },
+}
@@ -0,0 +1,126 @@
+//! A warm standby that takes over from a primary on the same host when it
+//! dies, without a load balancer in front of them.
+//!
+//! Both servers bind `addr` with `SO_REUSEPORT`, so the kernel spreads new
+//! connections between them. The primary listens on a Unix socket at
+//! `failover_socket` and writes a heartbeat down every connection to it.
+//! The standby follows that heartbeat and answers upgrades with a 503 until
+//! it stops: once the primary has closed the socket or missed heartbeats
+//! for `failover_after`, the standby takes upgrades itself, and starts
+//! sending heartbeats for the next standby.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, UnixListener, UnixStream};
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+
+/// How often the standby tries the primary's socket while waiting for it
+/// to come up.
+const CONNECT_RETRY: Duration = Duration::from_millis(100);
+
+/// Binds `addr` with `SO_REUSEPORT`, so the other server of the pair can
+/// bind it too.
+pub async fn bind(addr: &str) -> io::Result<TcpListener> {
+    let addr: SocketAddr = lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to bind"))?;
+
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Sends a heartbeat every `interval` to each standby that connects to
+/// `path`, forever. A stale socket left at `path` by a primary that died is
+/// replaced.
+pub async fn send_heartbeats(path: &Path, interval: Duration) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+
+    // Dropped along with this future, so heartbeats stop if it is
+    let mut standbys = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (standby, _) = accepted?;
+                standbys.spawn(beat(standby, interval));
+            }
+            Some(_) = standbys.join_next() => {}
+        }
+    }
+}
+
+async fn beat(mut standby: UnixStream, interval: Duration) {
+    let mut beats = time::interval(interval);
+    loop {
+        beats.tick().await;
+        if standby.write_all(b".").await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Resolves once the primary at `path` has gone: its socket has closed, or
+/// nothing has come down it for `after`. A primary that can't be reached
+/// for `after` from the start counts as gone too.
+pub async fn primary_gone(path: &Path, after: Duration) {
+    let deadline = Instant::now() + after;
+    let mut primary = loop {
+        match UnixStream::connect(path).await {
+            Ok(primary) => break primary,
+            Err(_) if Instant::now() < deadline => time::sleep(CONNECT_RETRY).await,
+            Err(_) => return,
+        }
+    };
+
+    let mut beats = [0; 64];
+    while let Ok(Ok(1..)) = time::timeout(after, primary.read(&mut beats)).await {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_standby_notices_the_primary_stopping() {
+        let path = std::env::temp_dir().join(format!("wocket-failover-{}", std::process::id()));
+        let interval = Duration::from_millis(10);
+        let after = Duration::from_millis(200);
+
+        let primary = tokio::spawn({
+            let path = path.clone();
+            async move { send_heartbeats(&path, interval).await }
+        });
+        let gone = tokio::spawn({
+            let path = path.clone();
+            async move { primary_gone(&path, after).await }
+        });
+
+        time::sleep(after * 2).await;
+        assert!(!gone.is_finished());
+
+        primary.abort();
+        time::timeout(after * 5, gone).await.unwrap().unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        // Both of a pair can listen on the one port
+        let first = bind("127.0.0.1:0").await.unwrap();
+        let addr = first.local_addr().unwrap().to_string();
+        let second = bind(&addr).await.unwrap();
+        assert_eq!(second.local_addr().unwrap(), first.local_addr().unwrap());
+    }
+}
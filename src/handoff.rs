@@ -0,0 +1,140 @@
+//! Passing the listening socket from a running server to its replacement,
+//! so deploys don't drop the listener.
+//!
+//! The running server listens on a Unix socket at `handoff_socket`. A new
+//! server started with `takeover` set connects to it instead of binding its
+//! own listener. The old server removes the Unix socket, sends the listener
+//! over it with `SCM_RIGHTS`, and stops accepting. It exits once its
+//! remaining connections have closed, while the new server accepts new
+//! ones and listens at `handoff_socket` for the next deploy.
+
+use std::io;
+use std::mem;
+use std::net::TcpListener;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::ptr;
+
+/// Connects to the server running at `path` and takes its listener.
+pub fn take_listener(path: &Path) -> io::Result<TcpListener> {
+    let stream = UnixStream::connect(path)?;
+    let fd = receive_fd(&stream)?;
+
+    // SAFETY: the fd was just received, so nothing else owns it
+    Ok(unsafe { TcpListener::from_raw_fd(fd) })
+}
+
+/// Waits at `path` for a new server to take over, then sends it
+/// `listener`. Returns once it has been sent.
+pub async fn give_listener(path: &Path, listener: RawFd) -> io::Result<()> {
+    // Left over from a process that didn't get to hand off
+    let _ = std::fs::remove_file(path);
+
+    let unix_listener = tokio::net::UnixListener::bind(path)?;
+    let (stream, _) = unix_listener.accept().await?;
+
+    // Remove the socket before sending, so the new server can bind its own
+    // once it has the listener
+    drop(unix_listener);
+    std::fs::remove_file(path)?;
+
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    send_fd(&stream, listener)
+}
+
+fn send_fd(stream: &UnixStream, fd: RawFd) -> io::Result<()> {
+    // One byte of real data has to go with the control message
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr().cast(),
+        iov_len: data.len(),
+    };
+
+    let mut control = vec![0u8; cmsg_space()];
+
+    // SAFETY: msghdr is plain data, and the buffers it points to outlive
+    // the sendmsg call
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = control.len() as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>(), fd);
+
+        if libc::sendmsg(stream.as_raw_fd(), &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+fn receive_fd(stream: &UnixStream) -> io::Result<RawFd> {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr().cast(),
+        iov_len: data.len(),
+    };
+
+    let mut control = vec![0u8; cmsg_space()];
+
+    // SAFETY: as in send_fd, and the control message is only read if the
+    // kernel says it is an SCM_RIGHTS one
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = control.len() as _;
+
+        if libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no listener in handoff message",
+            ));
+        }
+
+        Ok(ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>()))
+    }
+}
+
+fn cmsg_space() -> usize {
+    // SAFETY: CMSG_SPACE only does arithmetic
+    unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as usize }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listener_survives_the_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (old, new) = UnixStream::pair().unwrap();
+
+        send_fd(&old, listener.as_raw_fd()).unwrap();
+        let fd = receive_fd(&new).unwrap();
+        let received = unsafe { TcpListener::from_raw_fd(fd) };
+
+        assert_eq!(
+            received.local_addr().unwrap(),
+            listener.local_addr().unwrap()
+        );
+    }
+}
@@ -0,0 +1,692 @@
+use base64::prelude::*;
+
+use sha1::Digest;
+use sha1::Sha1;
+
+use crate::headers::Headers;
+use crate::trace::TraceContext;
+use crate::upgrade::{self, Decision, Request, Reservations, UpgradeHook};
+
+/// What to do with a client's opening HTTP request.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Handshake {
+    /// The client asked for a WebSocket on `path`. Send it this 101
+    /// response and switch to WebSocket frames. The request, including any
+    /// body, took up the first `len` bytes; anything after that is frames
+    /// the client sent without waiting for the response.
+    ///
+    /// `subprotocol` is the one the hooks picked, and `trace` is the
+    /// client's W3C trace context, if it sent one. `reservations` are what
+    /// the hooks set aside for the connection, to hold until it closes.
+    Upgrade {
+        response: String,
+        path: String,
+        len: usize,
+        subprotocol: Option<String>,
+        trace: Option<TraceContext>,
+        reservations: Reservations,
+    },
+
+    /// A plain GET for `path`, without any upgrade headers. The request
+    /// took up the first `len` bytes, and `keep_alive` is whether the client
+    /// wants to send more requests on the same connection.
+    Page {
+        path: String,
+        len: usize,
+        keep_alive: bool,
+    },
+
+    /// Send this error response and close the connection.
+    Reject(String, Rejection),
+}
+
+/// Why an upgrade was refused, for counting failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// Not valid HTTP, not a GET, or missing a required header.
+    Malformed,
+
+    /// A WebSocket version other than 13.
+    BadVersion,
+
+    /// A hook refused it with a 401.
+    Unauthorized,
+
+    /// A hook refused it with a 403, e.g. for its origin.
+    Forbidden,
+
+    /// A hook refused it with a 404, e.g. for its path.
+    NotFound,
+
+    /// Deferred by a connection limit, or refused with a 429.
+    RateLimited,
+
+    /// A hook refused it with any other status.
+    Refused,
+}
+
+impl Rejection {
+    pub const ALL: [Rejection; 7] = [
+        Rejection::Malformed,
+        Rejection::BadVersion,
+        Rejection::Unauthorized,
+        Rejection::Forbidden,
+        Rejection::NotFound,
+        Rejection::RateLimited,
+        Rejection::Refused,
+    ];
+
+    fn from_status(status: u16) -> Self {
+        match status {
+            401 => Rejection::Unauthorized,
+            403 => Rejection::Forbidden,
+            404 => Rejection::NotFound,
+            429 => Rejection::RateLimited,
+            _ => Rejection::Refused,
+        }
+    }
+
+    /// A short snake_case name, e.g. for metric labels.
+    pub fn name(self) -> &'static str {
+        match self {
+            Rejection::Malformed => "malformed",
+            Rejection::BadVersion => "bad_version",
+            Rejection::Unauthorized => "unauthorized",
+            Rejection::Forbidden => "forbidden",
+            Rejection::NotFound => "not_found",
+            Rejection::RateLimited => "rate_limited",
+            Rejection::Refused => "refused",
+        }
+    }
+}
+
+/// Most bytes of HTTP headers read before giving up on a handshake.
+pub const MAX_HEADERS_LEN: usize = 16 * 1024;
+
+/// Works out how to respond to the request in `request_buf`. Valid upgrade
+/// requests are passed to `hooks` to decide on, and
+/// `extra_headers` are added to the 101 response if they accept. Returns
+/// `None` if the request hasn't fully arrived yet.
+pub fn handshake_response(
+    request_buf: &[u8],
+    hooks: &[Box<dyn UpgradeHook>],
+    extra_headers: &[(String, String)],
+) -> Option<Handshake> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+
+    let headers_len = match req.parse(request_buf) {
+        // However the headers arrived, only their length is at fault
+        Ok(httparse::Status::Partial) if request_buf.len() > MAX_HEADERS_LEN => {
+            return Some(headers_too_long())
+        }
+        Ok(httparse::Status::Partial) => return None,
+        Ok(httparse::Status::Complete(len)) if len > MAX_HEADERS_LEN => {
+            return Some(headers_too_long())
+        }
+        Ok(httparse::Status::Complete(len)) => len,
+        Err(_) => {
+            return Some(Handshake::Reject(
+                error_response("400 Bad Request", "", "malformed HTTP request"),
+                Rejection::Malformed,
+            ))
+        }
+    };
+
+    if req.method != Some("GET") {
+        return Some(Handshake::Reject(
+            error_response(
+                "405 Method Not Allowed",
+                "Allow: GET\r\n",
+                "WebSocket handshakes must use GET",
+            ),
+            Rejection::Malformed,
+        ));
+    }
+
+    let headers = Headers::new(req.headers);
+
+    // Some proxies send a body with the request. It is skipped, so the
+    // frames or requests after it aren't mistaken for part of it.
+    let body_len = match unique_header(headers, "Content-Length") {
+        Ok(None) => 0,
+        Ok(Some(len)) => match std::str::from_utf8(len)
+            .ok()
+            .and_then(|len| len.parse().ok())
+        {
+            Some(len) => len,
+            None => return Some(bad_request("invalid Content-Length header")),
+        },
+        Err(duplicate) => return Some(bad_request(&duplicate)),
+    };
+
+    if headers.contains("Transfer-Encoding") {
+        return Some(bad_request("chunked request bodies aren't supported"));
+    }
+
+    let len = match headers_len.checked_add(body_len) {
+        Some(len) if len <= MAX_HEADERS_LEN => len,
+        _ => {
+            return Some(Handshake::Reject(
+                error_response("413 Content Too Large", "", "request body too long"),
+                Rejection::Malformed,
+            ))
+        }
+    };
+
+    if request_buf.len() < len {
+        return None;
+    }
+
+    // Upgrades to anything but a WebSocket are ignored, as HTTP allows
+    if !headers.has_token("Upgrade", "websocket") {
+        let keep_alive = match req.version {
+            Some(1) => !headers.has_token("Connection", "close"),
+            _ => headers.has_token("Connection", "keep-alive"),
+        };
+
+        return Some(Handshake::Page {
+            path: String::from(req.path.unwrap_or("/")),
+            len,
+            keep_alive,
+        });
+    }
+
+    if !headers.has_token("Connection", "upgrade") {
+        return Some(bad_request("missing Upgrade in Connection header"));
+    }
+
+    let version = match unique_header(headers, "Sec-WebSocket-Version") {
+        Ok(version) => version,
+        Err(duplicate) => return Some(bad_request(&duplicate)),
+    };
+
+    match version {
+        Some(b"13") => {}
+        Some(_) => {
+            return Some(Handshake::Reject(
+                unsupported_version(),
+                Rejection::BadVersion,
+            ))
+        }
+        None => return Some(bad_request("missing Sec-WebSocket-Version header")),
+    }
+
+    let key_value = match unique_header(headers, "Sec-WebSocket-Key") {
+        Ok(Some(key)) => key,
+        Ok(None) => return Some(bad_request("missing Sec-WebSocket-Key header")),
+        Err(duplicate) => return Some(bad_request(&duplicate)),
+    };
+
+    // The key is a random 16 byte nonce, base64 encoded
+    if !BASE64_STANDARD
+        .decode(key_value)
+        .is_ok_and(|nonce| nonce.len() == 16)
+    {
+        return Some(bad_request("invalid Sec-WebSocket-Key header"));
+    }
+
+    match unique_header(headers, "Host") {
+        Ok(Some(_)) => {}
+        Ok(None) => return Some(bad_request("missing Host header")),
+        Err(duplicate) => return Some(bad_request(&duplicate)),
+    }
+
+    let path = req.path.unwrap_or("/");
+    let request = Request::new(path, headers);
+
+    let (subprotocol, hook_headers) = match upgrade::decide_all(hooks, &request) {
+        Decision::Accept {
+            subprotocol,
+            headers,
+        } => (subprotocol, headers),
+        Decision::Reject { status, body } => {
+            return Some(Handshake::Reject(
+                text_response(status, &body),
+                Rejection::from_status(status),
+            ))
+        }
+        Decision::Defer { retry_after } => {
+            return Some(Handshake::Reject(
+                error_response(
+                    "503 Service Unavailable",
+                    &format!("Retry-After: {}\r\n", retry_after.as_secs().max(1)),
+                    "server is busy, try again later",
+                ),
+                Rejection::RateLimited,
+            ))
+        }
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Accept: {}\r\n",
+        accept_value(key_value),
+    );
+
+    if let Some(subprotocol) = &subprotocol {
+        response.push_str(&format!("Sec-WebSocket-Protocol: {subprotocol}\r\n"));
+    }
+
+    for (name, value) in extra_headers.iter().chain(&hook_headers) {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+
+    Some(Handshake::Upgrade {
+        response,
+        path: String::from(path),
+        len,
+        subprotocol,
+        trace: TraceContext::from_headers(headers),
+        reservations: request.into_reservations(),
+    })
+}
+
+/// The value of the header called `name`, if there is one. A header that
+/// appears more than once can't be trusted to mean the same thing to us and
+/// to proxies in front of us, so that is an error, for a 400.
+fn unique_header<'a>(headers: Headers<'a>, name: &str) -> Result<Option<&'a [u8]>, String> {
+    let mut found = headers.all(name);
+
+    match (found.next(), found.next()) {
+        (Some(value), None) => Ok(Some(value)),
+        (None, _) => Ok(None),
+        (Some(_), Some(_)) => Err(format!("duplicate {name} header")),
+    }
+}
+
+fn headers_too_long() -> Handshake {
+    Handshake::Reject(
+        error_response(
+            "431 Request Header Fields Too Large",
+            "",
+            "request headers too long",
+        ),
+        Rejection::Malformed,
+    )
+}
+
+fn bad_request(message: &str) -> Handshake {
+    Handshake::Reject(
+        error_response("400 Bad Request", "", message),
+        Rejection::Malformed,
+    )
+}
+
+/// The response to a plain HTTP request when there's nothing to serve it,
+/// pointing the client at the WebSocket upgrade instead.
+pub fn upgrade_required() -> String {
+    error_response(
+        "426 Upgrade Required",
+        "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n",
+        "this endpoint only serves WebSocket connections",
+    )
+}
+
+/// The response to a readiness probe: 200 while the server takes new
+/// connections, 503 while it is draining.
+pub fn readiness(ready: bool) -> String {
+    if ready {
+        text_response(200, "ready")
+    } else {
+        text_response(503, "draining")
+    }
+}
+
+/// Builds a response with a plain text `body`, e.g. for an upgrade a hook
+/// refused.
+pub fn text_response(status: u16, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status} {}\r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n\
+        {body}",
+        reason_phrase(status),
+        body.len(),
+    )
+}
+
+/// Turns a response built by this module, which closes the connection, into
+/// one that keeps it open for the client's next request.
+pub fn keep_alive(response: &[u8]) -> Vec<u8> {
+    let Some(end) = response.windows(4).position(|window| window == b"\r\n\r\n") else {
+        return response.to_vec();
+    };
+    let (head, body) = response.split_at(end + 2);
+
+    let mut kept = Vec::with_capacity(response.len() + 5);
+    for line in head.split_inclusive(|&byte| byte == b'\n') {
+        if !line.starts_with(b"Connection:") {
+            kept.extend_from_slice(line);
+        }
+    }
+    kept.extend_from_slice(b"Connection: keep-alive\r\n");
+    kept.extend_from_slice(body);
+    kept
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        409 => "Conflict",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+fn unsupported_version() -> String {
+    error_response(
+        "426 Upgrade Required",
+        "Sec-WebSocket-Version: 13\r\n",
+        "only WebSocket version 13 is supported",
+    )
+}
+
+/// Builds an error response with a small JSON body describing the problem.
+/// `headers` are extra header lines, each ending in CRLF.
+fn error_response(status: &str, headers: &str, message: &str) -> String {
+    let body = format!("{{\"error\":\"{message}\"}}");
+
+    format!(
+        "HTTP/1.1 {status}\r\n\
+        {headers}\
+        Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n\
+        {body}",
+        body.len(),
+    )
+}
+
+/// The opening request a client sends to upgrade `path` on `host`, with a
+/// base64 encoded random `key`.
+pub fn upgrade_request(host: &str, path: &str, key: &str) -> String {
+    format!(
+        "GET {path} HTTP/1.1\r\n\
+        Host: {host}\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: {key}\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n"
+    )
+}
+
+/// Checks the server's answer to an `upgrade_request` sent with `key`.
+/// Returns the length of the response headers, after which frames start, or
+/// `Ok(None)` if they haven't fully arrived yet.
+pub fn check_upgrade_response(response_buf: &[u8], key: &str) -> Result<Option<usize>, String> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+
+    let len = match response.parse(response_buf) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(err) => return Err(format!("malformed HTTP response: {err}")),
+    };
+
+    if response.code != Some(101) {
+        return Err(format!(
+            "server refused the upgrade with {}",
+            response.code.unwrap_or_default()
+        ));
+    }
+
+    let accept = Headers::new(response.headers).get("Sec-WebSocket-Accept");
+
+    if accept != Some(accept_value(key.as_bytes()).as_bytes()) {
+        return Err(String::from("wrong Sec-WebSocket-Accept in response"));
+    }
+
+    Ok(Some(len))
+}
+
+/// The `Sec-WebSocket-Accept` value that answers a `Sec-WebSocket-Key`.
+pub fn accept_value(key_value: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key_value);
+    hasher.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    let hashed = hasher.finalize();
+
+    BASE64_STANDARD.encode(hashed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn respond(request: &[u8], extra_headers: &[(String, String)]) -> Option<Handshake> {
+        handshake_response(request, &[], extra_headers)
+    }
+
+    #[test]
+    fn test_accept_value() {
+        assert_eq!(
+            accept_value(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn plain_get_is_a_page() {
+        let request = b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        assert_eq!(
+            respond(request, &[]),
+            Some(Handshake::Page {
+                path: String::from("/index.html"),
+                len: request.len(),
+                keep_alive: true,
+            })
+        );
+
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        assert!(matches!(
+            respond(request, &[]),
+            Some(Handshake::Page {
+                keep_alive: false,
+                ..
+            })
+        ));
+
+        let request = b"GET / HTTP/1.0\r\n\r\n";
+        assert!(matches!(
+            respond(request, &[]),
+            Some(Handshake::Page {
+                keep_alive: false,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn extra_headers_on_upgrade() {
+        let request = b"GET / HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n";
+        let headers = [(String::from("Server"), String::from("wocket"))];
+
+        match respond(request, &headers) {
+            Some(Handshake::Upgrade { response, path, .. }) => {
+                assert!(response.ends_with("\r\nServer: wocket\r\n\r\n"));
+                assert_eq!(path, "/");
+            }
+            other => panic!("expected an upgrade, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn header_names_ignore_case() {
+        let request = b"GET /chat HTTP/1.1\r\n\
+            host: localhost\r\n\
+            connection: keep-alive, Upgrade\r\n\
+            upgrade: WebSocket\r\n\
+            SEC-WEBSOCKET-KEY: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            sec-websocket-version: 13\r\n\r\n";
+        assert!(matches!(
+            respond(request, &[]),
+            Some(Handshake::Upgrade { .. })
+        ));
+
+        let h2c = b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: h2c\r\n\r\n";
+        assert!(matches!(
+            respond(h2c, &[]),
+            Some(Handshake::Page { path, .. }) if path == "/chat"
+        ));
+    }
+
+    #[test]
+    fn keep_alive_replaces_connection_close() {
+        let response = keep_alive(text_response(200, "ready").as_bytes());
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            "HTTP/1.1 200 OK\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Length: 5\r\n\
+            Connection: keep-alive\r\n\r\n\
+            ready"
+        );
+    }
+
+    fn status_line(handshake: Option<Handshake>) -> String {
+        match handshake {
+            Some(Handshake::Reject(response, _)) => {
+                String::from(response.split("\r\n").next().unwrap())
+            }
+            other => panic!("expected a rejection, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn error_statuses() {
+        let post = b"POST / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n";
+        assert_eq!(
+            status_line(respond(post, &[])),
+            "HTTP/1.1 405 Method Not Allowed"
+        );
+
+        let old_version = b"GET / HTTP/1.1\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 8\r\n\r\n";
+        assert_eq!(
+            status_line(respond(old_version, &[])),
+            "HTTP/1.1 426 Upgrade Required"
+        );
+
+        let no_key = b"GET / HTTP/1.1\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n";
+        assert_eq!(
+            status_line(respond(no_key, &[])),
+            "HTTP/1.1 400 Bad Request"
+        );
+
+        assert_eq!(
+            status_line(respond(b"\x00\x01\r\n\r\n", &[])),
+            "HTTP/1.1 400 Bad Request"
+        );
+    }
+
+    #[test]
+    fn body_is_skipped() {
+        let request = b"GET / HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Content-Length: 4\r\n\r\n\
+            bodyFRAMES";
+
+        // Nothing happens until the whole body is there
+        assert_eq!(respond(&request[..request.len() - 8], &[]), None);
+
+        match respond(request, &[]) {
+            Some(Handshake::Upgrade { len, .. }) => assert_eq!(&request[len..], b"FRAMES"),
+            other => panic!("expected an upgrade, got {other:?}"),
+        }
+    }
+
+    fn error_message(handshake: Option<Handshake>) -> String {
+        match handshake {
+            Some(Handshake::Reject(response, Rejection::Malformed)) => {
+                String::from(response.split("\r\n\r\n").nth(1).unwrap())
+            }
+            other => panic!("expected a malformed rejection, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn hardened_checks() {
+        let request = |headers: &str| {
+            let request = format!(
+                "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n{headers}\r\n"
+            );
+            error_message(respond(request.as_bytes(), &[]))
+        };
+
+        assert_eq!(
+            request("Host: a\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: c2hvcnQ=\r\n"),
+            r#"{"error":"invalid Sec-WebSocket-Key header"}"#
+        );
+        assert_eq!(
+            request(
+                "Host: a\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Version: 13\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"
+            ),
+            r#"{"error":"duplicate Sec-WebSocket-Version header"}"#
+        );
+        assert_eq!(
+            request("Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"),
+            r#"{"error":"missing Host header"}"#
+        );
+
+        // RFC 6455 needs the upgrade asked for in Connection as well
+        let without = |connection: &str| {
+            let request = format!(
+                "GET / HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\n{connection}\
+                Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+            );
+            error_message(respond(request.as_bytes(), &[]))
+        };
+        let missing = r#"{"error":"missing Upgrade in Connection header"}"#;
+        assert_eq!(without(""), missing);
+        assert_eq!(without("Connection: keep-alive\r\n"), missing);
+
+        let huge = format!("GET / HTTP/1.1\r\nX: {}", "a".repeat(MAX_HEADERS_LEN));
+        assert_eq!(
+            status_line(respond(huge.as_bytes(), &[])),
+            "HTTP/1.1 431 Request Header Fields Too Large"
+        );
+
+        // Whole requests too, as long as it's the headers that are too long
+        let complete = huge + "\r\n\r\n";
+        assert_eq!(
+            status_line(respond(complete.as_bytes(), &[])),
+            "HTTP/1.1 431 Request Header Fields Too Large"
+        );
+        let body = format!(
+            "GET / HTTP/1.1\r\nContent-Length: {MAX_HEADERS_LEN}\r\n\r\n{}",
+            "a".repeat(MAX_HEADERS_LEN)
+        );
+        assert_eq!(
+            status_line(respond(body.as_bytes(), &[])),
+            "HTTP/1.1 413 Content Too Large"
+        );
+    }
+}
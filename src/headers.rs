@@ -0,0 +1,90 @@
+//! Lookups on parsed HTTP headers. Header names are matched ignoring case,
+//! and list-valued headers like `Connection: keep-alive, Upgrade` can be
+//! read as tokens, whether they come as one line or several.
+
+/// The headers of a request or response, as parsed by httparse.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Headers<'a>(&'a [httparse::Header<'a>]);
+
+impl<'a> Headers<'a> {
+    pub fn new(headers: &'a [httparse::Header<'a>]) -> Self {
+        Headers(headers)
+    }
+
+    /// The values of every header called `name`, in order.
+    pub fn all<'n>(self, name: &'n str) -> impl Iterator<Item = &'a [u8]> + 'n
+    where
+        'a: 'n,
+    {
+        self.0
+            .iter()
+            .filter(move |header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value)
+    }
+
+    /// The value of the first header called `name`.
+    pub fn get(self, name: &str) -> Option<&'a [u8]> {
+        self.all(name).next()
+    }
+
+    /// Whether there is a header called `name`.
+    pub fn contains(self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// The comma separated elements of every header called `name`, trimmed.
+    /// Empty elements and values that aren't UTF-8 are left out.
+    pub fn tokens<'n>(self, name: &'n str) -> impl Iterator<Item = &'a str> + 'n
+    where
+        'a: 'n,
+    {
+        self.all(name)
+            .filter_map(|value| std::str::from_utf8(value).ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+    }
+
+    /// Whether `token` is one of the `tokens` of `name`, ignoring case.
+    pub fn has_token(self, name: &str, token: &str) -> bool {
+        self.tokens(name)
+            .any(|found| found.eq_ignore_ascii_case(token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_ignore_case_and_lists_span_lines() {
+        let parsed = [
+            httparse::Header {
+                name: "connection",
+                value: b"keep-alive, Upgrade",
+            },
+            httparse::Header {
+                name: "UPGRADE",
+                value: b"websocket",
+            },
+            httparse::Header {
+                name: "Sec-WebSocket-Protocol",
+                value: b"chat,, ",
+            },
+            httparse::Header {
+                name: "sec-websocket-protocol",
+                value: b"superchat",
+            },
+        ];
+        let headers = Headers::new(&parsed);
+
+        assert_eq!(headers.get("Upgrade"), Some(&b"websocket"[..]));
+        assert!(headers.has_token("Connection", "upgrade"));
+        assert!(!headers.has_token("Connection", "close"));
+        assert_eq!(
+            headers.tokens("Sec-WebSocket-Protocol").collect::<Vec<_>>(),
+            ["chat", "superchat"]
+        );
+        assert!(!headers.contains("Host"));
+    }
+}
@@ -0,0 +1,146 @@
+use std::fs;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use tokio::time;
+
+/// What to do with a message after inspecting its content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inspection {
+    Pass,
+
+    /// Discard the message and carry on.
+    Drop,
+
+    /// Let the message through, but report it with a reason.
+    Flag(String),
+
+    /// Close the connection with 1008 (Policy Violation) and a reason.
+    Close(String),
+}
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Looks at the content of each inbound message, e.g. to hand it to a spam
+/// or malware scanner. Inspection is async so it can call out to other
+/// services; the connection doesn't read its next message until it's done.
+pub trait Inspector: Send + Sync {
+    fn inspect<'a>(&'a self, message: &'a [u8]) -> BoxFuture<'a, Inspection>;
+}
+
+/// Runs each inspector in turn, stopping at the first one that doesn't pass
+/// the message.
+pub async fn inspect_all(inspectors: &[Box<dyn Inspector>], message: &[u8]) -> Inspection {
+    for inspector in inspectors {
+        match inspector.inspect(message).await {
+            Inspection::Pass => {}
+            other => return other,
+        }
+    }
+
+    Inspection::Pass
+}
+
+/// What the client is sent, with `OnDeadline::Busy`, instead of the echo
+/// of a message that took too long to inspect.
+pub const BUSY: &[u8] = b"busy";
+
+/// What happens to a message whose inspection runs past the deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnDeadline {
+    /// Drop the message and send `BUSY` back instead.
+    Busy,
+
+    /// Close the connection with 1013 (Try Again Later).
+    Close,
+}
+
+/// Runs `inspect_all`, giving up once `deadline` has passed. Returns `None`
+/// if it did. A zero `deadline` waits as long as the inspectors take.
+pub async fn inspect_within(
+    inspectors: &[Box<dyn Inspector>],
+    message: &[u8],
+    deadline: Duration,
+) -> Option<Inspection> {
+    let inspection = inspect_all(inspectors, message);
+    if deadline.is_zero() {
+        return Some(inspection.await);
+    }
+    time::timeout(deadline, inspection).await.ok()
+}
+
+/// What `BlockedPatterns` does when it finds a match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnMatch {
+    Drop,
+    Flag,
+    Close,
+}
+
+/// Matches messages against a list of byte patterns.
+pub struct BlockedPatterns {
+    patterns: Vec<Vec<u8>>,
+    on_match: OnMatch,
+}
+
+impl BlockedPatterns {
+    /// Reads one pattern per line from `path`. Blank lines are ignored.
+    pub fn load(path: &str, on_match: OnMatch) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| format!("failed to read {path}: {err}"))?;
+
+        let patterns = contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| line.as_bytes().to_vec())
+            .collect();
+
+        Ok(BlockedPatterns { patterns, on_match })
+    }
+}
+
+impl Inspector for BlockedPatterns {
+    fn inspect<'a>(&'a self, message: &'a [u8]) -> BoxFuture<'a, Inspection> {
+        let found = self.patterns.iter().find(|pattern| {
+            message
+                .windows(pattern.len())
+                .any(|window| window == pattern.as_slice())
+        });
+
+        let inspection = match found {
+            None => Inspection::Pass,
+            Some(pattern) => {
+                let reason = format!("blocked pattern {:?}", String::from_utf8_lossy(pattern));
+
+                match self.on_match {
+                    OnMatch::Drop => Inspection::Drop,
+                    OnMatch::Flag => Inspection::Flag(reason),
+                    OnMatch::Close => Inspection::Close(reason),
+                }
+            }
+        };
+
+        Box::pin(async move { inspection })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn blocked_patterns() {
+        let inspector = BlockedPatterns {
+            patterns: vec![b"spam".to_vec()],
+            on_match: OnMatch::Close,
+        };
+        let inspectors: Vec<Box<dyn Inspector>> = vec![Box::new(inspector)];
+
+        assert_eq!(inspect_all(&inspectors, b"hello").await, Inspection::Pass);
+        assert_eq!(
+            inspect_all(&inspectors, b"buy spam now").await,
+            Inspection::Close(String::from("blocked pattern \"spam\""))
+        );
+    }
+}
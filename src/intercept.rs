@@ -0,0 +1,151 @@
+/// What should happen to a message after an interceptor has looked at it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Hand the message on to the next layer.
+    Pass,
+
+    /// Discard the message. Later layers don't see it.
+    Drop,
+}
+
+/// A layer that can inspect, rewrite or drop messages on their way in from
+/// a client and on their way back out.
+pub trait Interceptor: Send + Sync {
+    fn inbound(&self, _message: &mut Vec<u8>) -> Action {
+        Action::Pass
+    }
+
+    fn outbound(&self, _message: &mut Vec<u8>) -> Action {
+        Action::Pass
+    }
+
+    /// Sees the payload of a ping from the client, when automatic pongs are
+    /// off. Whatever it holds afterwards is sent back as the pong, so it can
+    /// carry heartbeat data; dropping it sends no pong at all.
+    fn ping(&self, _payload: &mut Vec<u8>) -> Action {
+        Action::Pass
+    }
+}
+
+/// An ordered stack of interceptors. Inbound messages go through the layers
+/// first to last, outbound messages last to first, so the first layer added
+/// is the outermost one.
+#[derive(Default)]
+pub struct Chain {
+    layers: Vec<Box<dyn Interceptor>>,
+}
+
+impl Chain {
+    pub fn new() -> Self {
+        Chain::default()
+    }
+
+    pub fn with(mut self, layer: impl Interceptor + 'static) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    pub fn inbound(&self, message: &mut Vec<u8>) -> Action {
+        for layer in &self.layers {
+            if layer.inbound(message) == Action::Drop {
+                return Action::Drop;
+            }
+        }
+
+        Action::Pass
+    }
+
+    pub fn outbound(&self, message: &mut Vec<u8>) -> Action {
+        for layer in self.layers.iter().rev() {
+            if layer.outbound(message) == Action::Drop {
+                return Action::Drop;
+            }
+        }
+
+        Action::Pass
+    }
+
+    /// Passes a ping payload through the layers first to last, like an
+    /// inbound message.
+    pub fn ping(&self, payload: &mut Vec<u8>) -> Action {
+        for layer in &self.layers {
+            if layer.ping(payload) == Action::Drop {
+                return Action::Drop;
+            }
+        }
+
+        Action::Pass
+    }
+}
+
+/// Prints the size of every message that passes through.
+pub struct LogMessages;
+
+impl Interceptor for LogMessages {
+    fn inbound(&self, message: &mut Vec<u8>) -> Action {
+        println!("<- {} bytes", message.len());
+        Action::Pass
+    }
+
+    fn outbound(&self, message: &mut Vec<u8>) -> Action {
+        println!("-> {} bytes", message.len());
+        Action::Pass
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Append(u8);
+
+    impl Interceptor for Append {
+        fn inbound(&self, message: &mut Vec<u8>) -> Action {
+            message.push(self.0);
+            Action::Pass
+        }
+
+        fn outbound(&self, message: &mut Vec<u8>) -> Action {
+            message.push(self.0);
+            Action::Pass
+        }
+    }
+
+    struct DropEmpty;
+
+    impl Interceptor for DropEmpty {
+        fn inbound(&self, message: &mut Vec<u8>) -> Action {
+            if message.is_empty() {
+                Action::Drop
+            } else {
+                Action::Pass
+            }
+        }
+    }
+
+    #[test]
+    fn layers_run_in_order() {
+        let chain = Chain::new().with(Append(1)).with(Append(2));
+
+        let mut message = vec![];
+        assert_eq!(chain.inbound(&mut message), Action::Pass);
+        assert_eq!(message, [1, 2]);
+
+        let mut message = vec![];
+        assert_eq!(chain.outbound(&mut message), Action::Pass);
+        assert_eq!(message, [2, 1]);
+    }
+
+    #[test]
+    fn drop_stops_the_chain() {
+        let chain = Chain::new().with(DropEmpty).with(Append(1));
+
+        let mut message = vec![];
+        assert_eq!(chain.inbound(&mut message), Action::Drop);
+        assert!(message.is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A block of addresses like `10.0.0.0/8` or `2001:db8::/32`. A bare address
+/// is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let rest_bits = prefix_len % 8;
+
+    if net[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+
+    if rest_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xFF << (8 - rest_bits);
+    net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid CIDR block: {s}");
+
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+
+        let written: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let addr = written.to_canonical();
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        // An IPv4-mapped block like `::ffff:10.0.0.0/104` is matched as the
+        // IPv4 block it maps, so its prefix loses the 96 bits of `::ffff:`
+        let mapped_bits = if written.is_ipv6() && addr.is_ipv4() {
+            96
+        } else {
+            0
+        };
+
+        let prefix_len = match prefix_len {
+            None => max_len,
+            Some(len) => match len.parse::<u8>().map(|len| len.checked_sub(mapped_bits)) {
+                Ok(Some(len)) if len <= max_len => len,
+                _ => return Err(invalid()),
+            },
+        };
+
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+/// Decides which peers may connect. A peer in a denied block is always
+/// refused. If any blocks are allowed, a peer must be in one of them.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Parses a comma separated list of CIDR blocks.
+    pub fn parse_list(list: &str) -> Result<Vec<Cidr>, String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|block| !block.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    /// Reads a filter from a file with one `allow <cidr>` or `deny <cidr>`
+    /// rule per line. Blank lines and lines starting with `#` are ignored.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| format!("failed to read {path}: {err}"))?;
+
+        let mut filter = IpFilter::default();
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once(char::is_whitespace) {
+                Some(("allow", cidr)) => filter.allow.push(cidr.trim().parse()?),
+                Some(("deny", cidr)) => filter.deny.push(cidr.trim().parse()?),
+                _ => return Err(format!("invalid rule in {path}: {line}")),
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains(ip("10.1.255.3")));
+        assert!(!cidr.contains(ip("10.2.0.1")));
+
+        let cidr: Cidr = "192.168.1.128/25".parse().unwrap();
+        assert!(cidr.contains(ip("192.168.1.200")));
+        assert!(!cidr.contains(ip("192.168.1.127")));
+
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(ip("2001:db8:1::1")));
+        assert!(!cidr.contains(ip("10.1.0.1")));
+
+        // IPv4 peers on a dual stack listener show up as mapped addresses
+        let cidr: Cidr = "127.0.0.1".parse().unwrap();
+        assert!(cidr.contains(ip("::ffff:127.0.0.1")));
+
+        // So can the blocks themselves
+        let cidr: Cidr = "::ffff:1.2.3.4".parse().unwrap();
+        assert!(cidr.contains(ip("1.2.3.4")));
+        assert!(!cidr.contains(ip("1.2.3.5")));
+
+        let cidr: Cidr = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert!(cidr.contains(ip("10.200.0.1")));
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+    }
+
+    #[test]
+    fn cidr_parse_errors() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("::ffff:10.0.0.0/95".parse::<Cidr>().is_err());
+        assert!("::ffff:10.0.0.0/129".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = IpFilter {
+            allow: IpFilter::parse_list("10.0.0.0/8").unwrap(),
+            deny: IpFilter::parse_list("10.6.6.0/24").unwrap(),
+        };
+
+        assert!(filter.allows(ip("10.1.2.3")));
+        assert!(!filter.allows(ip("10.6.6.6")));
+        assert!(!filter.allows(ip("192.168.0.1")));
+        assert!(IpFilter::default().allows(ip("192.168.0.1")));
+    }
+}
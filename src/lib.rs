@@ -0,0 +1,55 @@
+//! The WebSocket server behind the `wocket` binary, plus the pieces that
+//! are useful on their own: the opening handshake, upgrade hooks, a
+//! blocking client and server in [`sync`], and test helpers in [`testing`].
+
+pub mod ban;
+pub mod cancel;
+pub mod chaos;
+pub mod check;
+pub mod close;
+pub mod config;
+pub mod conformance;
+pub mod connections;
+pub mod envelope;
+pub mod events;
+#[cfg(unix)]
+pub mod failover;
+#[cfg(unix)]
+pub mod handoff;
+pub mod handshake;
+pub mod headers;
+pub mod inspect;
+pub mod intercept;
+pub mod ipfilter;
+pub mod memory;
+pub mod metrics;
+#[cfg(unix)]
+pub mod notify;
+pub mod policy;
+pub mod pool;
+pub mod pressure;
+pub mod reconnect;
+pub mod recover;
+pub mod rpc;
+pub mod rtt;
+pub mod runtime;
+pub mod sampling;
+pub mod schedule;
+pub mod server;
+pub mod slow;
+pub mod static_files;
+pub mod sync;
+pub mod testing;
+pub mod throttle;
+pub mod trace;
+pub mod transport;
+pub mod ulid;
+pub mod upgrade;
+pub mod upstream;
+
+mod error;
+mod message;
+
+pub use error::{Error, Result};
+pub use message::Message;
+pub use wocket_codec as codec;
@@ -1,216 +1,328 @@
 use std::error::Error;
+use std::fs;
+use std::future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
 
-use base64::prelude::*;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::time::{self, Instant};
+
+use wocket::check;
+use wocket::config::{Config, FailoverRole};
+use wocket::conformance;
+#[cfg(unix)]
+use wocket::notify;
+use wocket::policy;
+use wocket::runtime;
+use wocket::server::{self, Server};
+use wocket::throttle::Throttle;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    if let Some(command) = args.next() {
+        return match (command.as_str(), args.next()) {
+            ("check", None) => check(),
+            ("conformance", Some(url)) => Runtime::new()?.block_on(conformance(url)),
+            _ => Err("usage: wocket [check | conformance ws://host[:port][/path]]".into()),
+        };
+    }
 
-use sha1::Digest;
-use sha1::Sha1;
+    let config = Config::from_env()?;
+    runtime::build(&config)?.block_on(serve(config))
+}
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+/// Runs the server until it is stopped, or hands its listener over.
+async fn serve(config: Config) -> Result<(), Box<dyn Error>> {
+    let server = Arc::new(Server::from_config(config)?);
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let addr = "127.0.0.1:8080";
+    let listener = bind(&server.config).await?;
+    println!("Listening on: {}", listener.local_addr()?);
 
-    let listener = TcpListener::bind(addr).await?;
-    println!("Listening on: {addr}");
+    #[cfg(unix)]
+    notify::notify(notify::READY);
 
-    loop {
-        let (socket, _) = listener.accept().await?;
+    if server.config.ip_filter_file.is_some() && !server.config.ip_filter_reload.is_zero() {
+        tokio::spawn(reload_ip_filter(Arc::clone(&server)));
+    }
 
-        tokio::spawn(handle_client(socket));
+    if server.config.slow_consumer_depth > 0 {
+        tokio::spawn(server::watch_slow_consumers(Arc::clone(&server)));
+    }
+
+    #[cfg(unix)]
+    if let Some(role) = server.config.failover_role {
+        tokio::spawn(failover(Arc::clone(&server), role));
     }
-}
 
-async fn handle_client(mut socket: TcpStream) {
-    let mut buf = vec![0; 1024];
-    let mut done_handshake = false;
+    if server.config.soft_memory_limit > 0 {
+        tokio::spawn(server::watch_memory_pressure(Arc::clone(&server)));
+    }
 
-    loop {
-        let n = socket
-            .read(&mut buf)
-            .await
-            .expect("failed to read data from socket");
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(Arc::clone(&server)));
+    #[cfg(unix)]
+    tokio::spawn(drain_on_signal(Arc::clone(&server)));
+
+    // Accept until a new server takes over the listener or we're told to
+    // stop
+    let terminated = {
+        let handed_off = handed_off(&server.config, &listener);
+        let terminated = terminated();
+        tokio::pin!(handed_off, terminated);
+
+        let mut accepts = match server.config.max_accept_rate {
+            0 => None,
+            rate => Some(Throttle::new(rate, server.config.accept_burst)),
+        };
+
+        loop {
+            let (socket, peer) = tokio::select! {
+                accepted = accept(&listener, &mut accepts) => accepted?,
+                sent = &mut handed_off => {
+                    sent?;
+                    break false;
+                }
+                signalled = &mut terminated => {
+                    signalled?;
+                    break true;
+                }
+            };
+
+            // Refuse filtered peers before reading anything from them
+            let tags = match policy::check_all(&server.accept_policies, peer) {
+                Some(tags) => tags,
+                None => continue,
+            };
+
+            if server.config.log_messages {
+                println!("Connection from {peer} {tags:?}");
+            }
 
-        if n == 0 {
-            return;
+            tokio::spawn(server::handle_client(
+                socket,
+                peer,
+                tags,
+                Arc::clone(&server),
+            ));
         }
+    };
 
-        if !done_handshake {
-            let (response, bad_req) = handshake_response(&buf[0..n]);
+    drop(listener);
 
-            socket
-                .write_all(response.as_bytes())
-                .await
-                .expect("failed to write data to socket");
+    #[cfg(unix)]
+    notify::notify(notify::STOPPING);
 
-            if bad_req {
-                return;
-            }
+    if terminated {
+        println!("Shutting down, closing connections");
+        server.shutdown.send_replace(true);
 
-            done_handshake = true;
-            continue;
-        }
-
-        // Not a handshake, treat it as a WebSocket frame
-        match parse_ws_frame(&buf[0..n]) {
-            Err(_) => return,
-            Ok(message) => {
-                let response = write_ws_frame(&message);
-
-                // Echo back the message
-                socket
-                    .write_all(&response)
-                    .await
-                    .expect("failed to write data to socket");
-            }
+        let timeout = server.config.shutdown_timeout;
+        if time::timeout(timeout, connections_closed(&server))
+            .await
+            .is_err()
+        {
+            println!("Connections still open after {timeout:?}, exiting anyway");
         }
+    } else {
+        // A new server has the listener now, so finish off our connections
+        println!("Handed over the listener, waiting for connections to close");
+        connections_closed(&server).await;
     }
+
+    Ok(())
 }
 
-fn handshake_response(request_buf: &[u8]) -> (String, bool) {
-    let mut headers = [httparse::EMPTY_HEADER; 64];
-    let mut req = httparse::Request::new(&mut headers);
+/// Checks that the server would start with the config in the environment,
+/// and prints the report. Fails if it wouldn't.
+fn check() -> Result<(), Box<dyn Error>> {
+    let report = check::run();
+    println!("{report}");
 
-    req.parse(request_buf)
-        .expect("failed to parse http headers");
+    if !report.passed() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
-    let bad_response = String::from("HTTP/1.1 400 Bad Request\r\n\r\n");
+/// Runs the conformance cases against the server at `url`, and prints the
+/// report. Fails if any case failed.
+async fn conformance(url: String) -> Result<(), Box<dyn Error>> {
+    let report = tokio::task::spawn_blocking(move || conformance::run(&url)).await?;
+    println!("{report}");
 
-    if req.method != Some("GET") {
-        return (bad_response, true);
+    if !report.passed() {
+        std::process::exit(1);
     }
+    Ok(())
+}
 
-    let key_header = headers
-        .iter()
-        .find(|&header| header.name == "Sec-WebSocket-Key");
+/// Accepts the next connection, once `accepts` allows another. Until
+/// then, new connections wait in the listen backlog.
+async fn accept(
+    listener: &TcpListener,
+    accepts: &mut Option<Throttle>,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    if let Some(accepts) = accepts {
+        time::sleep(accepts.take(1, Instant::now())).await;
+    }
+    listener.accept().await
+}
 
-    let key_value = match key_header {
-        Some(header) => header.value,
-        _ => return (bad_response, true),
-    };
+async fn connections_closed(server: &Server) {
+    while server.active_connections.load(Ordering::Relaxed) > 0 {
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
 
-    let response = format!(
-        "HTTP/1.1 101 Switching Protocols\r\n\
-        Upgrade: websocket\r\n\
-        Connection: Upgrade\r\n\
-        Sec-WebSocket-Accept: {}\r\n\r\n",
-        accept_value(key_value),
-    );
+/// Resolves on SIGTERM or SIGINT.
+#[cfg(unix)]
+async fn terminated() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
 
-    (response, false)
-}
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
 
-fn accept_value(key_value: &[u8]) -> String {
-    let mut hasher = Sha1::new();
-    hasher.update(key_value);
-    hasher.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
-    let hashed = hasher.finalize();
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
 
-    BASE64_STANDARD.encode(hashed)
+    Ok(())
 }
 
-fn parse_ws_frame(frame_buf: &[u8]) -> Result<Vec<u8>, &'static str> {
-    if frame_buf.len() < 2 {
-        return Err("Reached end of frame while parsing");
-    }
+/// Resolves on Ctrl-C.
+#[cfg(not(unix))]
+async fn terminated() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
 
-    let fin_bit = frame_buf[0] >> 7;
-    if fin_bit != 1 {
-        return Err("FIN bit not 1, payload is fragmented which we don't support");
+/// Binds the listener, or takes it from the server being replaced.
+async fn bind(config: &Config) -> io::Result<TcpListener> {
+    #[cfg(unix)]
+    if let (true, Some(path)) = (config.takeover, &config.handoff_socket) {
+        let listener = wocket::handoff::take_listener(path)?;
+        listener.set_nonblocking(true)?;
+        return TcpListener::from_std(listener);
     }
 
-    let opcode = frame_buf[0] & 0xF;
-    if opcode != 0x2 {
-        return Err("Opcode field is not 0x2 which means the message is not binary");
+    #[cfg(unix)]
+    if config.failover_role.is_some() {
+        return wocket::failover::bind(&config.addr).await;
     }
 
-    let mask_bit = frame_buf[1] >> 7;
-    if mask_bit != 1 {
-        return Err("Mask bit not 1, should never happen");
-    }
+    TcpListener::bind(&config.addr).await
+}
 
-    let mut payload_len = (frame_buf[1] & 0b0111_1111) as u16;
-    let mut mask_idx = 2;
+/// Plays this server's part in a failover pair: a standby waits for the
+/// primary to go before taking upgrades, and then either sends heartbeats.
+#[cfg(unix)]
+async fn failover(server: Arc<Server>, role: FailoverRole) {
+    let config = &server.config;
+    // The config can't have a role without a socket
+    let Some(path) = &config.failover_socket else {
+        return;
+    };
 
-    if payload_len == 127 {
-        return Err("Message longer than 65535 bytes, not supported");
+    if role == FailoverRole::Standby {
+        wocket::failover::primary_gone(path, config.failover_after).await;
+        println!("The primary has gone, taking over");
+        server.promote();
     }
 
-    if payload_len == 126 {
-        // This means the real length does not fit in 7 bits, and it is a 16 bit
-        // unsigned integer starting at frame_buf[2]
-        if frame_buf.len() < 4 {
-            return Err("Reached end of frame while parsing");
-        }
-
-        payload_len = ((frame_buf[2] as u16) << 8) | (frame_buf[3] as u16);
-        mask_idx = 4;
+    if let Err(err) = wocket::failover::send_heartbeats(path, config.failover_heartbeat).await {
+        println!("Failed to send heartbeats on {}: {err}", path.display());
     }
+}
 
-    let masking_key = &frame_buf[mask_idx..mask_idx + 4];
-    let payload = &frame_buf[mask_idx + 4..];
-
-    let mut message = vec![];
-    for i in 0..(payload_len as usize) {
-        message.push(payload[i] ^ masking_key[i % 4]);
+/// Resolves once a new server has taken over the listener. Never resolves
+/// without a handoff socket.
+async fn handed_off(config: &Config, listener: &TcpListener) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(path) = &config.handoff_socket {
+        use std::os::fd::AsRawFd;
+        return wocket::handoff::give_listener(path, listener.as_raw_fd()).await;
     }
 
-    Ok(message)
+    future::pending().await
 }
 
-fn write_ws_frame(message: &[u8]) -> Vec<u8> {
-    let mut frame = vec![];
+/// Reloads the IP filter whenever its file is modified. If the new rules
+/// can't be loaded, the old ones stay in place.
+async fn reload_ip_filter(server: Arc<Server>) {
+    let path = server.config.ip_filter_file.as_deref().unwrap_or_default();
+    let modified = || fs::metadata(path).and_then(|meta| meta.modified()).ok();
 
-    // FIN bit is 1, opcode field is binary (0x2)
-    frame.push(0b1000_0010);
+    let mut last_modified = modified();
+    let mut interval = time::interval(server.config.ip_filter_reload);
 
-    // Push message length
-    if message.len() <= 125 {
-        frame.push(message.len() as u8);
-    } else {
-        frame.push(126);
-        frame.push((message.len() >> 8) as u8);
-        frame.push((message.len() & 0xFF) as u8);
-    }
+    loop {
+        interval.tick().await;
 
-    frame.extend_from_slice(message);
+        let now_modified = modified();
+        if now_modified == last_modified {
+            continue;
+        }
+        last_modified = now_modified;
 
-    frame
+        reload(&server);
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Reloads the IP filter on every SIGHUP. Nothing else is reloaded: the
+/// rest of the config comes from the environment, which can't change under
+/// a running process, and the other files it names are only read at
+/// startup.
+#[cfg(unix)]
+async fn reload_on_hangup(server: Arc<Server>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            println!("Can't reload on SIGHUP: {err}");
+            return;
+        }
+    };
 
-    #[test]
-    fn test_accept_value() {
-        assert_eq!(
-            accept_value(b"dGhlIHNhbXBsZSBub25jZQ=="),
-            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
-        );
+    while hangup.recv().await.is_some() {
+        reload(&server);
     }
+}
 
-    #[test]
-    fn parse_short() {
-        let mut buf = vec![];
-        buf.push(0b1000_0010); // FIN bit 1, opcode type binary (0x2)
-        buf.push(0b1000_0101); // Mask bit 1, length 5
-
-        // 4 byte masking key (random)
-        buf.push(0x12);
-        buf.push(0x34);
-        buf.push(0xab);
-        buf.push(0xcd);
+/// Starts draining on SIGUSR1. Later signals are ignored.
+#[cfg(unix)]
+async fn drain_on_signal(server: Arc<Server>) {
+    use tokio::signal::unix::{signal, SignalKind};
 
-        buf.push(b'H' ^ 0x12);
-        buf.push(b'e' ^ 0x34);
-        buf.push(b'l' ^ 0xab);
-        buf.push(b'l' ^ 0xcd);
-        buf.push(b'o' ^ 0x12);
+    let mut usr1 = match signal(SignalKind::user_defined1()) {
+        Ok(usr1) => usr1,
+        Err(err) => {
+            println!("Can't drain on SIGUSR1: {err}");
+            return;
+        }
+    };
 
-        let expected = b"Hello".to_vec();
+    if usr1.recv().await.is_some() {
+        println!(
+            "Draining, closing connections in {:?}",
+            server.config.drain_timeout
+        );
+        server.drain().await;
+    }
+}
 
-        assert_eq!(parse_ws_frame(&buf), Ok(expected));
+/// Rebuilds the IP filter, keeping the old one if the new rules can't be
+/// loaded.
+fn reload(server: &Server) {
+    match server.config.ip_filter() {
+        Ok(filter) => {
+            *server.ip_filter.write().unwrap() = filter;
+            println!("Reloaded IP filter");
+        }
+        Err(err) => println!("Keeping old IP filter: {err}"),
     }
 }
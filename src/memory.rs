@@ -0,0 +1,112 @@
+//! Accounting for the bytes connections have buffered, so the server as a
+//! whole can be held under a memory cap.
+//!
+//! Each connection keeps an `Account` with the total capacity of its read
+//! buffer, the message it is reassembling and its pending writes. When the
+//! sum over all connections passes the cap, any connection holding more
+//! than its fair share (the cap split evenly between open connections) is
+//! closed, so the most bloated ones go first. New upgrades are deferred
+//! once usage gets within a tenth of the cap.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Bytes buffered across every connection.
+#[derive(Debug, Default)]
+pub struct MemoryUsage {
+    used: AtomicUsize,
+
+    /// Most bytes all connections may buffer between them. Zero means no
+    /// limit, but usage is still tracked.
+    pub cap: usize,
+}
+
+impl MemoryUsage {
+    pub fn new(cap: usize) -> Arc<Self> {
+        Arc::new(MemoryUsage {
+            used: AtomicUsize::new(0),
+            cap,
+        })
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Whether usage is close enough to the cap that new connections
+    /// should be turned away.
+    pub fn nearly_full(&self) -> bool {
+        self.cap > 0 && self.used() >= self.cap - self.cap / 10
+    }
+
+    /// Starts accounting for one connection. Its bytes are released when
+    /// the account is dropped.
+    pub fn account(self: &Arc<Self>) -> Account {
+        Account {
+            usage: Arc::clone(self),
+            bytes: 0,
+        }
+    }
+}
+
+/// The bytes one connection has buffered.
+#[derive(Debug)]
+pub struct Account {
+    usage: Arc<MemoryUsage>,
+    bytes: usize,
+}
+
+impl Account {
+    /// Records that the connection now has `bytes` buffered.
+    pub fn set(&mut self, bytes: usize) {
+        if bytes > self.bytes {
+            self.usage
+                .used
+                .fetch_add(bytes - self.bytes, Ordering::Relaxed);
+        } else {
+            self.usage
+                .used
+                .fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+        }
+        self.bytes = bytes;
+    }
+
+    /// Whether the server is over its cap and this connection holds more
+    /// than its share of it with `open` connections.
+    pub fn over_share(&self, open: usize) -> bool {
+        let cap = self.usage.cap;
+        cap > 0 && self.usage.used() > cap && self.bytes > cap / open.max(1)
+    }
+}
+
+impl Drop for Account {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_biggest_connection_is_over_its_share() {
+        let usage = MemoryUsage::new(100);
+        let mut small = usage.account();
+        let mut big = usage.account();
+
+        small.set(20);
+        big.set(70);
+        assert!(usage.nearly_full());
+        assert!(!big.over_share(2));
+
+        big.set(90);
+        assert_eq!(usage.used(), 110);
+        assert!(big.over_share(2));
+        assert!(!small.over_share(2));
+
+        drop(big);
+        assert_eq!(usage.used(), 20);
+        assert!(!usage.nearly_full());
+    }
+}
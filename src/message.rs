@@ -0,0 +1,59 @@
+use std::mem;
+
+use crate::codec::{CloseFrame, Event, WsConnection};
+use crate::{Error, Result};
+
+/// A message received from or sent to the peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+
+    /// The close handshake. Received, it carries the peer's status code and
+    /// reason if it sent one. Sent without a frame, it closes with 1000
+    /// (Normal Closure).
+    Close(Option<CloseFrame>),
+}
+
+impl Message {
+    /// Queues this message on `conn`.
+    pub(crate) fn queue(&self, conn: &mut WsConnection) -> Result<()> {
+        if conn.is_closing() {
+            return Err(Error::Closed);
+        }
+
+        let queued = match self {
+            Message::Text(text) => conn.send_text(text),
+            Message::Binary(data) => conn.send_binary(data),
+            Message::Ping(data) => conn.send_ping(data),
+            Message::Pong(data) => conn.send_pong(data),
+            Message::Close(frame) => {
+                match frame {
+                    Some(frame) => conn.close(frame.code, &frame.reason),
+                    None => conn.close(1000, ""),
+                }
+                Ok(())
+            }
+        };
+
+        queued.map_err(Error::InvalidMessage)
+    }
+
+    /// Turns an event from `WsConnection::receive` into a message. Data
+    /// messages are taken out of the `message` buffer they were unmasked
+    /// into.
+    pub(crate) fn from_event(event: Event, message: &mut Vec<u8>) -> Message {
+        match event {
+            Event::Binary => Message::Binary(mem::take(message)),
+            Event::Text => {
+                let text = mem::take(message);
+                Message::Text(String::from_utf8(text).expect("checked by WsConnection"))
+            }
+            Event::Ping(data) => Message::Ping(data),
+            Event::Pong(data) => Message::Pong(data),
+            Event::Close(frame) => Message::Close(frame),
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! Counters kept by the server, served in the Prometheus text format at
+//! `metrics_path`.
+//!
+//! Closed connections are counted by whichever of their close code, route
+//! and subprotocol are configured as labels. Clients choose paths and
+//! subprotocols, so there is a cap on how many label combinations are
+//! kept; closes with a new combination past the cap are counted under
+//! `other` for every label.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::handshake::Rejection;
+
+/// Something closed connections can be counted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Label {
+    Code,
+    Route,
+    Subprotocol,
+}
+
+impl Label {
+    /// Parses a comma separated list of `code`, `route` and `subprotocol`.
+    pub fn parse_list(list: &str) -> Result<Vec<Label>, String> {
+        let mut labels = Vec::new();
+        for name in list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let label = match name {
+                "code" => Label::Code,
+                "route" => Label::Route,
+                "subprotocol" => Label::Subprotocol,
+                _ => return Err(format!("unknown metric label: {name}")),
+            };
+            if !labels.contains(&label) {
+                labels.push(label);
+            }
+        }
+        Ok(labels)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Label::Code => "code",
+            Label::Route => "route",
+            Label::Subprotocol => "subprotocol",
+        }
+    }
+}
+
+/// What is known about a connection when it closes.
+#[derive(Debug, Clone, Copy)]
+pub struct Closed<'a> {
+    /// The close code's name, from `CloseCodes::name`.
+    pub code: &'a str,
+
+    /// The path it was upgraded on, without the query.
+    pub route: &'a str,
+
+    pub subprotocol: Option<&'a str>,
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    /// Refused upgrades, indexed by `Rejection`.
+    handshake_failures: [AtomicU64; Rejection::ALL.len()],
+
+    /// Closed connections, by their rendered labels.
+    closes: Mutex<BTreeMap<String, u64>>,
+
+    labels: Vec<Label>,
+
+    /// Most label combinations kept in `closes`, not counting `other`.
+    max_series: usize,
+
+    /// Times a connection was found to be a slow consumer.
+    slow_consumers_total: AtomicU64,
+
+    /// Connections that are slow consumers right now.
+    slow_consumers: AtomicU64,
+
+    /// Connections whose task panicked.
+    panics_total: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new(vec![Label::Code], 100)
+    }
+}
+
+impl Metrics {
+    /// Counts closed connections by `labels`, in that order, and by at most
+    /// `max_series` combinations of them.
+    pub fn new(labels: Vec<Label>, max_series: usize) -> Self {
+        Metrics {
+            handshake_failures: Default::default(),
+            closes: Mutex::default(),
+            labels,
+            max_series,
+            slow_consumers_total: AtomicU64::new(0),
+            slow_consumers: AtomicU64::new(0),
+            panics_total: AtomicU64::new(0),
+        }
+    }
+
+    pub fn handshake_failed(&self, reason: Rejection) {
+        self.handshake_failures[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake_failures(&self, reason: Rejection) -> u64 {
+        self.handshake_failures[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// Counts a closed connection.
+    pub fn closed(&self, closed: Closed) {
+        let series = self.series(|label| match label {
+            Label::Code => closed.code,
+            Label::Route => closed.route,
+            Label::Subprotocol => closed.subprotocol.unwrap_or_default(),
+        });
+
+        let mut closes = self.closes.lock().unwrap();
+        if let Some(count) = closes.get_mut(&series) {
+            *count += 1;
+            return;
+        }
+
+        let other = self.series(|_| "other");
+        let series = match closes.len() - usize::from(closes.contains_key(&other)) {
+            kept if kept < self.max_series => series,
+            _ => other,
+        };
+        *closes.entry(series).or_insert(0) += 1;
+    }
+
+    /// The labels for a series, e.g. `code="normal",route="/chat"`, with
+    /// `value` giving each label's value.
+    fn series<'a>(&self, value: impl Fn(Label) -> &'a str) -> String {
+        let mut series = String::new();
+        for &label in &self.labels {
+            if !series.is_empty() {
+                series.push(',');
+            }
+            let _ = write!(series, "{}=\"", label.name());
+            for c in value(label).chars() {
+                match c {
+                    '\\' => series.push_str("\\\\"),
+                    '"' => series.push_str("\\\""),
+                    '\n' => series.push_str("\\n"),
+                    c => series.push(c),
+                }
+            }
+            series.push('"');
+        }
+        series
+    }
+
+    pub fn slow_consumer(&self) {
+        self.slow_consumers_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_slow_consumers(&self, count: usize) {
+        self.slow_consumers.store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn panicked(&self) {
+        self.panics_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Everything in the Prometheus text format, along with the number of
+    /// open connections.
+    pub fn render(&self, open_connections: usize) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP wocket_open_connections Open WebSocket connections.\n");
+        out.push_str("# TYPE wocket_open_connections gauge\n");
+        let _ = writeln!(out, "wocket_open_connections {open_connections}");
+
+        out.push_str("# HELP wocket_handshake_failures_total Refused upgrades by reason.\n");
+        out.push_str("# TYPE wocket_handshake_failures_total counter\n");
+        for reason in Rejection::ALL {
+            let _ = writeln!(
+                out,
+                "wocket_handshake_failures_total{{reason=\"{}\"}} {}",
+                reason.name(),
+                self.handshake_failures(reason),
+            );
+        }
+
+        out.push_str("# HELP wocket_closes_total Closed connections.\n");
+        out.push_str("# TYPE wocket_closes_total counter\n");
+        for (series, count) in self.closes.lock().unwrap().iter() {
+            let _ = match series.is_empty() {
+                true => writeln!(out, "wocket_closes_total {count}"),
+                false => writeln!(out, "wocket_closes_total{{{series}}} {count}"),
+            };
+        }
+
+        out.push_str("# HELP wocket_slow_consumers Connections whose outbox is staying full.\n");
+        out.push_str("# TYPE wocket_slow_consumers gauge\n");
+        let slow = self.slow_consumers.load(Ordering::Relaxed);
+        let _ = writeln!(out, "wocket_slow_consumers {slow}");
+
+        out.push_str(
+            "# HELP wocket_slow_consumers_total Times a connection became a slow consumer.\n",
+        );
+        out.push_str("# TYPE wocket_slow_consumers_total counter\n");
+        let total = self.slow_consumers_total.load(Ordering::Relaxed);
+        let _ = writeln!(out, "wocket_slow_consumers_total {total}");
+
+        out.push_str(
+            "# HELP wocket_panics_total Connections closed because their task panicked.\n",
+        );
+        out.push_str("# TYPE wocket_panics_total counter\n");
+        let panics = self.panics_total.load(Ordering::Relaxed);
+        let _ = writeln!(out, "wocket_panics_total {panics}");
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_are_counted_by_reason() {
+        let metrics = Metrics::default();
+        metrics.handshake_failed(Rejection::BadVersion);
+        metrics.handshake_failed(Rejection::BadVersion);
+        let closed = |code| Closed {
+            code,
+            route: "/",
+            subprotocol: None,
+        };
+        metrics.closed(closed("normal"));
+        metrics.closed(closed("credentials_expired"));
+        metrics.closed(closed("normal"));
+        metrics.slow_consumer();
+        metrics.set_slow_consumers(1);
+
+        let rendered = metrics.render(3);
+        assert!(rendered.contains("\nwocket_open_connections 3\n"));
+        assert!(rendered.contains("{reason=\"bad_version\"} 2\n"));
+        assert!(rendered.contains("{reason=\"malformed\"} 0\n"));
+        assert!(rendered.contains("wocket_closes_total{code=\"normal\"} 2\n"));
+        assert!(rendered.contains("{code=\"credentials_expired\"} 1\n"));
+        assert!(rendered.contains("\nwocket_slow_consumers 1\n"));
+        assert!(rendered.contains("\nwocket_slow_consumers_total 1\n"));
+    }
+
+    #[test]
+    fn routes_are_capped() {
+        let labels = Label::parse_list("route, subprotocol").unwrap();
+        assert!(Label::parse_list("code,peer").is_err());
+
+        let metrics = Metrics::new(labels, 2);
+        for route in ["/users/1", "/users/2", "/users/3", "/users/4", "/users/1"] {
+            metrics.closed(Closed {
+                code: "normal",
+                route,
+                subprotocol: Some("chat\"v2"),
+            });
+        }
+
+        let rendered = metrics.render(0);
+        let sub = "subprotocol=\"chat\\\"v2\"";
+        assert!(rendered.contains(&format!("{{route=\"/users/1\",{sub}}} 2\n")));
+        assert!(rendered.contains(&format!("{{route=\"/users/2\",{sub}}} 1\n")));
+        assert!(rendered.contains("{route=\"other\",subprotocol=\"other\"} 2\n"));
+        assert!(!rendered.contains("/users/3"));
+    }
+}
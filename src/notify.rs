@@ -0,0 +1,62 @@
+//! Lifecycle notifications for service managers.
+//!
+//! Under systemd with `Type=notify`, `NOTIFY_SOCKET` names a datagram
+//! socket the server reports `READY=1` to once it is listening, and
+//! `STOPPING=1` to when it starts shutting down or handing off. Without
+//! `NOTIFY_SOCKET` nothing is sent, so running in the foreground under
+//! launchd or anything else works as before.
+
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+/// The server is listening and accepting connections.
+pub const READY: &str = "READY=1";
+
+/// The server has stopped accepting and is closing its connections.
+pub const STOPPING: &str = "STOPPING=1";
+
+/// Sends `state` to the service manager, if there is one. Failures are
+/// ignored, as the server works the same either way.
+pub fn notify(state: &str) {
+    if let Some(path) = env::var_os("NOTIFY_SOCKET") {
+        let _ = send(Path::new(&path), state);
+    }
+}
+
+fn send(path: &Path, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+
+    // A leading '@' is a socket in the abstract namespace
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.to_str().and_then(|path| path.strip_prefix('@')) {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr;
+
+        let addr = SocketAddr::from_abstract_name(name)?;
+        return socket.send_to_addr(state.as_bytes(), &addr).map(drop);
+    }
+
+    socket.send_to(state.as_bytes(), path).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_the_state() {
+        let path = env::temp_dir().join(format!("wocket-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+
+        send(&path, READY).unwrap();
+
+        let mut buf = [0; 64];
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], READY.as_bytes());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use crate::ipfilter::{Cidr, IpFilter};
+
+/// The outcome of checking a new connection against an accept policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+
+    /// Accept the connection and attach a tag to it, e.g. `country=NZ`.
+    Tag(String),
+
+    Reject,
+}
+
+/// Decides whether to accept a connection, based only on the peer address.
+/// Policies run right after accept, before anything is read from the socket.
+pub trait AcceptPolicy: Send + Sync {
+    fn check(&self, peer: SocketAddr) -> Verdict;
+}
+
+/// Runs each policy in turn. Returns `None` if any of them rejects the peer,
+/// otherwise the tags they attached.
+pub fn check_all(policies: &[Box<dyn AcceptPolicy>], peer: SocketAddr) -> Option<Vec<String>> {
+    let mut tags = vec![];
+
+    for policy in policies {
+        match policy.check(peer) {
+            Verdict::Accept => {}
+            Verdict::Tag(tag) => tags.push(tag),
+            Verdict::Reject => return None,
+        }
+    }
+
+    Some(tags)
+}
+
+impl AcceptPolicy for IpFilter {
+    fn check(&self, peer: SocketAddr) -> Verdict {
+        if self.allows(peer.ip()) {
+            Verdict::Accept
+        } else {
+            Verdict::Reject
+        }
+    }
+}
+
+impl<P: AcceptPolicy> AcceptPolicy for RwLock<P> {
+    fn check(&self, peer: SocketAddr) -> Verdict {
+        self.read().unwrap().check(peer)
+    }
+}
+
+impl<P: AcceptPolicy + ?Sized> AcceptPolicy for Arc<P> {
+    fn check(&self, peer: SocketAddr) -> Verdict {
+        (**self).check(peer)
+    }
+}
+
+/// Looks peers up in a table of address ranges and country codes, tags them
+/// with `country=<code>`, and rejects those from blocked countries.
+///
+/// The table is a CSV file with a `<cidr>,<country>` pair per line, the
+/// format most GeoIP databases can be exported to.
+pub struct GeoTable {
+    ranges: Vec<(Cidr, String)>,
+    blocked: Vec<String>,
+}
+
+impl GeoTable {
+    pub fn load(path: &str, blocked: Vec<String>) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| format!("failed to read {path}: {err}"))?;
+
+        let mut ranges = vec![];
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once(',') {
+                Some((cidr, country)) => {
+                    ranges.push((cidr.trim().parse()?, country.trim().to_uppercase()))
+                }
+                None => return Err(format!("invalid line in {path}: {line}")),
+            }
+        }
+
+        Ok(GeoTable { ranges, blocked })
+    }
+}
+
+impl AcceptPolicy for GeoTable {
+    fn check(&self, peer: SocketAddr) -> Verdict {
+        let country = self
+            .ranges
+            .iter()
+            .find(|(cidr, _)| cidr.contains(peer.ip()))
+            .map(|(_, country)| country);
+
+        match country {
+            None => Verdict::Accept,
+            Some(country) if self.blocked.contains(country) => Verdict::Reject,
+            Some(country) => Verdict::Tag(format!("country={country}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geo_table_tags_and_blocks() {
+        let table = GeoTable {
+            ranges: vec![
+                ("10.0.0.0/8".parse().unwrap(), String::from("NZ")),
+                ("192.168.0.0/16".parse().unwrap(), String::from("XX")),
+            ],
+            blocked: vec![String::from("XX")],
+        };
+
+        let policies: Vec<Box<dyn AcceptPolicy>> = vec![Box::new(table)];
+
+        let peer = |s: &str| s.parse().unwrap();
+        assert_eq!(
+            check_all(&policies, peer("10.1.1.1:80")),
+            Some(vec![String::from("country=NZ")])
+        );
+        assert_eq!(check_all(&policies, peer("192.168.1.1:80")), None);
+        assert_eq!(check_all(&policies, peer("172.16.0.1:80")), Some(vec![]));
+    }
+}
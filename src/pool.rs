@@ -0,0 +1,103 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// A pool of byte buffers that are handed out to connections and returned
+/// when dropped, so idle and short-lived connections don't hit the allocator
+/// on every read and write.
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    buffer_size: usize,
+    capacity: usize,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, capacity: usize) -> Arc<Self> {
+        Arc::new(BufferPool {
+            buffers: Mutex::new(Vec::new()),
+            buffer_size,
+            capacity,
+        })
+    }
+
+    /// Takes an empty buffer from the pool, allocating one if the pool is
+    /// empty. The buffer has at least `buffer_size` bytes of capacity.
+    pub fn get(self: &Arc<Self>) -> PooledBuf {
+        let buf = self
+            .buffers
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(self.buffer_size));
+
+        PooledBuf {
+            buf,
+            pool: Arc::clone(self),
+        }
+    }
+
+    /// Frees every idle buffer. The pool fills up again as buffers come
+    /// back.
+    pub fn clear(&self) {
+        self.buffers.lock().unwrap().clear();
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        // Buffers that grew far past the configured size are dropped rather
+        // than pinning that memory in the pool forever
+        if buf.capacity() > self.buffer_size * 4 {
+            return;
+        }
+
+        buf.clear();
+
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.capacity {
+            buffers.push(buf);
+        }
+    }
+}
+
+/// A buffer on loan from a `BufferPool`.
+pub struct PooledBuf {
+    buf: Vec<u8>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_reused() {
+        let pool = BufferPool::new(64, 1);
+
+        let mut buf = pool.get();
+        buf.extend_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+        drop(buf);
+
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+    }
+}
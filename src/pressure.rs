@@ -0,0 +1,185 @@
+//! A soft limit on the process's resident memory. As the process gets
+//! close to it, the server gives things up in order rather than growing
+//! until it is OOM-killed: first it defers new upgrades, then it shrinks
+//! buffers, then it closes connections that have gone quiet.
+//!
+//! Unlike `max_memory`, which only counts what connections buffer, this
+//! looks at the whole process as the kernel sees it.
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/// How often resident memory is read.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the server has given up, from least to most. Each level keeps the
+/// ones before it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Degradation {
+    #[default]
+    Normal,
+
+    /// New upgrades get a 503, from 80% of the limit.
+    StopAccepting,
+
+    /// Idle pooled buffers are freed and read buffers only keep what is in
+    /// them, from 90%.
+    ShrinkBuffers,
+
+    /// Connections that haven't sent anything for `idle_close_after` are
+    /// closed with 1013, from the limit itself.
+    CloseIdle,
+}
+
+impl Degradation {
+    const LEVELS: [Degradation; 3] = [
+        Degradation::StopAccepting,
+        Degradation::ShrinkBuffers,
+        Degradation::CloseIdle,
+    ];
+
+    /// The share of the limit at which this level starts, in percent.
+    fn threshold(self) -> usize {
+        match self {
+            Degradation::Normal => 0,
+            Degradation::StopAccepting => 80,
+            Degradation::ShrinkBuffers => 90,
+            Degradation::CloseIdle => 100,
+        }
+    }
+}
+
+impl fmt::Display for Degradation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Degradation::Normal => "normal",
+            Degradation::StopAccepting => "not accepting",
+            Degradation::ShrinkBuffers => "shrinking buffers",
+            Degradation::CloseIdle => "closing idle connections",
+        })
+    }
+}
+
+/// The current level, shared by the watcher that sets it and connections
+/// that act on it.
+#[derive(Debug)]
+pub struct MemoryPressure {
+    limit: usize,
+    level: watch::Sender<Degradation>,
+}
+
+impl MemoryPressure {
+    /// Zero `limit` means no limit, and the level stays normal.
+    pub fn new(limit: usize) -> Self {
+        MemoryPressure {
+            limit,
+            level: watch::Sender::new(Degradation::Normal),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn level(&self) -> Degradation {
+        *self.level.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Degradation> {
+        self.level.subscribe()
+    }
+
+    /// Moves to the level for `resident` bytes. Returns the new level if it
+    /// changed.
+    pub fn update(&self, resident: usize) -> Option<Degradation> {
+        if self.limit == 0 {
+            return None;
+        }
+
+        let changed = self.level.send_if_modified(|current| {
+            let level = level_for(self.limit, *current, resident);
+            std::mem::replace(current, level) != level
+        });
+        changed.then(|| self.level())
+    }
+}
+
+/// The level for `resident` bytes under `limit`, coming from `current`. A
+/// level is only left once usage is a twentieth of the limit below where
+/// it starts, so the server doesn't flap around a threshold.
+fn level_for(limit: usize, current: Degradation, resident: usize) -> Degradation {
+    let percent = |level: Degradation| limit / 100 * level.threshold();
+    let slack = limit / 20;
+
+    Degradation::LEVELS
+        .into_iter()
+        .rev()
+        .find(|&level| {
+            let start = percent(level);
+            resident >= start || (level <= current && resident + slack >= start)
+        })
+        .unwrap_or(Degradation::Normal)
+}
+
+/// The process's resident memory in bytes.
+#[cfg(target_os = "linux")]
+pub fn resident() -> io::Result<usize> {
+    // The second field of statm is resident pages
+    let statm = std::fs::read_to_string("/proc/self/statm")?;
+    let pages: usize = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pages| pages.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unexpected /proc/self/statm"))?;
+
+    // SAFETY: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Ok(pages * usize::try_from(page_size).unwrap_or(4096))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn resident() -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "resident memory can only be read on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_come_in_order_and_leave_with_slack() {
+        use Degradation::*;
+
+        let limit = 1000;
+        assert_eq!(level_for(limit, Normal, 500), Normal);
+        assert_eq!(level_for(limit, Normal, 850), StopAccepting);
+        assert_eq!(level_for(limit, Normal, 950), ShrinkBuffers);
+        assert_eq!(level_for(limit, Normal, 1200), CloseIdle);
+
+        // Just under a threshold keeps the level the server is already at
+        assert_eq!(level_for(limit, CloseIdle, 980), CloseIdle);
+        assert_eq!(level_for(limit, CloseIdle, 940), ShrinkBuffers);
+        assert_eq!(level_for(limit, StopAccepting, 760), StopAccepting);
+        assert_eq!(level_for(limit, StopAccepting, 700), Normal);
+    }
+
+    #[test]
+    fn changes_are_reported_once() {
+        let pressure = MemoryPressure::new(1000);
+        let mut level = pressure.subscribe();
+
+        assert_eq!(pressure.update(950), Some(Degradation::ShrinkBuffers));
+        assert!(level.has_changed().unwrap());
+        level.mark_unchanged();
+        assert_eq!(pressure.update(960), None);
+        assert!(!level.has_changed().unwrap());
+
+        assert!(resident().map_or(true, |bytes| bytes > 0));
+    }
+}
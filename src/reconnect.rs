@@ -0,0 +1,243 @@
+//! A blocking client that survives its connection dropping, for spotty
+//! mobile and IoT links.
+//!
+//! Messages sent while the connection is down wait in a bounded queue, and
+//! go out in the order they were sent once `reconnect` succeeds. When the
+//! queue is full, its `Overflow` policy decides which message loses out.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::sync::{self, WebSocket};
+use crate::{Error, Message, Result};
+
+/// What happens to a message sent while offline with the queue full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// The oldest queued message is dropped to make room, for updates
+    /// where only the latest ones matter.
+    #[default]
+    DropOldest,
+
+    /// The new message is dropped, keeping the ones sent first.
+    DropNewest,
+
+    /// The send fails with `Error::QueueFull`, so the caller decides.
+    Reject,
+}
+
+/// Messages waiting for the connection to come back.
+#[derive(Debug, Default)]
+pub struct OfflineQueue {
+    messages: VecDeque<Message>,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+impl OfflineQueue {
+    /// Zero `capacity` queues nothing, so every send while offline fails.
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        OfflineQueue {
+            messages: VecDeque::new(),
+            capacity,
+            overflow,
+        }
+    }
+
+    /// Queues `message` behind the others, or drops a message according to
+    /// the overflow policy if the queue is full.
+    pub fn push(&mut self, message: Message) -> Result<()> {
+        if self.capacity == 0 {
+            return Err(Error::Closed);
+        }
+
+        if self.messages.len() >= self.capacity {
+            match self.overflow {
+                Overflow::DropOldest => {
+                    self.messages.pop_front();
+                }
+                Overflow::DropNewest => return Ok(()),
+                Overflow::Reject => return Err(Error::QueueFull),
+            }
+        }
+        self.messages.push_back(message);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+/// A connection to one `ws://` URL that can be reopened after it fails.
+pub struct ReconnectingWebSocket {
+    url: String,
+    socket: Option<WebSocket>,
+    queue: OfflineQueue,
+    keep_alive: Option<(Duration, Duration)>,
+}
+
+impl ReconnectingWebSocket {
+    /// Connects to `url`. Failing to is an error, since a URL that never
+    /// worked is more likely wrong than offline.
+    pub fn connect(url: &str) -> Result<Self> {
+        Ok(ReconnectingWebSocket {
+            url: String::from(url),
+            socket: Some(sync::connect(url)?),
+            queue: OfflineQueue::default(),
+            keep_alive: None,
+        })
+    }
+
+    /// Queues up to `capacity` messages while offline. Without this, sends
+    /// while offline fail with `Error::Closed`.
+    pub fn with_queue(mut self, capacity: usize, overflow: Overflow) -> Self {
+        self.queue = OfflineQueue::new(capacity, overflow);
+        self
+    }
+
+    /// Turns on `WebSocket::keep_alive` for this connection and every one
+    /// that replaces it, so a silent link counts as a dropped one.
+    pub fn with_keep_alive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.keep_alive = Some((interval, timeout));
+        if let Some(socket) = &mut self.socket {
+            socket.keep_alive(interval, timeout);
+        }
+        self
+    }
+
+    /// Sends `message`, or queues it if the connection is down. A send that
+    /// fails takes the connection down, and the message is queued instead;
+    /// it may have got through before the failure, and so arrive twice.
+    pub fn send(&mut self, message: Message) -> Result<()> {
+        let Some(socket) = &mut self.socket else {
+            return self.queue.push(message);
+        };
+
+        match socket.send(message.clone()) {
+            Err(err) if is_dropped(&err) => {
+                self.socket = None;
+                self.queue.push(message)
+            }
+            sent => sent,
+        }
+    }
+
+    /// Blocks until the next message arrives, like `WebSocket::read`. If
+    /// the connection drops, the error is returned and it stays down until
+    /// `reconnect`. While it's down, this fails with `Error::Closed`.
+    pub fn read(&mut self) -> Result<Message> {
+        let socket = self.socket.as_mut().ok_or(Error::Closed)?;
+
+        let read = socket.read();
+        if read.as_ref().is_err_and(is_dropped) {
+            self.socket = None;
+        }
+        read
+    }
+
+    /// Opens a new connection, then sends everything queued on it in order.
+    /// If the connection fails part way through, the rest stay queued.
+    pub fn reconnect(&mut self) -> Result<()> {
+        let mut socket = sync::connect(&self.url)?;
+        if let Some((interval, timeout)) = self.keep_alive {
+            socket.keep_alive(interval, timeout);
+        }
+
+        while let Some(message) = self.queue.messages.front() {
+            socket.send(message.clone())?;
+            self.queue.messages.pop_front();
+        }
+
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    /// How many messages are waiting for `reconnect`.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+/// Whether `err` means the connection is gone, rather than the message being
+/// bad.
+fn is_dropped(err: &Error) -> bool {
+    matches!(err, Error::Io(_) | Error::Disconnected(_) | Error::Closed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn overflow_policies() {
+        let text = |text: &str| Message::Text(String::from(text));
+        let queued = |queue: &OfflineQueue| queue.messages.iter().cloned().collect::<Vec<_>>();
+
+        let mut oldest = OfflineQueue::new(2, Overflow::DropOldest);
+        let mut newest = OfflineQueue::new(2, Overflow::DropNewest);
+        let mut reject = OfflineQueue::new(2, Overflow::Reject);
+        for queue in [&mut oldest, &mut newest, &mut reject] {
+            queue.push(text("a")).unwrap();
+            queue.push(text("b")).unwrap();
+        }
+
+        oldest.push(text("c")).unwrap();
+        newest.push(text("c")).unwrap();
+        assert!(matches!(reject.push(text("c")), Err(Error::QueueFull)));
+
+        assert_eq!(queued(&oldest), [text("b"), text("c")]);
+        assert_eq!(queued(&newest), [text("a"), text("b")]);
+        assert_eq!(queued(&reject), [text("a"), text("b")]);
+        assert!(matches!(
+            OfflineQueue::default().push(text("a")),
+            Err(Error::Closed)
+        ));
+    }
+
+    #[test]
+    fn queued_messages_go_out_after_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            // The first connection drops straight away
+            let (stream, _) = listener.accept().unwrap();
+            drop(sync::accept(stream).unwrap());
+
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = sync::accept(stream).unwrap();
+            (0..3).map(|_| socket.read().unwrap()).collect::<Vec<_>>()
+        });
+
+        let mut socket = ReconnectingWebSocket::connect(&format!("ws://{addr}/"))
+            .unwrap()
+            .with_queue(2, Overflow::DropOldest);
+        assert!(socket.read().is_err());
+        assert!(!socket.is_connected());
+
+        for text in ["one", "two", "three"] {
+            socket.send(Message::Text(String::from(text))).unwrap();
+        }
+        assert_eq!(socket.queued(), 2);
+
+        socket.reconnect().unwrap();
+        assert_eq!(socket.queued(), 0);
+        socket.send(Message::Text(String::from("four"))).unwrap();
+
+        let received = server.join().unwrap();
+        let expected = ["two", "three", "four"].map(|text| Message::Text(String::from(text)));
+        assert_eq!(received, expected);
+    }
+}
@@ -0,0 +1,189 @@
+//! Catching panics in a connection's task, so a bug in an interceptor,
+//! inspector or hook closes the one connection it happened on with 1011
+//! and leaves a report, rather than the task vanishing silently.
+//!
+//! A backtrace can only be taken while the panic is happening, so the first
+//! use installs a panic hook that takes one for panics being caught here.
+//! Every other panic still goes to the hook that was there before.
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Once;
+use std::task::{Context, Poll};
+
+thread_local! {
+    /// Whether this thread is polling a future wrapped in `catch_unwind`.
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+
+    /// Where the last caught panic happened, and the backtrace to it.
+    static CAUGHT: RefCell<Option<(String, Backtrace)>> = const { RefCell::new(None) };
+}
+
+/// A caught panic.
+#[derive(Debug)]
+pub struct PanicReport {
+    pub message: String,
+
+    /// Where it happened, e.g. `src/intercept.rs:12:9`. Only known if the
+    /// hook installed here saw it.
+    pub location: Option<String>,
+    pub backtrace: Option<Backtrace>,
+}
+
+impl PanicReport {
+    fn new(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(message) => String::from(*message),
+                Err(_) => String::from("Box<dyn Any>"),
+            },
+        };
+
+        let (location, backtrace) = match CAUGHT.take() {
+            Some((location, backtrace)) => (Some(location), Some(backtrace)),
+            None => (None, None),
+        };
+
+        PanicReport {
+            message,
+            location,
+            backtrace,
+        }
+    }
+}
+
+impl fmt::Display for PanicReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "panicked at {location}: {}", self.message)?,
+            None => write!(f, "panicked: {}", self.message)?,
+        }
+        match &self.backtrace {
+            Some(backtrace) => write!(f, "\nstack backtrace:\n{backtrace}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What the client of a connection whose task panicked is sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorResponse {
+    /// The close code. The close goes out without interceptors, since they
+    /// may be what panicked.
+    pub code: u16,
+
+    /// The close reason, with any `{id}` replaced by the connection's ID.
+    /// It is cut to fit in a close frame.
+    pub reason: String,
+
+    /// A binary message sent just before the close, e.g. a JSON error for
+    /// the client to show, with `{id}` replaced the same way.
+    pub message: Option<String>,
+}
+
+impl Default for ErrorResponse {
+    fn default() -> Self {
+        ErrorResponse {
+            code: 1011,
+            reason: String::from("internal error"),
+            message: None,
+        }
+    }
+}
+
+impl ErrorResponse {
+    pub fn reason(&self, id: u64) -> String {
+        self.reason.replace("{id}", &id.to_string())
+    }
+
+    pub fn message(&self, id: u64) -> Option<String> {
+        let message = self.message.as_ref()?;
+        Some(message.replace("{id}", &id.to_string()))
+    }
+}
+
+/// Resolves with the output of `future`, or what it panicked with.
+pub fn catch_unwind<F: Future>(future: F) -> CatchUnwind<F> {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !CATCHING.get() {
+                return previous(info);
+            }
+            let location = info
+                .location()
+                .map_or_else(String::new, ToString::to_string);
+            CAUGHT.set(Some((location, Backtrace::force_capture())));
+        }));
+    });
+
+    CatchUnwind {
+        future: Box::pin(future),
+    }
+}
+
+/// The future returned by `catch_unwind`.
+pub struct CatchUnwind<F> {
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, PanicReport>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let future = self.future.as_mut();
+
+        let outer = CATCHING.replace(true);
+        let polled = panic::catch_unwind(AssertUnwindSafe(|| future.poll(cx)));
+        CATCHING.set(outer);
+
+        match polled {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(PanicReport::new(payload))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn panics_come_back_with_where_they_happened() {
+        assert_eq!(catch_unwind(async { 7 }).await.unwrap(), 7);
+
+        let report = catch_unwind(async {
+            tokio::task::yield_now().await;
+            panic!("bad input {}", 7);
+        })
+        .await
+        .unwrap_err();
+
+        assert_eq!(report.message, "bad input 7");
+        assert!(report.location.unwrap().starts_with("src/recover.rs:"));
+        assert!(report.backtrace.is_some());
+    }
+
+    #[test]
+    fn responses_name_the_connection() {
+        let response = ErrorResponse {
+            code: 4500,
+            reason: String::from("sorry, see incident {id}"),
+            message: Some(String::from(r#"{"error":"internal","connection":{id}}"#)),
+        };
+        assert_eq!(response.reason(12), "sorry, see incident 12");
+        assert_eq!(
+            response.message(12).unwrap(),
+            r#"{"error":"internal","connection":12}"#
+        );
+        assert_eq!(ErrorResponse::default().message(12), None);
+    }
+}
@@ -0,0 +1,179 @@
+//! Request/response calls over a connection's messages, matched up by
+//! correlation ID, so RPC-style code can await a reply without keeping its
+//! own map of outstanding requests.
+//!
+//! Every call message starts with an 8 byte big-endian call ID, and the
+//! reply starts with the same ID; an echo server replies correctly as it
+//! is. `Rpc` doesn't own the connection: calls queue their messages on a
+//! channel for whatever writes to it, and whatever reads from it hands each
+//! message to `resolve`.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
+
+use crate::{Error, Result};
+
+/// Length of the call ID at the start of every call and reply.
+pub const ID_LEN: usize = 8;
+
+/// Splits a call or reply into its ID and payload. `None` if it is too
+/// short to have an ID.
+pub fn split(message: &[u8]) -> Option<(u64, &[u8])> {
+    let (id, payload) = message.split_first_chunk::<ID_LEN>()?;
+    Some((u64::from_be_bytes(*id), payload))
+}
+
+/// The reply to `call` carrying `payload`, for the answering end. `None`
+/// if `call` has no ID.
+pub fn reply(call: &[u8], payload: &[u8]) -> Option<Vec<u8>> {
+    let (id, _) = split(call)?;
+    Some([&id.to_be_bytes()[..], payload].concat())
+}
+
+/// The calls made on one connection and waiting for replies.
+#[derive(Debug)]
+pub struct Rpc {
+    outgoing: mpsc::Sender<Vec<u8>>,
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Vec<u8>>>>,
+}
+
+impl Rpc {
+    /// Calls are sent by queueing them on `outgoing`.
+    pub fn new(outgoing: mpsc::Sender<Vec<u8>>) -> Self {
+        Rpc {
+            outgoing,
+            next_id: AtomicU64::new(0),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends `payload` and waits up to `timeout` for the reply's payload.
+    /// Fails with `Error::Closed` if the connection's writer has gone away,
+    /// and with a `TimedOut` IO error if there was no reply in time.
+    pub async fn call(&self, payload: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (replied, reply) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, replied);
+
+        // Forget the call however this ends, including being cancelled
+        let _pending = Pending { rpc: self, id };
+
+        let message = [&id.to_be_bytes()[..], payload].concat();
+        self.outgoing
+            .send(message)
+            .await
+            .map_err(|_| Error::Closed)?;
+
+        match time::timeout(timeout, reply).await {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(Error::Closed),
+            Err(_) => Err(Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no reply to call {id} within {timeout:?}"),
+            ))),
+        }
+    }
+
+    /// Hands a received message to the call it replies to. Messages that
+    /// aren't replies to an outstanding call, including late replies to
+    /// calls that timed out, are given back.
+    pub fn resolve(&self, message: Vec<u8>) -> Option<Vec<u8>> {
+        let Some((id, payload)) = split(&message) else {
+            return Some(message);
+        };
+
+        match self.pending.lock().unwrap().remove(&id) {
+            Some(replied) => {
+                let _ = replied.send(payload.to_vec());
+                None
+            }
+            None => Some(message),
+        }
+    }
+
+    /// Calls waiting for their replies.
+    pub fn outstanding(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+}
+
+/// Removes a call from `pending` when it is dropped.
+struct Pending<'a> {
+    rpc: &'a Rpc,
+    id: u64,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.rpc.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::config::Config;
+    use crate::server::Server;
+    use crate::testing;
+    use crate::Message;
+
+    #[tokio::test]
+    async fn calls_get_their_own_replies() {
+        let server = Arc::new(Server::from_config(Config::default()).unwrap());
+        let mut client = testing::connect_pair(server, "/").await.unwrap();
+
+        let (outgoing, mut to_send) = mpsc::channel(8);
+        let rpc = Rpc::new(outgoing);
+
+        // The echo server replies to both in order, but they are matched up
+        // by ID either way
+        let calls = async {
+            let timeout = Duration::from_secs(5);
+            tokio::join!(rpc.call(b"first", timeout), rpc.call(b"second", timeout))
+        };
+        let connection = async {
+            for _ in 0..2 {
+                let message = to_send.recv().await.unwrap();
+                client.send(Message::Binary(message)).await.unwrap();
+            }
+            for _ in 0..2 {
+                let Message::Binary(reply) = client.read().await.unwrap() else {
+                    panic!("expected a binary reply");
+                };
+                assert_eq!(rpc.resolve(reply), None);
+            }
+        };
+
+        let ((first, second), ()) = tokio::join!(calls, connection);
+        assert_eq!(first.unwrap(), b"first");
+        assert_eq!(second.unwrap(), b"second");
+        assert_eq!(
+            rpc.resolve(b"unsolicited".to_vec()),
+            Some(b"unsolicited".to_vec())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_calls_time_out() {
+        let (outgoing, mut to_send) = mpsc::channel(8);
+        let rpc = Rpc::new(outgoing);
+
+        let result = rpc.call(b"anyone?", Duration::from_secs(1)).await;
+        assert!(matches!(result, Err(Error::Io(err)) if err.kind() == io::ErrorKind::TimedOut));
+        assert_eq!(rpc.outstanding(), 0);
+
+        // A reply that turns up too late isn't for anyone
+        let late = reply(&to_send.recv().await.unwrap(), b"me").unwrap();
+        assert_eq!(rpc.resolve(late.clone()), Some(late));
+    }
+}
@@ -0,0 +1,82 @@
+//! Round-trip times measured by pinging the peer and timing its pong.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Correlates pings with their pongs, and keeps a smoothed round-trip time
+/// the way TCP does (RFC 6298), so one slow pong doesn't swing it much.
+#[derive(Debug, Default)]
+pub struct RttEstimator {
+    next_nonce: u64,
+
+    /// The ping waiting for its pong, and when it was sent.
+    outstanding: Option<(u64, Instant)>,
+
+    smoothed: Option<Duration>,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        RttEstimator::default()
+    }
+
+    /// Starts a measurement, and returns the payload to send in the ping.
+    /// A ping still waiting for its pong is given up on.
+    pub fn ping(&mut self, now: Instant) -> [u8; 8] {
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+
+        self.outstanding = Some((nonce, now));
+        nonce.to_be_bytes()
+    }
+
+    /// Handles a pong's payload. Returns the round trip if it answers the
+    /// outstanding ping; other pongs, like unsolicited heartbeats, are
+    /// ignored.
+    pub fn pong(&mut self, payload: &[u8], now: Instant) -> Option<Duration> {
+        let (nonce, sent) = self.outstanding?;
+        if payload != nonce.to_be_bytes() {
+            return None;
+        }
+        self.outstanding = None;
+
+        let sample = now.saturating_duration_since(sent);
+        self.smoothed = Some(match self.smoothed {
+            None => sample,
+            Some(smoothed) => (smoothed * 7 + sample) / 8,
+        });
+
+        Some(sample)
+    }
+
+    /// The smoothed round-trip time, once there has been a measurement.
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.smoothed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_outstanding_ping_counts() {
+        let start = Instant::now();
+        let mut rtt = RttEstimator::new();
+
+        let stale = rtt.ping(start);
+        let payload = rtt.ping(start);
+        let later = start + Duration::from_millis(80);
+
+        assert_eq!(rtt.pong(&stale, later), None);
+        assert_eq!(rtt.pong(b"heartbeat", later), None);
+        assert_eq!(rtt.pong(&payload, later), Some(Duration::from_millis(80)));
+        assert_eq!(rtt.pong(&payload, later), None);
+        assert_eq!(rtt.smoothed(), Some(Duration::from_millis(80)));
+
+        let payload = rtt.ping(later);
+        rtt.pong(&payload, later + Duration::from_millis(160));
+        assert_eq!(rtt.smoothed(), Some(Duration::from_millis(90)));
+    }
+}
@@ -0,0 +1,132 @@
+//! The tokio runtime the binary runs on, built from the config so it can be
+//! tuned for a dedicated host without recompiling.
+
+use std::io;
+use std::num::NonZeroUsize;
+use std::thread;
+
+use tokio::runtime::{Builder, Runtime};
+
+use crate::config::Config;
+
+/// A multi-threaded runtime with the config's worker and blocking thread
+/// counts and thread name. With `pin_workers`, each worker is pinned to
+/// its own core, out of the ones the process is allowed to run on. Fails
+/// on platforms without core pinning if it is asked for.
+pub fn build(config: &Config) -> io::Result<Runtime> {
+    let workers = match config.worker_threads {
+        0 => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        workers => workers,
+    };
+
+    let mut builder = Builder::new_multi_thread();
+    builder
+        .enable_all()
+        .worker_threads(workers)
+        .max_blocking_threads(config.max_blocking_threads)
+        .thread_name(config.thread_name.clone());
+
+    if config.pin_workers {
+        pin_workers(&mut builder, workers)?;
+    }
+
+    builder.build()
+}
+
+#[cfg(target_os = "linux")]
+fn pin_workers(builder: &mut Builder, workers: usize) -> io::Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let cores = affinity::allowed()?;
+    let started = AtomicUsize::new(0);
+
+    // The workers are the threads started while the runtime is built;
+    // blocking threads, started later as they are needed, can run anywhere
+    builder.on_thread_start(move || {
+        let n = started.fetch_add(1, Ordering::Relaxed);
+        if n < workers {
+            let core = cores[n % cores.len()];
+            if let Err(err) = affinity::pin(core) {
+                println!("Couldn't pin worker {n} to core {core}: {err}");
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_workers(_: &mut Builder, _: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pinning workers to cores is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+mod affinity {
+    use std::io;
+    use std::mem;
+
+    /// The cores this process may run on, in order.
+    pub fn allowed() -> io::Result<Vec<usize>> {
+        // SAFETY: cpu_set_t is plain data, and the kernel writes at most
+        // its size
+        let set = unsafe {
+            let mut set: libc::cpu_set_t = mem::zeroed();
+            if libc::sched_getaffinity(0, mem::size_of_val(&set), &mut set) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            set
+        };
+
+        let cores = 0..libc::CPU_SETSIZE as usize;
+        // SAFETY: every core checked is inside the set
+        let cores: Vec<_> = cores
+            .filter(|&core| unsafe { libc::CPU_ISSET(core, &set) })
+            .collect();
+
+        if cores.is_empty() {
+            return Err(io::Error::other("no cores to pin workers to"));
+        }
+        Ok(cores)
+    }
+
+    /// Keeps the calling thread on `core`.
+    pub fn pin(core: usize) -> io::Result<()> {
+        // SAFETY: as in allowed, and the kernel only reads the set
+        unsafe {
+            let mut set: libc::cpu_set_t = mem::zeroed();
+            libc::CPU_SET(core, &mut set);
+            if libc::sched_setaffinity(0, mem::size_of_val(&set), &set) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workers_are_named_and_pinned() {
+        let config = Config {
+            worker_threads: 2,
+            thread_name: String::from("wocket-test"),
+            pin_workers: cfg!(target_os = "linux"),
+            ..Config::default()
+        };
+        let runtime = build(&config).unwrap();
+
+        let name = runtime.block_on(async {
+            let worker = tokio::spawn(async {
+                #[cfg(target_os = "linux")]
+                assert_eq!(affinity::allowed().unwrap().len(), 1);
+                thread::current().name().map(String::from)
+            });
+            worker.await.unwrap()
+        });
+        assert_eq!(name.as_deref(), Some("wocket-test"));
+    }
+}
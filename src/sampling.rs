@@ -0,0 +1,115 @@
+//! Sampling for the per-message log lines from `log_messages`, so a busy
+//! connection can't flood the log.
+//!
+//! Each connection logs one in every `every` of its messages, and at most
+//! `max_per_sec` a second. Both can be changed while the server runs, and
+//! connections pick the new values up with their next message.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// The sampling rates, shared by every connection.
+#[derive(Debug)]
+pub struct LogSampling {
+    every: AtomicU64,
+    max_per_sec: AtomicU64,
+}
+
+impl LogSampling {
+    /// Logs one in `every` messages, and at most `max_per_sec` a second per
+    /// connection. `every` of 0 or 1 logs them all, and `max_per_sec` of 0
+    /// doesn't cap them.
+    pub fn new(every: u64, max_per_sec: u64) -> Self {
+        LogSampling {
+            every: AtomicU64::new(every),
+            max_per_sec: AtomicU64::new(max_per_sec),
+        }
+    }
+
+    pub fn set(&self, every: u64, max_per_sec: u64) {
+        self.every.store(every, Ordering::Relaxed);
+        self.max_per_sec.store(max_per_sec, Ordering::Relaxed);
+    }
+
+    /// The current `every` and `max_per_sec`.
+    pub fn get(&self) -> (u64, u64) {
+        let every = self.every.load(Ordering::Relaxed);
+        (every, self.max_per_sec.load(Ordering::Relaxed))
+    }
+}
+
+/// Where one connection is in the sampling.
+#[derive(Debug)]
+pub struct Sampler {
+    seen: u64,
+
+    /// When the current second started, and how many were logged in it.
+    window: Instant,
+    logged: u64,
+
+    /// Messages not logged since the last one that was.
+    skipped: u64,
+}
+
+impl Sampler {
+    pub fn new(now: Instant) -> Self {
+        Sampler {
+            seen: 0,
+            window: now,
+            logged: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Whether to log the message seen at `now`. If so, returns how many
+    /// were skipped since the last one logged.
+    pub fn sample(&mut self, sampling: &LogSampling, now: Instant) -> Option<u64> {
+        let (every, max_per_sec) = sampling.get();
+        self.seen += 1;
+
+        if every > 1 && !(self.seen - 1).is_multiple_of(every) {
+            self.skipped += 1;
+            return None;
+        }
+
+        if max_per_sec > 0 {
+            if now - self.window >= Duration::from_secs(1) {
+                self.window = now;
+                self.logged = 0;
+            }
+            if self.logged >= max_per_sec {
+                self.skipped += 1;
+                return None;
+            }
+        }
+
+        self.logged += 1;
+        Some(std::mem::take(&mut self.skipped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_in_n_under_a_cap() {
+        let start = Instant::now();
+        let sampling = LogSampling::new(3, 0);
+        let mut sampler = Sampler::new(start);
+
+        let logged: Vec<_> = (0..7).map(|_| sampler.sample(&sampling, start)).collect();
+        assert_eq!(logged, [Some(0), None, None, Some(2), None, None, Some(2)]);
+
+        // Two a second, then the rest of that second are skipped
+        sampling.set(1, 2);
+        let later = start + Duration::from_secs(5);
+        assert_eq!(sampler.sample(&sampling, later), Some(0));
+        assert_eq!(sampler.sample(&sampling, later), Some(0));
+        assert_eq!(sampler.sample(&sampling, later), None);
+        let next_second = later + Duration::from_secs(1);
+        assert_eq!(sampler.sample(&sampling, next_second), Some(1));
+    }
+}
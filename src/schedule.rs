@@ -0,0 +1,176 @@
+//! Messages sent to a connection after a delay, for reminders and
+//! timeouts.
+//!
+//! Timers are kept in a hashed timer wheel: a ring of slots the cursor
+//! moves through one tick at a time, where each timer sits in the slot it
+//! is due in, along with how many more turns of the ring it has to wait.
+//! Adding a timer and firing one are both constant time, and one task runs
+//! the wheel for every connection rather than a task per timer.
+
+use std::mem;
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use crate::connections::{ConnectionId, Connections};
+
+/// How often the wheel turns. Timers fire on a tick, so this is how
+/// precise they are.
+pub const TICK: Duration = Duration::from_millis(10);
+
+/// Slots in the wheel. Timers further out than one turn wait for more
+/// turns.
+const SLOTS: usize = 1024;
+
+#[derive(Debug)]
+struct Timer {
+    /// Turns of the wheel left before it is due.
+    rounds: u64,
+    id: ConnectionId,
+    message: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Wheel {
+    slots: Vec<Vec<Timer>>,
+    cursor: usize,
+    len: usize,
+}
+
+impl Wheel {
+    fn new() -> Self {
+        Wheel {
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            cursor: 0,
+            len: 0,
+        }
+    }
+
+    /// Adds a timer due `ticks` ticks from now, at least one.
+    fn insert(&mut self, ticks: u64, id: ConnectionId, message: Vec<u8>) {
+        let ticks = ticks.max(1);
+        let slot = (self.cursor as u64 + ticks) % SLOTS as u64;
+        let rounds = (ticks - 1) / SLOTS as u64;
+
+        self.slots[slot as usize].push(Timer {
+            rounds,
+            id,
+            message,
+        });
+        self.len += 1;
+    }
+
+    /// Moves on a tick, and returns the timers that are now due.
+    fn advance(&mut self) -> Vec<Timer> {
+        self.cursor = (self.cursor + 1) % SLOTS;
+
+        let slot = mem::take(&mut self.slots[self.cursor]);
+        let (due, mut waiting): (Vec<Timer>, Vec<Timer>) =
+            slot.into_iter().partition(|timer| timer.rounds == 0);
+        waiting.iter_mut().for_each(|timer| timer.rounds -= 1);
+
+        self.slots[self.cursor] = waiting;
+        self.len -= due.len();
+        due
+    }
+}
+
+/// Sends messages to connections once their delay has passed.
+#[derive(Debug)]
+pub struct Scheduler {
+    connections: Arc<Connections>,
+    wheel: Mutex<Wheel>,
+
+    /// Wakes the task running the wheel once there are timers again.
+    added: Notify,
+    started: Once,
+}
+
+impl Scheduler {
+    pub fn new(connections: Arc<Connections>) -> Self {
+        Scheduler {
+            connections,
+            wheel: Mutex::new(Wheel::new()),
+            added: Notify::new(),
+            started: Once::new(),
+        }
+    }
+
+    /// Sends `message` to connection `id` once `delay` has passed, at most
+    /// two ticks late. It is dropped if the connection has closed by then.
+    ///
+    /// The task running the wheel is started by the first call, so this
+    /// has to be called from within a tokio runtime.
+    pub fn send_after(self: &Arc<Self>, delay: Duration, id: ConnectionId, message: &[u8]) {
+        // The current tick is partly over, so it doesn't count
+        let ticks = delay.as_nanos().div_ceil(TICK.as_nanos()) + 1;
+        let ticks = u64::try_from(ticks).unwrap_or(u64::MAX);
+        self.wheel
+            .lock()
+            .unwrap()
+            .insert(ticks, id, message.to_vec());
+
+        self.started.call_once(|| {
+            tokio::spawn(Arc::clone(self).run());
+        });
+        self.added.notify_one();
+    }
+
+    /// Timers that haven't fired yet.
+    pub fn len(&self) -> usize {
+        self.wheel.lock().unwrap().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    async fn run(self: Arc<Self>) {
+        let mut ticks = time::interval_at(Instant::now() + TICK, TICK);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Burst);
+
+        loop {
+            // Don't tick while there is nothing to fire
+            if self.is_empty() {
+                self.added.notified().await;
+                ticks.reset();
+            }
+
+            ticks.tick().await;
+            let due = self.wheel.lock().unwrap().advance();
+            for timer in due {
+                self.connections.send(timer.id, &timer.message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timers_fire_on_their_tick() {
+        let mut wheel = Wheel::new();
+        wheel.insert(0, 1, b"soon".to_vec());
+        wheel.insert(3, 2, b"later".to_vec());
+        wheel.insert(SLOTS as u64 + 3, 3, b"next turn".to_vec());
+        assert_eq!(wheel.len, 3);
+
+        assert_eq!(wheel.advance()[0].id, 1);
+        assert!(wheel.advance().is_empty());
+
+        let due = wheel.advance();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, 2);
+
+        let mut fired = Vec::new();
+        for _ in 0..SLOTS {
+            fired.extend(wheel.advance().into_iter().map(|timer| timer.id));
+        }
+        assert_eq!(fired, [3]);
+        assert_eq!(wheel.len, 0);
+    }
+}
@@ -0,0 +1,1966 @@
+use std::error::Error;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{broadcast, watch};
+use tokio::time::{self, Instant};
+
+use crate::ban::{BanFile, BanStore, Bans};
+use crate::cancel::CancellationToken;
+use crate::chaos::{Chaos, Fault};
+use crate::close::CloseCodes;
+use crate::codec::{self, batch, Event, WsConnection};
+use crate::config::{Config, FailoverRole, Growth};
+use crate::connections::{ConnectionId, Connections, Instruction, Registration, Selector};
+use crate::envelope::SignedEnvelopes;
+use crate::events::{self, ServerEvent};
+use crate::handshake::{self, Handshake};
+use crate::inspect::{self, BlockedPatterns, Inspection, Inspector, OnDeadline};
+use crate::intercept::{Action, Chain};
+use crate::ipfilter::IpFilter;
+use crate::memory::MemoryUsage;
+use crate::metrics::{Closed, Metrics};
+use crate::policy::{AcceptPolicy, GeoTable};
+use crate::pool::{BufferPool, PooledBuf};
+use crate::pressure::{self, Degradation, MemoryPressure};
+use crate::recover;
+use crate::rtt::RttEstimator;
+use crate::sampling::{LogSampling, Sampler};
+use crate::schedule::Scheduler;
+use crate::slow::{self, SlowConsumers};
+use crate::static_files;
+use crate::throttle::Throttle;
+use crate::trace::{EchoTraceContext, TraceContext};
+use crate::transport::{Counted, Transport};
+use crate::ulid::Ulid;
+use crate::upgrade::{
+    self, AllowedOrigins, AllowedPaths, ConnectionLimit, Draining, MemoryLimit,
+    MemoryPressureLimit, Reservations, RouteCounts, RouteLimits, Standby, Subprotocols,
+    UpgradeHook,
+};
+
+/// State shared by every connection.
+pub struct Server {
+    pub config: Config,
+    pub pool: Arc<BufferPool>,
+    pub interceptors: Chain,
+    pub inspectors: Vec<Box<dyn Inspector>>,
+    pub ip_filter: Arc<RwLock<IpFilter>>,
+    pub accept_policies: Vec<Box<dyn AcceptPolicy>>,
+    pub upgrade_hooks: Vec<Box<dyn UpgradeHook>>,
+
+    /// Number of open WebSocket connections.
+    pub active_connections: Arc<AtomicUsize>,
+
+    /// Number of open WebSocket connections on each path.
+    pub route_connections: Arc<RouteCounts>,
+
+    /// Bytes buffered by all connections.
+    pub memory: Arc<MemoryUsage>,
+
+    /// How much the server has given up to stay under `soft_memory_limit`.
+    pub pressure: Arc<MemoryPressure>,
+
+    pub metrics: Metrics,
+
+    /// How many of each connection's messages `log_messages` prints.
+    pub log_sampling: LogSampling,
+
+    /// Names for application close codes, used in logs and metrics.
+    pub close_codes: CloseCodes,
+
+    /// Open WebSocket connections by ID.
+    pub connections: Arc<Connections>,
+
+    /// Messages waiting to be sent to connections later.
+    pub scheduler: Arc<Scheduler>,
+
+    pub bans: Arc<Bans>,
+
+    /// Lifecycle events, for `subscribe`.
+    pub events: broadcast::Sender<ServerEvent>,
+
+    /// Set to `true` to turn away new upgrades and send open connections
+    /// the drain message.
+    pub draining: watch::Sender<bool>,
+
+    /// Set to `true` to close every connection with 1001 (Going Away).
+    pub shutdown: watch::Sender<bool>,
+
+    /// `true` while this is the standby of a failover pair, turning away
+    /// upgrades.
+    pub standby: watch::Sender<bool>,
+}
+
+impl Server {
+    /// Sets up the policies, hooks and interceptors that `config` asks for.
+    /// Fails if a file it names can't be loaded.
+    pub fn from_config(config: Config) -> Result<Self, Box<dyn Error>> {
+        let pool = BufferPool::new(config.read_buffer_size, config.pool_capacity);
+
+        // Envelopes are the outermost layer, so the others see messages
+        // without their signatures
+        let mut interceptors = Chain::new();
+        if let Some(key) = &config.envelope_key {
+            interceptors = interceptors.with(SignedEnvelopes::new(key));
+        }
+
+        let mut inspectors: Vec<Box<dyn Inspector>> = vec![];
+        if let Some(path) = &config.blocked_patterns {
+            inspectors.push(Box::new(BlockedPatterns::load(
+                path,
+                config.on_blocked_pattern,
+            )?));
+        }
+
+        let ip_filter = Arc::new(RwLock::new(config.ip_filter()?));
+
+        let ban_store = config
+            .ban_file
+            .clone()
+            .map(|path| Box::new(BanFile(path)) as Box<dyn BanStore>);
+        let bans = Arc::new(Bans::new(ban_store)?);
+
+        let mut accept_policies: Vec<Box<dyn AcceptPolicy>> = vec![
+            Box::new(Arc::clone(&ip_filter)),
+            Box::new(Arc::clone(&bans)),
+        ];
+        if let Some(path) = &config.geoip_table {
+            let table = GeoTable::load(path, config.blocked_countries.clone())?;
+            accept_policies.push(Box::new(table));
+        }
+
+        let active_connections = Arc::new(AtomicUsize::new(0));
+
+        let draining = watch::Sender::new(false);
+        let standby = watch::Sender::new(config.failover_role == Some(FailoverRole::Standby));
+
+        let mut upgrade_hooks: Vec<Box<dyn UpgradeHook>> = vec![
+            Box::new(Draining {
+                draining: draining.subscribe(),
+                retry_after: config.retry_after,
+            }),
+            Box::new(Standby {
+                standby: standby.subscribe(),
+                retry_after: config.retry_after,
+            }),
+        ];
+        if !config.paths.is_empty() {
+            upgrade_hooks.push(Box::new(AllowedPaths(config.paths.clone())));
+        }
+        if !config.allowed_origins.is_empty() {
+            upgrade_hooks.push(Box::new(AllowedOrigins(config.allowed_origins.clone())));
+        }
+        if config.echo_trace_context {
+            upgrade_hooks.push(Box::new(EchoTraceContext));
+        }
+        if !config.subprotocols.is_empty() {
+            upgrade_hooks.push(Box::new(Subprotocols(config.subprotocols.clone())));
+        }
+        if config.message_batching {
+            let protocol = String::from(batch::PROTOCOL);
+            upgrade_hooks.push(Box::new(Subprotocols(vec![protocol])));
+        }
+
+        // These count every connection, limited or not, so always run
+        let max = match config.max_connections {
+            0 => usize::MAX,
+            max => max,
+        };
+        upgrade_hooks.push(Box::new(ConnectionLimit {
+            max,
+            active: Arc::clone(&active_connections),
+            retry_after: config.retry_after,
+        }));
+
+        let route_connections = Arc::new(RouteCounts::default());
+        upgrade_hooks.push(Box::new(RouteLimits {
+            limits: config.route_limits.clone(),
+            counts: Arc::clone(&route_connections),
+            retry_after: config.retry_after,
+        }));
+
+        let memory = MemoryUsage::new(config.max_memory);
+        if config.max_memory > 0 {
+            upgrade_hooks.push(Box::new(MemoryLimit {
+                usage: Arc::clone(&memory),
+                retry_after: config.retry_after,
+            }));
+        }
+
+        let pressure = Arc::new(MemoryPressure::new(config.soft_memory_limit));
+        if config.soft_memory_limit > 0 {
+            upgrade_hooks.push(Box::new(MemoryPressureLimit {
+                pressure: Arc::clone(&pressure),
+                retry_after: config.retry_after,
+            }));
+        }
+
+        let metrics = Metrics::new(config.metric_labels.clone(), config.metric_max_series);
+        let log_sampling = LogSampling::new(config.log_sample_every, config.log_max_per_sec);
+        let connections = Arc::new(Connections::default());
+        let scheduler = Arc::new(Scheduler::new(Arc::clone(&connections)));
+
+        Ok(Server {
+            config,
+            pool,
+            interceptors,
+            inspectors,
+            ip_filter,
+            accept_policies,
+            upgrade_hooks,
+            active_connections,
+            route_connections,
+            memory,
+            pressure,
+            metrics,
+            log_sampling,
+            close_codes: CloseCodes::default(),
+            connections,
+            scheduler,
+            bans,
+            events: broadcast::Sender::new(events::CAPACITY),
+            draining,
+            shutdown: watch::Sender::new(false),
+            standby,
+        })
+    }
+
+    /// Changes how many messages `log_messages` prints per connection: one
+    /// in `every`, and at most `max_per_sec` a second. Open connections
+    /// follow it from their next message.
+    pub fn set_log_sampling(&self, every: u64, max_per_sec: u64) {
+        self.log_sampling.set(every, max_per_sec);
+    }
+
+    /// Receives every lifecycle event from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sends an event to subscribers. It is only built if there are any.
+    fn emit(&self, event: impl FnOnce() -> ServerEvent) {
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(event());
+        }
+    }
+
+    /// Closes connection `id` with `code` and `reason`. Returns `false` if
+    /// it isn't open.
+    pub fn kick(&self, id: ConnectionId, code: u16, reason: &str) -> bool {
+        self.connections.kick(id, code, reason)
+    }
+
+    /// The token cancelled once connection `id` has closed, e.g. to spawn
+    /// a watcher for it with `CancellationToken::spawn`. `None` if it isn't
+    /// open.
+    pub fn cancellation_token(&self, id: ConnectionId) -> Option<CancellationToken> {
+        self.connections.cancellation_token(id)
+    }
+
+    /// Labels connection `id`, e.g. with `region=eu`, for broadcasts to
+    /// select it by.
+    pub fn label(&self, id: ConnectionId, label: &str) -> Result<(), String> {
+        self.connections.label(id, label)
+    }
+
+    /// Sends `message` to every connection with all the labels in
+    /// `selector`, e.g. `region=eu AND tier=pro`. Returns how many it was
+    /// sent to; connections too far behind miss it.
+    pub fn broadcast(&self, selector: &str, message: &[u8]) -> Result<usize, String> {
+        let selector = Selector::parse(selector)?;
+        Ok(self.connections.broadcast(&selector, message))
+    }
+
+    /// Sends `message` to connection `id` once `delay` has passed, unless it
+    /// has closed by then. Has to be called from within the runtime.
+    pub fn send_after(&self, delay: Duration, id: ConnectionId, message: &[u8]) {
+        self.scheduler.send_after(delay, id, message);
+    }
+
+    /// Refuses connections from `ip` from now on, including after a restart
+    /// if there is a ban file, and closes its open ones with 1008 (Policy
+    /// Violation). Fails if the ban couldn't be saved, but it still applies
+    /// until the server stops.
+    pub fn ban(&self, ip: IpAddr) -> io::Result<()> {
+        let saved = self.bans.ban(ip);
+
+        for (id, info) in self.connections.list() {
+            if info.peer.ip().to_canonical() == ip.to_canonical() {
+                self.kick(id, 1008, "banned");
+            }
+        }
+
+        saved
+    }
+
+    /// Starts taking upgrades, as the standby of a failover pair does when
+    /// the primary has gone.
+    pub fn promote(&self) {
+        self.standby.send_replace(false);
+    }
+
+    /// Stops taking new connections, sends open ones the drain message,
+    /// and closes them once `drain_timeout` has passed.
+    pub async fn drain(&self) {
+        self.draining.send_replace(true);
+        time::sleep(self.config.drain_timeout).await;
+        self.shutdown.send_replace(true);
+    }
+}
+
+/// Runs one connection, from the opening handshake until it closes. `peer`
+/// is only used in log messages and events; accept policies should already
+/// have been checked, and `tags` are the ones they attached. Tags that are
+/// labels, like `country=DE`, label the connection once it is upgraded.
+pub async fn handle_client<S: Transport>(
+    socket: S,
+    peer: SocketAddr,
+    tags: Vec<String>,
+    server: Arc<Server>,
+) {
+    let mut socket = Counted::new(socket);
+    let mut summary = Summary::new(Ulid::new());
+    let request_id = summary.request_id;
+
+    match recover::catch_unwind(run(&mut socket, peer, &tags, &server, &mut summary)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => server.emit(|| ServerEvent::Error {
+            peer,
+            request_id,
+            message: err.to_string(),
+        }),
+        Err(report) => {
+            server.metrics.panicked();
+            match summary.opened {
+                Some((id, _)) => println!("Connection {id} from {peer} {request_id} {report}"),
+                None => println!("Connection from {peer} {request_id} {report}"),
+            }
+
+            // The connection's state went with the task, so the close is
+            // written straight to the socket, and whatever comes back in
+            // the next second is taken as the reply
+            if let (Some((id, _)), None) = (summary.opened, summary.code) {
+                let response = &server.config.internal_error;
+                summary.closed_with(response.code);
+
+                let mut frames = Vec::new();
+                if let Some(message) = response.message(id) {
+                    codec::write_frame(
+                        true,
+                        codec::opcode::BINARY,
+                        message.as_bytes(),
+                        None,
+                        &mut frames,
+                    );
+                }
+                let reason = response.reason(id);
+                codec::write_close_frame(response.code, reason.as_bytes(), &mut frames);
+
+                if socket.write_all(&frames).await.is_ok() {
+                    let mut reply = [0; 128];
+                    let read = socket.read(&mut reply);
+                    let _ = time::timeout(Duration::from_secs(1), read).await;
+                }
+            }
+
+            server.emit(|| ServerEvent::Error {
+                peer,
+                request_id,
+                message: report.message,
+            });
+        }
+    }
+
+    if let Some((id, opened)) = summary.opened {
+        // A connection that ended without a close frame closed abnormally
+        let code = summary.code.unwrap_or(1006);
+        server.metrics.closed(Closed {
+            code: &server.close_codes.name(code),
+            route: &summary.route,
+            subprotocol: summary.subprotocol.as_deref(),
+        });
+
+        server.emit(|| ServerEvent::ConnectionClosed {
+            id,
+            peer,
+            request_id,
+            code: summary.code,
+            duration: opened.elapsed(),
+            bytes_received: socket.bytes_read,
+            bytes_sent: socket.bytes_written,
+        });
+    }
+}
+
+/// Checks every connection's outbox each `slow::CHECK_INTERVAL`, forever,
+/// and reports the ones that stay above `slow_consumer_depth`.
+pub async fn watch_slow_consumers(server: Arc<Server>) {
+    let config = &server.config;
+    let mut consumers = SlowConsumers::new(config.slow_consumer_depth, config.slow_consumer_after);
+    let mut interval = time::interval(slow::CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let backlogs = server.connections.backlogs();
+        for slow in consumers.check(backlogs, Instant::now()) {
+            server.metrics.slow_consumer();
+            server.emit(|| ServerEvent::SlowConsumer {
+                id: slow.id,
+                peer: slow.peer,
+                request_id: slow.request_id,
+                depth: slow.depth,
+                oldest: slow.oldest,
+            });
+        }
+        server.metrics.set_slow_consumers(consumers.slow());
+    }
+}
+
+/// Reads the process's resident memory each `pressure::CHECK_INTERVAL`,
+/// forever, and moves between degradation levels as it nears
+/// `soft_memory_limit`.
+pub async fn watch_memory_pressure(server: Arc<Server>) {
+    let mut interval = time::interval(pressure::CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let resident = match pressure::resident() {
+            Ok(resident) => resident,
+            Err(err) => {
+                println!("Can't enforce the soft memory limit: {err}");
+                return;
+            }
+        };
+
+        if let Some(level) = server.pressure.update(resident) {
+            println!(
+                "Using {resident} of {} bytes, now {level}",
+                server.pressure.limit()
+            );
+        }
+        if server.pressure.level() >= Degradation::ShrinkBuffers {
+            server.pool.clear();
+        }
+    }
+}
+
+/// What happens after answering a request, before the connection has been
+/// upgraded.
+enum Next {
+    /// Switch to WebSocket frames on `path`, after the request's `len`
+    /// bytes.
+    Upgrade {
+        path: String,
+        len: usize,
+        subprotocol: Option<String>,
+        trace: Option<TraceContext>,
+        reservations: Reservations,
+    },
+
+    /// Keep the connection open, and look for another request after this
+    /// one's length in bytes.
+    Request(usize),
+
+    Close,
+}
+
+/// What `handle_client` reports once a connection ends.
+struct Summary {
+    /// Made when the connection was accepted.
+    request_id: Ulid,
+
+    /// The connection's ID and when it was upgraded, if it was.
+    opened: Option<(ConnectionId, Instant)>,
+
+    /// The first close status sent or received.
+    code: Option<u16>,
+
+    /// The path it was upgraded on, and the subprotocol agreed.
+    route: String,
+    subprotocol: Option<String>,
+}
+
+impl Summary {
+    fn new(request_id: Ulid) -> Self {
+        Summary {
+            request_id,
+            opened: None,
+            code: None,
+            route: String::new(),
+            subprotocol: None,
+        }
+    }
+
+    fn closed_with(&mut self, code: u16) {
+        self.code.get_or_insert(code);
+    }
+}
+
+async fn run<S: Transport>(
+    socket: &mut Counted<S>,
+    peer: SocketAddr,
+    tags: &[String],
+    server: &Arc<Server>,
+    summary: &mut Summary,
+) -> io::Result<()> {
+    let config = &server.config;
+    let pool = &server.pool;
+
+    let mut done_handshake = false;
+
+    // The connection's slots under the connection and route limits, taken
+    // by the hooks that let it through and held until it is dropped
+    let mut _reservations = Reservations::default();
+    let mut registration: Option<Registration> = None;
+
+    // Bytes read from the socket that haven't been parsed yet. This is only
+    // taken from the pool when there is something to read, and given back
+    // once everything in it has been handled, so idle connections don't hold
+    // on to a buffer.
+    let mut pending: Option<PooledBuf> = None;
+
+    // Encoded frames waiting to be written, and when they have to be written
+    // by if no more frames join them
+    let mut batch: Option<PooledBuf> = None;
+    let mut flush_at: Option<Instant> = None;
+
+    let mut conn = WsConnection::new()
+        .with_max_message_size(config.max_buffered_bytes)
+        .with_auto_pong(config.auto_pong)
+        .with_oversized_control(config.oversized_control);
+
+    // The message being received. It stays around between reads while the
+    // frames of a fragmented message arrive.
+    let mut partial_message: Option<PooledBuf> = None;
+
+    // What this connection has buffered, counted against the server's cap
+    let mut account = server.memory.account();
+
+    // Caps how fast writes go out
+    let mut throttle = match config.max_send_rate {
+        0 => None,
+        rate => Some(Throttle::new(rate, config.send_burst)),
+    };
+
+    // Whether the client asked for batches, and the echoes from the current
+    // read waiting to be sent as one
+    let mut batching = false;
+    let mut packed = Vec::new();
+
+    // Faults to inject into echoes, once the connection has an ID to seed
+    // them with
+    let mut chaos = None;
+
+    // Frames handled since this task last gave up its worker
+    let mut frames_since_yield = 0;
+
+    // Round-trip time measurement, and when the next ping is due once the
+    // connection has been upgraded
+    let mut rtt = RttEstimator::new();
+    let mut ping_at: Option<Instant> = None;
+
+    // Dropped once the server has started draining or shutting down, and
+    // this connection has been told
+    let mut draining = Some(server.draining.subscribe());
+    let mut shutdown = Some(server.shutdown.subscribe());
+
+    let request_id = summary.request_id;
+    let mut log = MessageLog::new(server, peer, request_id);
+
+    // The 101 carries the request ID, for clients to quote back
+    let mut response_headers = config.response_headers.clone();
+    response_headers.push((String::from("X-Request-Id"), request_id.to_string()));
+
+    // The degradation level, and when the client last sent anything, so
+    // quiet connections can be closed when memory runs short
+    let mut pressure = server.pressure.subscribe();
+    let mut last_active = Instant::now();
+
+    'connection: loop {
+        let idle_at =
+            (done_handshake && !conn.is_closing() && *pressure.borrow() >= Degradation::CloseIdle)
+                .then(|| last_active + config.idle_close_after);
+
+        if let Some(buf) = &mut pending {
+            make_room(buf, config.read_buffer_growth);
+        }
+        let mut read = None;
+
+        tokio::select! {
+            _ = turned_on(&mut draining) => {
+                draining = None;
+
+                if let (true, Some(message)) = (done_handshake, &config.drain_message) {
+                    // It goes out like any other message, e.g. signed
+                    let mut message = message.clone();
+                    log.outbound(&message);
+                    if server.interceptors.outbound(&mut message) == Action::Pass
+                        && conn.send_binary(&alone(batching, message)).is_ok()
+                    {
+                        queue_output(&mut conn, &mut batch, pool);
+                        flush(socket, &mut batch, &mut throttle).await?;
+                        flush_at = None;
+                    }
+                }
+                continue;
+            }
+            instruction = instructed(&mut registration) => {
+                match instruction {
+                    // A message the interceptors and batching leave alone is
+                    // the same frame for every recipient
+                    Instruction::Send(outgoing) if !batching && server.interceptors.is_empty() => {
+                        log.outbound(outgoing.message());
+                        if conn.send_encoded(outgoing.frame()).is_err() {
+                            continue;
+                        }
+                    }
+                    Instruction::Send(outgoing) => {
+                        let mut message = outgoing.message().to_vec();
+                        log.outbound(&message);
+                        if server.interceptors.outbound(&mut message) != Action::Pass
+                            || conn.send_binary(&alone(batching, message)).is_err()
+                        {
+                            continue;
+                        }
+                    }
+                    Instruction::Close(frame) => {
+                        // Keep reading until the peer answers the close
+                        summary.closed_with(frame.code);
+                        conn.close(frame.code, &frame.reason);
+                    }
+                }
+                queue_output(&mut conn, &mut batch, pool);
+                flush(socket, &mut batch, &mut throttle).await?;
+                flush_at = None;
+                continue;
+            }
+            _ = at(ping_at) => {
+                let payload = rtt.ping(Instant::now());
+                if conn.send_ping(&payload).is_ok() {
+                    queue_output(&mut conn, &mut batch, pool);
+                    flush(socket, &mut batch, &mut throttle).await?;
+                    flush_at = None;
+                }
+                ping_at = Some(Instant::now() + config.rtt_interval);
+                continue;
+            }
+            // Works out the idle deadline again
+            _ = pressure.changed() => continue,
+            _ = at(idle_at) => {
+                // Keep reading until the peer answers the close
+                summary.closed_with(1013);
+                conn.close(1013, "server is low on memory");
+                queue_output(&mut conn, &mut batch, pool);
+                flush(socket, &mut batch, &mut throttle).await?;
+                flush_at = None;
+                continue;
+            }
+            _ = turned_on(&mut shutdown) => {
+                if !done_handshake {
+                    return Ok(());
+                }
+
+                if let (false, Some(registration)) =
+                    (config.reconnect_spread.is_zero(), &registration)
+                {
+                    let mut message = retry_after(registration.id(), config.reconnect_spread);
+                    log.outbound(&message);
+                    if server.interceptors.outbound(&mut message) == Action::Pass {
+                        let _ = conn.send_binary(&alone(batching, message));
+                    }
+                }
+
+                // Keep reading until the peer answers the close
+                shutdown = None;
+                summary.closed_with(1001);
+                conn.close(1001, "server shutting down");
+                queue_output(&mut conn, &mut batch, pool);
+                flush(socket, &mut batch, &mut throttle).await?;
+                flush_at = None;
+                continue;
+            }
+            woken = wait(socket, flush_at, pending.as_deref_mut()) => {
+                match woken? {
+                    Woken::Readable => {}
+                    Woken::Read(n) => read = Some(n),
+                    Woken::FlushDue => {
+                        flush(socket, &mut batch, &mut throttle).await?;
+                        flush_at = None;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        let buf = pending.get_or_insert_with(|| pool.get());
+        let n = match read {
+            Some(n) => n,
+            None => {
+                make_room(buf, config.read_buffer_growth);
+                socket.read_buf(&mut **buf).await?
+            }
+        };
+
+        if n == 0 {
+            return Ok(());
+        }
+        last_active = Instant::now();
+
+        if buf.len() > config.max_buffered_bytes {
+            return Ok(());
+        }
+
+        account.set(buf.capacity() + capacity(&partial_message) + capacity(&batch));
+        if account.over_share(server.active_connections.load(Ordering::Relaxed)) {
+            if done_handshake {
+                summary.closed_with(1013);
+                conn.close(1013, "server is low on memory");
+                queue_output(&mut conn, &mut batch, pool);
+                flush(socket, &mut batch, &mut throttle).await?;
+            }
+            return Ok(());
+        }
+
+        // With keep-alive, several plain requests may be waiting in the
+        // buffer, possibly followed by an upgrade
+        while !done_handshake {
+            // The path and request length, if this is an upgrade, or the
+            // request length if the connection stays open for another request
+            let (response, next) = match handshake::handshake_response(
+                buf,
+                &server.upgrade_hooks,
+                &response_headers,
+            ) {
+                Some(Handshake::Upgrade {
+                    response,
+                    path,
+                    len,
+                    subprotocol,
+                    trace,
+                    reservations,
+                }) => {
+                    let next = Next::Upgrade {
+                        path,
+                        len,
+                        subprotocol,
+                        trace,
+                        reservations,
+                    };
+                    (response.into_bytes(), next)
+                }
+                Some(Handshake::Page {
+                    path,
+                    len,
+                    keep_alive,
+                }) => {
+                    let response = if config.ready_path.as_deref() == Some(upgrade::route(&path)) {
+                        let ready = !*server.draining.borrow() && !*server.standby.borrow();
+                        handshake::readiness(ready).into_bytes()
+                    } else if config.metrics_path.as_deref() == Some(upgrade::route(&path)) {
+                        let open = server.active_connections.load(Ordering::Relaxed);
+                        let body = server.metrics.render(open);
+                        handshake::text_response(200, &body).into_bytes()
+                    } else {
+                        match &config.static_root {
+                            Some(root) => static_files::response(root, &path).await,
+                            None => handshake::upgrade_required().into_bytes(),
+                        }
+                    };
+
+                    if keep_alive && config.http_keep_alive {
+                        (handshake::keep_alive(&response), Next::Request(len))
+                    } else {
+                        (response, Next::Close)
+                    }
+                }
+                Some(Handshake::Reject(response, reason)) => {
+                    server.metrics.handshake_failed(reason);
+                    server.emit(|| ServerEvent::HandshakeRejected {
+                        peer,
+                        request_id,
+                        reason,
+                    });
+                    (response.into_bytes(), Next::Close)
+                }
+                // The request hasn't fully arrived yet
+                None => continue 'connection,
+            };
+
+            socket.write_all(&response).await?;
+
+            let (path, len, subprotocol, trace) = match next {
+                Next::Upgrade {
+                    path,
+                    len,
+                    subprotocol,
+                    trace,
+                    reservations,
+                } => {
+                    _reservations = reservations;
+                    (path, len, subprotocol, trace)
+                }
+                Next::Request(len) => {
+                    buf.drain(..len);
+                    if buf.is_empty() {
+                        pending = None;
+                        continue 'connection;
+                    }
+                    continue;
+                }
+                Next::Close => return Ok(()),
+            };
+
+            let registered = server.connections.register(peer, request_id);
+            let id = registered.id();
+            for tag in tags {
+                // Tags that aren't labels are only there for the event
+                let _ = server.connections.label(id, tag);
+            }
+            if config.log_messages {
+                match &trace {
+                    Some(trace) => println!(
+                        "{peer} {request_id} is connection {id} in trace {}",
+                        trace.trace_id()
+                    ),
+                    None => println!("{peer} {request_id} is connection {id}"),
+                }
+            }
+            registration = Some(registered);
+
+            summary.opened = Some((id, Instant::now()));
+            summary.route = String::from(upgrade::route(&path));
+            summary.subprotocol = subprotocol.clone();
+            server.emit(|| ServerEvent::ConnectionOpened {
+                id,
+                peer,
+                request_id,
+                path,
+                trace,
+                tags: tags.to_vec(),
+            });
+
+            done_handshake = true;
+            batching = subprotocol.as_deref() == Some(batch::PROTOCOL);
+            if !config.chaos.is_off() {
+                let seed = config.chaos_seed ^ id.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+                chaos = Some(Chaos::new(config.chaos, config.chaos_max_delay, seed));
+            }
+            if !config.rtt_interval.is_zero() {
+                ping_at = Some(Instant::now() + config.rtt_interval);
+            }
+
+            // Frames the client sent without waiting for the response are
+            // handled straight away
+            buf.drain(..len);
+            if buf.is_empty() {
+                pending = None;
+                continue 'connection;
+            }
+        }
+
+        // Not a handshake, treat it as WebSocket frames. There may be several
+        // in the buffer, possibly followed by the start of one that hasn't
+        // fully arrived yet.
+        let mut parsed = 0;
+
+        loop {
+            // Control frames can be answered straight away, e.g. a close
+            // with its reply, so the echoes before them go out first
+            if !packed.is_empty() {
+                let header = codec::parse_frame_header(&buf[parsed..]);
+                if let Ok(Some(header)) = header {
+                    if codec::opcode::is_control(header.opcode) {
+                        send_packed(&mut conn, &mut packed);
+                    }
+                }
+            }
+
+            let message = partial_message.get_or_insert_with(|| pool.get());
+            let received = conn.receive_in_place(&mut buf[parsed..], message);
+
+            // Pongs, close replies and protocol error closes
+            queue_output(&mut conn, &mut batch, pool);
+
+            let received = match received {
+                Ok(received) => received,
+                Err(_) => {
+                    flush(socket, &mut batch, &mut throttle).await?;
+                    return Ok(());
+                }
+            };
+
+            if received.consumed == 0 {
+                break;
+            }
+            let frame_start = parsed;
+            parsed += received.consumed;
+
+            // A buffer full of small frames is handled without touching the
+            // socket, so tokio's own budget never makes this task yield
+            frames_since_yield += 1;
+            if frames_since_yield == config.frames_per_yield {
+                frames_since_yield = 0;
+                tokio::task::yield_now().await;
+            }
+
+            match received.event {
+                Some(Event::Binary) => {}
+                Some(Event::Text) => {
+                    send_packed(&mut conn, &mut packed);
+                    summary.closed_with(1003);
+                    conn.close(1003, "only binary messages are supported");
+                    queue_output(&mut conn, &mut batch, pool);
+                    partial_message = None;
+                    continue;
+                }
+                Some(Event::Close(frame)) => {
+                    summary.closed_with(frame.as_ref().map_or(1005, |frame| frame.code));
+
+                    if config.log_messages {
+                        match frame {
+                            Some(frame) => {
+                                let name = server.close_codes.name(frame.code);
+                                println!(
+                                    "{peer} {request_id} closed with {} ({name}): {}",
+                                    frame.code, frame.reason
+                                )
+                            }
+                            None => println!("{peer} {request_id} closed without a status code"),
+                        }
+                    }
+
+                    flush(socket, &mut batch, &mut throttle).await?;
+                    return Ok(());
+                }
+                Some(Event::Ping(mut payload)) if !config.auto_pong => {
+                    // A pong too big to send is the same as dropping it
+                    if server.interceptors.ping(&mut payload) == Action::Pass
+                        && conn.send_pong(&payload).is_ok()
+                    {
+                        queue_output(&mut conn, &mut batch, pool);
+                    }
+                    continue;
+                }
+                Some(Event::Pong(payload)) => {
+                    if rtt.pong(&payload, Instant::now()).is_some() {
+                        if let Some(registration) = &registration {
+                            registration.set_rtt(rtt.smoothed());
+                        }
+                    }
+                    continue;
+                }
+                // Fragments of a message and pings
+                _ => continue,
+            }
+
+            let mut message = partial_message.take().unwrap();
+
+            // An unfragmented message is echoed straight from the read
+            // buffer, unless interceptors need it in a buffer of its own
+            let mut in_place = received
+                .in_place
+                .map(|range| frame_start + range.start..frame_start + range.end);
+            if let Some(range) = in_place.clone().filter(|_| !server.interceptors.is_empty()) {
+                message.extend_from_slice(&buf[range]);
+                in_place = None;
+            }
+
+            if in_place.is_none() && server.interceptors.inbound(&mut message) == Action::Drop {
+                continue;
+            }
+
+            let payload = in_place.clone().map_or(&message[..], |range| &buf[range]);
+            log.inbound(payload);
+            let inspection =
+                inspect::inspect_within(&server.inspectors, payload, config.inspect_deadline).await;
+            let Some(inspection) = inspection else {
+                if config.on_inspect_deadline == OnDeadline::Close {
+                    send_packed(&mut conn, &mut packed);
+                    summary.closed_with(1013);
+                    conn.close(1013, "message took too long to handle");
+                    queue_output(&mut conn, &mut batch, pool);
+                    flush(socket, &mut batch, &mut throttle).await?;
+
+                    // Keep reading until the peer answers the close
+                    continue;
+                }
+
+                // Sent in place of the echo, after any echoes before it
+                let mut busy = inspect::BUSY.to_vec();
+                log.outbound(&busy);
+                if server.interceptors.outbound(&mut busy) == Action::Pass {
+                    send_packed(&mut conn, &mut packed);
+                    let _ = conn.send_binary(&alone(batching, busy));
+                }
+                continue;
+            };
+
+            match inspection {
+                Inspection::Pass => {}
+                Inspection::Drop => continue,
+                Inspection::Flag(reason) => {
+                    println!("Flagged message from {peer} {request_id}: {reason}")
+                }
+                Inspection::Close(reason) => {
+                    send_packed(&mut conn, &mut packed);
+                    summary.closed_with(1008);
+                    conn.close(1008, &reason);
+                    queue_output(&mut conn, &mut batch, pool);
+                    flush(socket, &mut batch, &mut throttle).await?;
+                    return Ok(());
+                }
+            }
+
+            log.outbound(in_place.clone().map_or(&message[..], |range| &buf[range]));
+            if in_place.is_none() && server.interceptors.outbound(&mut message) == Action::Drop {
+                continue;
+            }
+
+            // Echo back the message. This fails if we've started closing, in
+            // which case there's nothing to do.
+            let payload = in_place.map_or(&message[..], |range| &buf[range]);
+            if batching {
+                if batch::push(&mut packed, payload).is_err() {
+                    continue;
+                }
+                if packed.len() < config.write_batch_size {
+                    continue;
+                }
+                send_packed(&mut conn, &mut packed);
+            } else if conn.send_binary(payload).is_err() {
+                continue;
+            }
+            if !inject(
+                &mut chaos,
+                socket,
+                &mut conn,
+                &mut batch,
+                &mut throttle,
+                pool,
+            )
+            .await?
+            {
+                return Ok(());
+            }
+            queue_output(&mut conn, &mut batch, pool);
+
+            if batch
+                .as_ref()
+                .is_some_and(|out| out.len() >= config.write_batch_size)
+            {
+                flush(socket, &mut batch, &mut throttle).await?;
+                flush_at = None;
+            }
+        }
+
+        buf.drain(..parsed);
+
+        send_packed(&mut conn, &mut packed);
+        if !inject(
+            &mut chaos,
+            socket,
+            &mut conn,
+            &mut batch,
+            &mut throttle,
+            pool,
+        )
+        .await?
+        {
+            return Ok(());
+        }
+        queue_output(&mut conn, &mut batch, pool);
+
+        if buf.is_empty() {
+            pending = None;
+        } else if server.pressure.level() >= Degradation::ShrinkBuffers {
+            buf.shrink_to(config.read_buffer_size);
+        }
+
+        if batch.is_some() {
+            let deadline =
+                *flush_at.get_or_insert_with(|| Instant::now() + config.write_batch_latency);
+
+            if deadline <= Instant::now() {
+                flush(socket, &mut batch, &mut throttle).await?;
+                flush_at = None;
+            }
+        }
+
+        account.set(capacity(&pending) + capacity(&partial_message) + capacity(&batch));
+    }
+}
+
+fn capacity(buf: &Option<PooledBuf>) -> usize {
+    buf.as_ref().map_or(0, |buf| buf.capacity())
+}
+
+/// What woke a connection up in `wait`.
+enum Woken {
+    /// The socket may have data, to be read into a buffer from the pool.
+    Readable,
+
+    /// This many bytes were read onto the end of the pending buffer.
+    Read(usize),
+
+    /// `flush_at` passed.
+    FlushDue,
+}
+
+/// Waits until the socket is readable, or `flush_at` passes. A connection
+/// part way through a request or frame already holds a buffer, so it is
+/// read into here, inside the `select!`, where a peer that stops sending
+/// half way can't keep kicks, pings and shutdown from getting through.
+async fn wait<S: Transport>(
+    socket: &mut S,
+    flush_at: Option<Instant>,
+    pending: Option<&mut Vec<u8>>,
+) -> io::Result<Woken> {
+    let woken = async {
+        match pending {
+            Some(buf) => socket.read_buf(buf).await.map(Woken::Read),
+            None => socket.readable().await.map(|()| Woken::Readable),
+        }
+    };
+
+    match flush_at {
+        Some(deadline) => time::timeout_at(deadline, woken)
+            .await
+            .unwrap_or(Ok(Woken::FlushDue)),
+        None => woken.await,
+    }
+}
+
+/// Grows a full read buffer, so the next read has somewhere to go.
+fn make_room(buf: &mut Vec<u8>, growth: Growth) {
+    if buf.len() == buf.capacity() {
+        let additional = match growth {
+            Growth::Double => buf.capacity().max(1),
+            Growth::Linear(n) => n,
+        };
+        buf.reserve_exact(additional);
+    }
+}
+
+/// Resolves with the next message or kick from the registry. Never
+/// resolves before the upgrade.
+async fn instructed(registration: &mut Option<Registration>) -> Instruction {
+    match registration {
+        Some(registration) => registration.instruction().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves at `deadline`. Never resolves if it is `None`.
+async fn at(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves once `flag` is set. Never resolves if it is `None`.
+async fn turned_on(flag: &mut Option<watch::Receiver<bool>>) {
+    if let Some(flag) = flag {
+        if flag.wait_for(|&on| on).await.is_ok() {
+            return;
+        }
+    }
+
+    // A dropped sender can't set it any more
+    std::future::pending().await
+}
+
+/// Rolls for a fault on the echo frame the connection has just queued, and
+/// applies it. Returns `false` if the connection has to end.
+async fn inject<S: Transport>(
+    chaos: &mut Option<Chaos>,
+    socket: &mut S,
+    conn: &mut WsConnection,
+    batch: &mut Option<PooledBuf>,
+    throttle: &mut Option<Throttle>,
+    pool: &Arc<BufferPool>,
+) -> io::Result<bool> {
+    let Some(chaos) = chaos else {
+        return Ok(true);
+    };
+    if conn.output().is_empty() {
+        return Ok(true);
+    }
+
+    let mut frame = Vec::new();
+    conn.take_output(&mut frame);
+
+    match chaos.roll(frame.len()) {
+        None => {}
+        Some(Fault::Delay(delay)) => {
+            flush(socket, batch, throttle).await?;
+            time::sleep(delay).await;
+        }
+        Some(Fault::Drop) => return Ok(true),
+        Some(Fault::Reset) => {
+            socket.reset();
+            return Ok(false);
+        }
+        Some(Fault::Cut(len)) => {
+            flush(socket, batch, throttle).await?;
+            socket.write_all(&frame[..len]).await?;
+            return Ok(false);
+        }
+        Some(Fault::Corrupt(index, mask)) => frame[index] ^= mask,
+    }
+
+    batch
+        .get_or_insert_with(|| pool.get())
+        .extend_from_slice(&frame);
+    Ok(true)
+}
+
+/// The `retry-after=<secs>` message for connection `id`. Consecutive IDs
+/// get delays far apart, so connections opened together, e.g. after the
+/// last restart, are spread over the whole of `spread` rather than bunched.
+fn retry_after(id: ConnectionId, spread: Duration) -> Vec<u8> {
+    // Multiples of the golden ratio, wrapped to a fraction, keep landing
+    // in the biggest gaps left by the ones before
+    let fraction = id.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let slots = spread.as_secs().max(1);
+    let secs = 1 + ((u128::from(fraction) * u128::from(slots)) >> 64) as u64;
+    format!("retry-after={secs}").into_bytes()
+}
+
+/// Prints the size of one connection's messages, when `log_messages` is on,
+/// as sampled by the server's `log_sampling`.
+struct MessageLog<'a> {
+    server: &'a Server,
+    peer: SocketAddr,
+    request_id: Ulid,
+    sampler: Option<Sampler>,
+}
+
+impl<'a> MessageLog<'a> {
+    fn new(server: &'a Server, peer: SocketAddr, request_id: Ulid) -> Self {
+        let sampler = server
+            .config
+            .log_messages
+            .then(|| Sampler::new(Instant::now()));
+        MessageLog {
+            server,
+            peer,
+            request_id,
+            sampler,
+        }
+    }
+
+    fn inbound(&mut self, message: &[u8]) {
+        self.log("<-", message);
+    }
+
+    fn outbound(&mut self, message: &[u8]) {
+        self.log("->", message);
+    }
+
+    fn log(&mut self, arrow: &str, message: &[u8]) {
+        let Some(sampler) = &mut self.sampler else {
+            return;
+        };
+        let (peer, request_id) = (self.peer, self.request_id);
+        match sampler.sample(&self.server.log_sampling, Instant::now()) {
+            None => {}
+            Some(0) => println!("{peer} {request_id} {arrow} {} bytes", message.len()),
+            Some(skipped) => {
+                println!(
+                    "{peer} {request_id} {arrow} {} bytes (+{skipped} not logged)",
+                    message.len()
+                )
+            }
+        }
+    }
+}
+
+/// Sends the echoes packed so far as one batch message.
+fn send_packed(conn: &mut WsConnection, packed: &mut Vec<u8>) {
+    if !packed.is_empty() {
+        // Fails if we've started closing, and then they can't be sent anyway
+        let _ = conn.send_binary(packed);
+        packed.clear();
+    }
+}
+
+/// `message` as the client expects it: on its own, or as a batch of one if
+/// it asked for batches.
+fn alone(batching: bool, message: Vec<u8>) -> Vec<u8> {
+    if !batching {
+        return message;
+    }
+
+    let mut packed = Vec::new();
+    match batch::push(&mut packed, &message) {
+        Ok(()) => packed,
+        Err(_) => message,
+    }
+}
+
+/// Moves anything the connection has to send onto the end of the batch.
+fn queue_output(conn: &mut WsConnection, batch: &mut Option<PooledBuf>, pool: &Arc<BufferPool>) {
+    if !conn.output().is_empty() {
+        conn.take_output(batch.get_or_insert_with(|| pool.get()));
+    }
+}
+
+/// Writes out the batch, no faster than `throttle` allows if there is one.
+async fn flush<S: Transport>(
+    socket: &mut S,
+    batch: &mut Option<PooledBuf>,
+    throttle: &mut Option<Throttle>,
+) -> io::Result<()> {
+    let Some(out) = batch.take() else {
+        return Ok(());
+    };
+
+    let Some(throttle) = throttle else {
+        return socket.write_all(&out).await;
+    };
+
+    // Big batches go out a burst at a time, rather than at line rate after
+    // one long wait
+    for chunk in out.chunks(throttle.burst()) {
+        time::sleep(throttle.take(chunk.len(), Instant::now())).await;
+        socket.write_all(chunk).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::chaos::Faults;
+    use crate::intercept::Interceptor;
+    use crate::recover::ErrorResponse;
+    use crate::testing;
+    use crate::Message;
+
+    #[tokio::test(start_paused = true)]
+    async fn batched_writes_wait_for_the_latency() {
+        let config = Config {
+            write_batch_latency: Duration::from_millis(50),
+            ..Config::default()
+        };
+        let server = Arc::new(Server::from_config(config).unwrap());
+        let mut client = testing::connect_pair(server, "/").await.unwrap();
+
+        let start = Instant::now();
+        client
+            .send(Message::Binary(b"Hello".to_vec()))
+            .await
+            .unwrap();
+
+        // The clock only moves when everything is waiting on a timer, so
+        // the echo shows up exactly when the batch deadline passes
+        assert_eq!(
+            client.read().await.unwrap(),
+            Message::Binary(b"Hello".to_vec())
+        );
+        assert_eq!(start.elapsed(), Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_open_connections() {
+        let server = Arc::new(Server::from_config(Config::default()).unwrap());
+        let mut client = testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .unwrap();
+
+        server.shutdown.send_replace(true);
+
+        match client.read().await.unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, 1001),
+            other => panic!("expected a close, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_staggers_reconnects() {
+        let config = Config {
+            reconnect_spread: Duration::from_secs(60),
+            ..Config::default()
+        };
+        let server = Arc::new(Server::from_config(config).unwrap());
+        let mut client = testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .unwrap();
+        let id = server.connections.list()[0].0;
+
+        server.shutdown.send_replace(true);
+
+        let expected = retry_after(id, Duration::from_secs(60));
+        assert_eq!(client.read().await.unwrap(), Message::Binary(expected));
+        assert!(matches!(client.read().await.unwrap(), Message::Close(_)));
+
+        // Neighbouring connections are told to wait different amounts
+        let delays: std::collections::HashSet<_> = (0..10)
+            .map(|id| retry_after(id, Duration::from_secs(60)))
+            .collect();
+        assert_eq!(delays.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn memory_pressure_closes_idle_connections() {
+        let config = Config {
+            soft_memory_limit: 1000,
+            idle_close_after: Duration::ZERO,
+            ..Config::default()
+        };
+        let server = Arc::new(Server::from_config(config).unwrap());
+        let mut client = testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .unwrap();
+
+        assert_eq!(server.pressure.update(1000), Some(Degradation::CloseIdle));
+
+        match client.read().await.unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, 1013),
+            other => panic!("expected a close, got {other:?}"),
+        }
+        assert!(testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn the_101_carries_the_request_id() {
+        let server = Arc::new(Server::from_config(Config::default()).unwrap());
+        let (mut client, server_end) = testing::duplex();
+        tokio::spawn(handle_client(
+            server_end,
+            testing::PEER,
+            Vec::new(),
+            Arc::clone(&server),
+        ));
+
+        let request = handshake::upgrade_request("localhost", "/", "dGhlIHNhbXBsZSBub25jZQ==");
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = vec![];
+        while !response.ends_with(b"\r\n\r\n") {
+            client.read_buf(&mut response).await.unwrap();
+        }
+        let response = String::from_utf8(response).unwrap();
+        let header = response
+            .lines()
+            .find_map(|line| line.strip_prefix("X-Request-Id: "))
+            .unwrap();
+
+        let info = server.connections.list()[0].1;
+        assert_eq!(header.parse::<Ulid>().unwrap(), info.request_id);
+    }
+
+    #[tokio::test]
+    async fn geoip_tags_label_the_connection() {
+        let path = std::env::temp_dir().join(format!("wocket-geo-{}.csv", std::process::id()));
+        std::fs::write(&path, "127.0.0.0/8,NZ\n").unwrap();
+        let table = GeoTable::load(path.to_str().unwrap(), vec![]).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let policies: Vec<Box<dyn AcceptPolicy>> = vec![Box::new(table)];
+        let tags = crate::policy::check_all(&policies, testing::PEER).unwrap();
+
+        let server = Arc::new(Server::from_config(Config::default()).unwrap());
+        let mut events = server.subscribe();
+        let (mut client, server_end) = testing::duplex();
+        tokio::spawn(handle_client(
+            server_end,
+            testing::PEER,
+            tags,
+            Arc::clone(&server),
+        ));
+
+        let request = handshake::upgrade_request("localhost", "/", "dGhlIHNhbXBsZSBub25jZQ==");
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        match events.recv().await.unwrap() {
+            ServerEvent::ConnectionOpened { id, tags, .. } => {
+                assert_eq!(tags, ["country=NZ"]);
+                assert_eq!(server.connections.labels(id), ["country=NZ"]);
+            }
+            other => panic!("expected an open, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_pipelined_after_the_request() {
+        let server = Arc::new(Server::from_config(Config::default()).unwrap());
+        let (mut client, server_end) = testing::duplex();
+        tokio::spawn(handle_client(server_end, testing::PEER, Vec::new(), server));
+
+        let mut conn = WsConnection::client(1);
+        conn.send_binary(b"early").unwrap();
+        let mut request =
+            handshake::upgrade_request("localhost", "/", "dGhlIHNhbXBsZSBub25jZQ==").into_bytes();
+        conn.take_output(&mut request);
+        client.write_all(&request).await.unwrap();
+
+        // The echo comes back after the 101, without anything more being sent
+        let mut received = vec![];
+        let echo = loop {
+            client.read_buf(&mut received).await.unwrap();
+            let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") else {
+                continue;
+            };
+
+            let mut message = vec![];
+            match conn
+                .receive(&received[end + 4..], &mut message)
+                .unwrap()
+                .event
+            {
+                Some(Event::Binary) => break message,
+                _ => continue,
+            }
+        };
+        assert_eq!(echo, b"early");
+    }
+
+    #[tokio::test]
+    async fn echoes_from_one_read_come_back_in_one_batch() {
+        let config = Config {
+            message_batching: true,
+            ..Config::default()
+        };
+        let server = Arc::new(Server::from_config(config).unwrap());
+        let (mut client, server_end) = testing::duplex();
+        tokio::spawn(handle_client(server_end, testing::PEER, Vec::new(), server));
+
+        let request = handshake::upgrade_request("localhost", "/", "dGhlIHNhbXBsZSBub25jZQ==")
+            .replace(
+                "\r\n\r\n",
+                "\r\nSec-WebSocket-Protocol: wocket.batch.v1\r\n\r\n",
+            );
+        let mut request = request.into_bytes();
+        let mut conn = WsConnection::client(1);
+        for message in [&b"one"[..], b"two", b"three"] {
+            conn.send_binary(message).unwrap();
+        }
+        conn.take_output(&mut request);
+        client.write_all(&request).await.unwrap();
+
+        let mut received = vec![];
+        let (end, packed) = loop {
+            client.read_buf(&mut received).await.unwrap();
+            let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") else {
+                continue;
+            };
+
+            let mut message = vec![];
+            match conn
+                .receive(&received[end + 4..], &mut message)
+                .unwrap()
+                .event
+            {
+                Some(Event::Binary) => break (end, message),
+                _ => continue,
+            }
+        };
+
+        let response = String::from_utf8_lossy(&received[..end]);
+        assert!(response.contains("Sec-WebSocket-Protocol: wocket.batch.v1"));
+        let messages: Vec<_> = batch::unpack(&packed).map(Result::unwrap).collect();
+        assert_eq!(messages, [&b"one"[..], b"two", b"three"]);
+    }
+
+    #[tokio::test]
+    async fn chaos_cuts_echoes_short() {
+        let config = Config {
+            chaos: Faults::parse("cut=1").unwrap(),
+            ..Config::default()
+        };
+        let server = Arc::new(Server::from_config(config).unwrap());
+        let mut client = testing::connect_pair(server, "/").await.unwrap();
+
+        client.send(Message::Binary(vec![7; 1000])).await.unwrap();
+        match client.read().await {
+            Err(crate::Error::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("expected the pipe to close, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_alive_then_upgrade() {
+        let config = Config {
+            http_keep_alive: true,
+            ready_path: Some(String::from("/ready")),
+            ..Config::default()
+        };
+        let server = Arc::new(Server::from_config(config).unwrap());
+        let (mut client, server_end) = testing::duplex();
+        tokio::spawn(handle_client(server_end, testing::PEER, Vec::new(), server));
+
+        let ready = b"GET /ready HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        client
+            .write_all(&[&ready[..], &ready[..]].concat())
+            .await
+            .unwrap();
+
+        // Both pipelined requests are answered on the one connection
+        let mut received = vec![];
+        while received.windows(5).filter(|w| w == b"ready").count() < 2 {
+            client.read_buf(&mut received).await.unwrap();
+        }
+        assert!(received.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        let request = handshake::upgrade_request("localhost", "/", "dGhlIHNhbXBsZSBub25jZQ==");
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let mut received = vec![];
+        while !received.ends_with(b"\r\n\r\n") {
+            client.read_buf(&mut received).await.unwrap();
+        }
+        assert!(received.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+    }
+
+    struct Heartbeat;
+
+    impl Interceptor for Heartbeat {
+        fn ping(&self, payload: &mut Vec<u8>) -> Action {
+            payload.extend_from_slice(b" load=3");
+            Action::Pass
+        }
+    }
+
+    #[tokio::test]
+    async fn interceptors_answer_pings() {
+        let config = Config {
+            auto_pong: false,
+            ..Config::default()
+        };
+        let mut server = Server::from_config(config).unwrap();
+        server.interceptors = Chain::new().with(Heartbeat);
+        let mut client = testing::connect_pair(Arc::new(server), "/").await.unwrap();
+
+        client.send(Message::Ping(b"hb".to_vec())).await.unwrap();
+        assert_eq!(
+            client.read().await.unwrap(),
+            Message::Pong(b"hb load=3".to_vec())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pongs_measure_the_round_trip() {
+        let config = Config {
+            rtt_interval: Duration::from_secs(5),
+            ..Config::default()
+        };
+        let server = Arc::new(Server::from_config(config).unwrap());
+        let mut client = testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .unwrap();
+        assert_eq!(server.connections.list()[0].1.rtt, None);
+
+        // Reading the ping answers it
+        assert!(matches!(client.read().await.unwrap(), Message::Ping(_)));
+        while server.connections.list()[0].1.rtt.is_none() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn lifecycle_events() {
+        let server = Arc::new(Server::from_config(Config::default()).unwrap());
+        let mut events = server.subscribe();
+        let mut client = testing::connect_pair(Arc::clone(&server), "/chat")
+            .await
+            .unwrap();
+
+        let (opened_id, opened_request_id) = match events.recv().await.unwrap() {
+            ServerEvent::ConnectionOpened {
+                id,
+                path,
+                request_id,
+                ..
+            } => {
+                assert_eq!(path, "/chat");
+                (id, request_id)
+            }
+            other => panic!("expected an open, got {other:?}"),
+        };
+
+        client.send(Message::Close(None)).await.unwrap();
+        client.read().await.unwrap();
+
+        match events.recv().await.unwrap() {
+            ServerEvent::ConnectionClosed {
+                id,
+                request_id,
+                code,
+                bytes_received,
+                ..
+            } => {
+                assert_eq!((id, request_id), (opened_id, opened_request_id));
+                assert_eq!(code, Some(1000));
+                assert!(bytes_received > 0);
+            }
+            other => panic!("expected a close, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn banning_kicks_open_connections() {
+        let server = Arc::new(Server::from_config(Config::default()).unwrap());
+        let mut client = testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .unwrap();
+
+        server.ban(testing::PEER.ip()).unwrap();
+
+        match client.read().await.unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, 1008),
+            other => panic!("expected a close, got {other:?}"),
+        }
+        assert!(server.bans.is_banned(testing::PEER.ip()));
+    }
+
+    #[tokio::test]
+    async fn kicks_reach_a_connection_holding_half_a_frame() {
+        let server = Arc::new(Server::from_config(Config::default()).unwrap());
+        let (mut client, server_end) = testing::duplex();
+        tokio::spawn(handle_client(
+            server_end,
+            testing::PEER,
+            Vec::new(),
+            Arc::clone(&server),
+        ));
+
+        let request = handshake::upgrade_request("localhost", "/", "dGhlIHNhbXBsZSBub25jZQ==");
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![];
+        while !response.ends_with(b"\r\n\r\n") {
+            client.read_buf(&mut response).await.unwrap();
+        }
+
+        // A masked binary frame's first two bytes, and nothing more
+        client.write_all(&[0x82, 0x85]).await.unwrap();
+        tokio::task::yield_now().await;
+
+        let (id, _) = server.connections.list()[0];
+        assert!(server.kick(id, 4000, "bye"));
+
+        let mut close = [0; 4];
+        time::timeout(Duration::from_secs(2), client.read_exact(&mut close))
+            .await
+            .expect("no close frame while the frame was half received")
+            .unwrap();
+        assert_eq!(close, [0x88, 5, 0x0f, 0xa0]);
+    }
+
+    #[tokio::test]
+    async fn a_panicking_interceptor_closes_its_connection() {
+        struct Panics;
+
+        impl Interceptor for Panics {
+            fn inbound(&self, message: &mut Vec<u8>) -> Action {
+                assert!(message != b"boom", "can't handle {message:?}");
+                Action::Pass
+            }
+        }
+
+        let mut server = Server::from_config(Config::default()).unwrap();
+        server.interceptors = Chain::new().with(Panics);
+        let server = Arc::new(server);
+
+        let mut fine = testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .unwrap();
+        let mut client = testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .unwrap();
+        client
+            .send(Message::Binary(b"boom".to_vec()))
+            .await
+            .unwrap();
+
+        match client.read().await.unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, 1011),
+            other => panic!("expected a close, got {other:?}"),
+        }
+        assert!(server
+            .metrics
+            .render(0)
+            .contains("\nwocket_panics_total 1\n"));
+
+        // Only that one connection is closed
+        fine.send(Message::Binary(b"still here".to_vec()))
+            .await
+            .unwrap();
+        let echo = fine.read().await.unwrap();
+        assert_eq!(echo, Message::Binary(b"still here".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn internal_errors_get_the_configured_response() {
+        struct Panics;
+
+        impl Interceptor for Panics {
+            fn inbound(&self, _message: &mut Vec<u8>) -> Action {
+                panic!("always");
+            }
+        }
+
+        let config = Config {
+            internal_error: ErrorResponse {
+                code: 4500,
+                reason: String::from("incident {id}"),
+                message: Some(String::from(r#"{"error":"internal"}"#)),
+            },
+            ..Config::default()
+        };
+        let mut server = Server::from_config(config).unwrap();
+        server.interceptors = Chain::new().with(Panics);
+        let mut client = testing::connect_pair(Arc::new(server), "/").await.unwrap();
+        client.send(Message::Binary(b"hi".to_vec())).await.unwrap();
+
+        let error = Message::Binary(br#"{"error":"internal"}"#.to_vec());
+        assert_eq!(client.read().await.unwrap(), error);
+        match client.read().await.unwrap() {
+            Message::Close(Some(frame)) => {
+                assert_eq!((frame.code, &*frame.reason), (4500, "incident 0"))
+            }
+            other => panic!("expected a close, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_inspections_run_out_of_time() {
+        struct Stuck;
+
+        impl Inspector for Stuck {
+            fn inspect<'a>(&'a self, _message: &'a [u8]) -> inspect::BoxFuture<'a, Inspection> {
+                Box::pin(std::future::pending())
+            }
+        }
+
+        for on_deadline in [OnDeadline::Busy, OnDeadline::Close] {
+            let config = Config {
+                inspect_deadline: Duration::from_millis(20),
+                on_inspect_deadline: on_deadline,
+                ..Config::default()
+            };
+            let mut server = Server::from_config(config).unwrap();
+            server.inspectors.push(Box::new(Stuck));
+            let mut client = testing::connect_pair(Arc::new(server), "/").await.unwrap();
+            client.send(Message::Binary(b"hi".to_vec())).await.unwrap();
+
+            match (on_deadline, client.read().await.unwrap()) {
+                (OnDeadline::Busy, Message::Binary(busy)) => assert_eq!(busy, inspect::BUSY),
+                (OnDeadline::Close, Message::Close(Some(frame))) => assert_eq!(frame.code, 1013),
+                (_, other) => panic!("unexpected {other:?} with {on_deadline:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn closing_cancels_work_spawned_for_the_connection() {
+        let server = Arc::new(Server::from_config(Config::default()).unwrap());
+        let client = testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .unwrap();
+        let id = server.connections.list()[0].0;
+
+        let token = server.cancellation_token(id).unwrap();
+        let watcher = token.spawn(std::future::pending::<()>());
+
+        drop(client);
+        assert_eq!(watcher.await.unwrap(), None);
+        assert!(server.cancellation_token(id).is_none());
+    }
+
+    #[tokio::test]
+    async fn broadcasts_reach_labelled_connections() {
+        let server = Arc::new(Server::from_config(Config::default()).unwrap());
+        let mut labelled = testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .unwrap();
+        let mut other = testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .unwrap();
+
+        let mut ids: Vec<_> = server
+            .connections
+            .list()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        ids.sort();
+        server.label(ids[0], "tier=pro").unwrap();
+
+        assert_eq!(server.broadcast("tier=pro", b"news"), Ok(1));
+        assert_eq!(
+            labelled.read().await.unwrap(),
+            Message::Binary(b"news".to_vec())
+        );
+
+        other.send(Message::Binary(b"echo".to_vec())).await.unwrap();
+        assert_eq!(
+            other.read().await.unwrap(),
+            Message::Binary(b"echo".to_vec())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delayed_messages_arrive_on_time() {
+        let server = Arc::new(Server::from_config(Config::default()).unwrap());
+        let mut client = testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .unwrap();
+        let id = server.connections.list()[0].0;
+
+        let start = Instant::now();
+        server.send_after(Duration::from_secs(30), id, b"reminder");
+        assert_eq!(
+            client.read().await.unwrap(),
+            Message::Binary(b"reminder".to_vec())
+        );
+        assert!(start.elapsed() >= Duration::from_secs(30));
+        assert!(server.scheduler.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn draining_warns_then_closes() {
+        let config = Config {
+            drain_message: Some(b"reconnect".to_vec()),
+            drain_timeout: Duration::from_secs(5),
+            ..Config::default()
+        };
+        let server = Arc::new(Server::from_config(config).unwrap());
+        let mut client = testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .unwrap();
+
+        let drain = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.drain().await }
+        });
+
+        assert_eq!(
+            client.read().await.unwrap(),
+            Message::Binary(b"reconnect".to_vec())
+        );
+        assert!(testing::connect_pair(Arc::clone(&server), "/")
+            .await
+            .is_err());
+
+        let start = Instant::now();
+        match client.read().await.unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, 1001),
+            other => panic!("expected a close, got {other:?}"),
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        drain.await.unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+//! Spotting connections that can't keep up with the messages queued for
+//! them, before their outboxes fill and messages start being dropped.
+//!
+//! Outboxes are checked every `CHECK_INTERVAL`. A connection is slow once
+//! its outbox has been deeper than the high-water mark at every check for
+//! the configured time, and is reported once each time that happens.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::connections::{Backlog, ConnectionId};
+use crate::ulid::Ulid;
+
+/// How often outboxes are checked.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A connection whose outbox stayed above the high-water mark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowConsumer {
+    pub id: ConnectionId,
+    pub peer: SocketAddr,
+    pub request_id: Ulid,
+
+    /// Messages waiting for it.
+    pub depth: usize,
+
+    /// How long the oldest of them has been waiting.
+    pub oldest: Duration,
+}
+
+/// The connections found above the high-water mark so far.
+#[derive(Debug)]
+pub struct SlowConsumers {
+    high_water: usize,
+    after: Duration,
+    above: HashMap<ConnectionId, Above>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Above {
+    since: Instant,
+    reported: bool,
+}
+
+impl SlowConsumers {
+    /// Reports connections with more than `high_water` messages waiting
+    /// for `after` or longer.
+    pub fn new(high_water: usize, after: Duration) -> Self {
+        SlowConsumers {
+            high_water,
+            after,
+            above: HashMap::new(),
+        }
+    }
+
+    /// Takes the outboxes as they are at `now`, and returns the connections
+    /// that have just become slow.
+    pub fn check(
+        &mut self,
+        backlogs: Vec<(ConnectionId, Backlog)>,
+        now: Instant,
+    ) -> Vec<SlowConsumer> {
+        let mut slow = Vec::new();
+        let mut above = HashMap::new();
+
+        for (id, backlog) in backlogs {
+            if backlog.depth <= self.high_water {
+                continue;
+            }
+
+            let mut entry = self.above.get(&id).copied().unwrap_or(Above {
+                since: now,
+                reported: false,
+            });
+            if !entry.reported && now - entry.since >= self.after {
+                entry.reported = true;
+                slow.push(SlowConsumer {
+                    id,
+                    peer: backlog.peer,
+                    request_id: backlog.request_id,
+                    depth: backlog.depth,
+                    oldest: now - backlog.oldest,
+                });
+            }
+            above.insert(id, entry);
+        }
+
+        // Connections that dropped back down, or closed, start over
+        self.above = above;
+        slow
+    }
+
+    /// Connections that have been reported and are still above the
+    /// high-water mark.
+    pub fn slow(&self) -> usize {
+        self.above.values().filter(|entry| entry.reported).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::PEER;
+
+    #[test]
+    fn reported_once_per_stretch_above() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let backlog = |depth| {
+            let backlog = Backlog {
+                peer: PEER,
+                request_id: Ulid::new(),
+                depth,
+                oldest: start,
+            };
+            vec![(7, backlog)]
+        };
+
+        let mut consumers = SlowConsumers::new(10, Duration::from_secs(5));
+        assert!(consumers.check(backlog(11), at(0)).is_empty());
+        assert!(consumers.check(backlog(30), at(4)).is_empty());
+
+        let slow = consumers.check(backlog(40), at(5));
+        assert_eq!(slow.len(), 1);
+        assert_eq!((slow[0].id, slow[0].depth), (7, 40));
+        assert_eq!(slow[0].oldest, Duration::from_secs(5));
+        assert_eq!(consumers.slow(), 1);
+        assert!(consumers.check(backlog(40), at(6)).is_empty());
+
+        // Dropping to the high-water mark starts the clock again
+        assert!(consumers.check(backlog(10), at(7)).is_empty());
+        assert_eq!(consumers.slow(), 0);
+        assert!(consumers.check(backlog(11), at(8)).is_empty());
+        assert_eq!(consumers.check(backlog(11), at(13)).len(), 1);
+    }
+}
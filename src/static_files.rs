@@ -0,0 +1,168 @@
+use std::path::{Component, Path, PathBuf};
+
+use tokio::fs;
+
+/// Largest file served. Files are read whole into the response, so this
+/// keeps one request for a huge file from taking that much memory.
+pub const MAX_FILE_SIZE: u64 = 16 << 20;
+
+const NOT_FOUND: &[u8] =
+    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// Builds the HTTP response for a plain GET of `path`, served from `root`.
+/// `root` may be a directory, or a single file that is served for every
+/// path. Directories are served by their `index.html`.
+pub async fn response(root: &Path, path: &str) -> Vec<u8> {
+    let file = match resolve(root, path).await {
+        Some(file) => file,
+        None => return NOT_FOUND.to_vec(),
+    };
+
+    match fs::metadata(&file).await {
+        Ok(metadata) if metadata.len() > MAX_FILE_SIZE => {
+            let body = "file too large to serve";
+            return format!(
+                "HTTP/1.1 500 Internal Server Error\r\n\
+                Content-Type: text/plain; charset=utf-8\r\n\
+                Content-Length: {}\r\n\
+                Connection: close\r\n\r\n\
+                {body}",
+                body.len(),
+            )
+            .into_bytes();
+        }
+        Ok(_) => {}
+        Err(_) => return NOT_FOUND.to_vec(),
+    }
+
+    let body = match fs::read(&file).await {
+        Ok(body) => body,
+        Err(_) => return NOT_FOUND.to_vec(),
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 200 OK\r\n\
+        Content-Type: {}\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n",
+        content_type(&file),
+        body.len(),
+    )
+    .into_bytes();
+
+    response.extend_from_slice(&body);
+    response
+}
+
+async fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    if fs::metadata(root).await.ok()?.is_file() {
+        return Some(root.to_path_buf());
+    }
+
+    // Drop any query string, and refuse anything that could escape the root
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let relative = Path::new(path.trim_start_matches('/'));
+
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    let mut file = root.join(relative);
+    if fs::metadata(&file).await.ok()?.is_dir() {
+        file.push("index.html");
+    }
+
+    // A symlink inside the root can still point outside it
+    let file = fs::canonicalize(&file).await.ok()?;
+    let root = fs::canonicalize(root).await.ok()?;
+    file.starts_with(&root).then_some(file)
+}
+
+fn content_type(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default();
+
+    match extension {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(response: &[u8]) -> &[u8] {
+        let start = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        &response[start + 4..]
+    }
+
+    fn status(response: &[u8]) -> &str {
+        let line = response.split(|&b| b == b'\r').next().unwrap();
+        std::str::from_utf8(line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn files_are_served_from_inside_the_root() {
+        let dir = std::env::temp_dir().join(format!("wocket-static-{}", std::process::id()));
+        let root = dir.join("root");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(root.join("app")).unwrap();
+        std::fs::write(root.join("index.html"), "<h1>hi</h1>").unwrap();
+        std::fs::write(root.join("app/main.js"), "go()").unwrap();
+        std::fs::write(dir.join("secret.txt"), "hunter2").unwrap();
+
+        let index = response(&root, "/").await;
+        assert_eq!(status(&index), "HTTP/1.1 200 OK");
+        assert_eq!(body(&index), b"<h1>hi</h1>");
+        assert!(String::from_utf8_lossy(&index).contains("text/html"));
+
+        let script = response(&root, "/app/main.js?v=2").await;
+        assert_eq!(body(&script), b"go()");
+        assert!(String::from_utf8_lossy(&script).contains("text/javascript"));
+
+        // The directory has no index.html of its own
+        assert_eq!(
+            status(&response(&root, "/app/").await),
+            "HTTP/1.1 404 Not Found"
+        );
+        assert_eq!(
+            status(&response(&root, "/../secret.txt").await),
+            "HTTP/1.1 404 Not Found"
+        );
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("link.txt")).unwrap();
+            let escaped = response(&root, "/link.txt").await;
+            assert_eq!(escaped, NOT_FOUND);
+        }
+
+        // A file root is served for every path
+        let single = response(&root.join("index.html"), "/anything").await;
+        assert_eq!(body(&single), b"<h1>hi</h1>");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn content_types_follow_the_extension() {
+        assert_eq!(content_type(Path::new("a.css")), "text/css; charset=utf-8");
+        assert_eq!(content_type(Path::new("a.wasm")), "application/wasm");
+        assert_eq!(content_type(Path::new("a")), "application/octet-stream");
+    }
+}
@@ -0,0 +1,494 @@
+//! Blocking WebSockets over `std::net::TcpStream`, for scripts and tests
+//! that don't want to pull in an async runtime.
+//!
+//! ```no_run
+//! use wocket::Message;
+//!
+//! let mut socket = wocket::sync::connect("ws://127.0.0.1:8080/").unwrap();
+//! socket.send(Message::Binary(b"Hello".to_vec())).unwrap();
+//! println!("{:?}", socket.read().unwrap());
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, SystemTime};
+
+use base64::prelude::*;
+use tokio::time::Instant;
+
+use crate::codec::{MaskRng, SeededRng, State, WsConnection};
+use crate::handshake::{self, Handshake, MAX_HEADERS_LEN};
+use crate::rtt::RttEstimator;
+use crate::{Error, Message, Result};
+
+/// Longest `close` waits for the peer to reply to a close frame.
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An open WebSocket connection, from either end.
+pub struct WebSocket {
+    stream: TcpStream,
+    conn: WsConnection,
+
+    /// Bytes read from the stream that haven't been parsed yet.
+    buf: Vec<u8>,
+
+    /// The message being received.
+    message: Vec<u8>,
+
+    /// Messages that arrived while `ping_rtt` was waiting for its pong.
+    queued: VecDeque<Message>,
+
+    rtt: RttEstimator,
+
+    keep_alive: Option<KeepAlive>,
+
+    /// When the last bytes came from the peer.
+    last_heard: Instant,
+}
+
+/// Pings sent whenever the peer has been quiet, see `WebSocket::keep_alive`.
+#[derive(Debug, Clone, Copy)]
+struct KeepAlive {
+    interval: Duration,
+    timeout: Duration,
+
+    /// Whether the ping sent for this quiet spell is still unanswered.
+    pinged: bool,
+}
+
+/// Runs the server side of the handshake on a freshly accepted stream.
+/// Anything other than a valid upgrade request is answered with an error
+/// response, and returned as `Error::Handshake`.
+pub fn accept(mut stream: TcpStream) -> Result<WebSocket> {
+    let mut buf = vec![];
+
+    let response = loop {
+        read_more(&mut stream, &mut buf)?;
+
+        if let Some(handshake) = handshake::handshake_response(&buf, &[], &[]) {
+            break handshake;
+        }
+    };
+
+    match response {
+        Handshake::Upgrade { response, len, .. } => {
+            stream.write_all(response.as_bytes())?;
+
+            // The client may have sent frames straight after its request
+            buf.drain(..len);
+        }
+        Handshake::Page { .. } => {
+            stream.write_all(handshake::upgrade_required().as_bytes())?;
+            return Err(handshake_error("not a WebSocket upgrade request"));
+        }
+        Handshake::Reject(response, _) => {
+            stream.write_all(response.as_bytes())?;
+            return Err(handshake_error("invalid WebSocket upgrade request"));
+        }
+    }
+
+    Ok(WebSocket::new(stream, WsConnection::new(), buf))
+}
+
+/// Connects to a `ws://host[:port][/path]` URL. TLS isn't supported.
+pub fn connect(url: &str) -> Result<WebSocket> {
+    connect_with_rng(url, SeededRng::new(random_u64()))
+}
+
+/// Like `connect`, but with masking keys from `rng`, e.g. a `SeededRng`
+/// with a fixed seed to send the same bytes every run.
+pub fn connect_with_rng(url: &str, rng: impl MaskRng + Send + 'static) -> Result<WebSocket> {
+    let rest = url
+        .strip_prefix("ws://")
+        .ok_or_else(|| handshake_error("only ws:// URLs are supported"))?;
+
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+
+    let has_port = host
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+
+    let mut stream = if has_port {
+        TcpStream::connect(host)?
+    } else {
+        TcpStream::connect((host.trim_matches(['[', ']']), 80))?
+    };
+
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&random_u64().to_ne_bytes());
+    key[8..].copy_from_slice(&random_u64().to_ne_bytes());
+    let key = BASE64_STANDARD.encode(key);
+
+    stream.write_all(handshake::upgrade_request(host, path, &key).as_bytes())?;
+
+    let mut buf = vec![];
+
+    loop {
+        read_more(&mut stream, &mut buf)?;
+
+        match handshake::check_upgrade_response(&buf, &key) {
+            Ok(Some(len)) => {
+                // Keep any frames the server sent straight after its response
+                buf.drain(..len);
+                break;
+            }
+            Ok(None) if buf.len() <= MAX_HEADERS_LEN => {}
+            Ok(None) => return Err(handshake_error("response headers too long")),
+            Err(err) => return Err(handshake_error(err)),
+        }
+    }
+
+    Ok(WebSocket::new(
+        stream,
+        WsConnection::client_with_rng(rng),
+        buf,
+    ))
+}
+
+impl WebSocket {
+    fn new(stream: TcpStream, conn: WsConnection, buf: Vec<u8>) -> Self {
+        WebSocket {
+            stream,
+            conn,
+            buf,
+            message: vec![],
+            queued: VecDeque::new(),
+            rtt: RttEstimator::new(),
+            keep_alive: None,
+            last_heard: Instant::now(),
+        }
+    }
+
+    /// Pings the peer once nothing has come from it for `interval`, and
+    /// gives up on it once nothing has come for `timeout`, at which point
+    /// `read` fails with `Error::Disconnected`. This is the client's side
+    /// of the server's heartbeat, so a dead link is noticed from both ends.
+    ///
+    /// The pongs are swallowed, but update `rtt`. Reads wake up when a ping
+    /// is due, so this takes over the stream's read timeout.
+    pub fn keep_alive(&mut self, interval: Duration, timeout: Duration) {
+        self.keep_alive = Some(KeepAlive {
+            interval,
+            timeout,
+            pinged: false,
+        });
+    }
+
+    /// Blocks until the next message arrives. Pings are answered
+    /// automatically, but still returned.
+    ///
+    /// Protocol errors close the connection and are returned as
+    /// `Error::Protocol`. Once the close handshake is over, this returns
+    /// `Error::Closed`.
+    pub fn read(&mut self) -> Result<Message> {
+        match self.queued.pop_front() {
+            Some(message) => Ok(message),
+            None => self.receive(),
+        }
+    }
+
+    /// Sends a ping and blocks until its pong comes back. Returns the time
+    /// in between, and updates the smoothed `rtt`. Messages that arrive in
+    /// the meantime are kept for `read`.
+    pub fn ping_rtt(&mut self) -> Result<Duration> {
+        // This replaces any keep-alive ping as the one being timed
+        if let Some(keep_alive) = &mut self.keep_alive {
+            keep_alive.pinged = false;
+        }
+        let payload = self.rtt.ping(Instant::now());
+        self.send(Message::Ping(payload.to_vec()))?;
+
+        loop {
+            let message = self.receive()?;
+
+            if let Message::Pong(payload) = &message {
+                if let Some(rtt) = self.rtt.pong(payload, Instant::now()) {
+                    return Ok(rtt);
+                }
+            }
+
+            let closed = matches!(message, Message::Close(_));
+            self.queued.push_back(message);
+            if closed {
+                return Err(Error::Closed);
+            }
+        }
+    }
+
+    /// The smoothed round-trip time from `ping_rtt`, once it has been
+    /// called.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.smoothed()
+    }
+
+    fn receive(&mut self) -> Result<Message> {
+        loop {
+            if self.conn.is_closed() {
+                return Err(Error::Closed);
+            }
+
+            let received = self.conn.receive(&self.buf, &mut self.message);
+
+            // Pongs, close replies and protocol error closes
+            self.flush()?;
+
+            let received = received.map_err(Error::Protocol)?;
+
+            if received.consumed == 0 {
+                self.wait_for_more()?;
+                continue;
+            }
+            self.buf.drain(..received.consumed);
+
+            // No event means a fragment of a bigger message
+            let Some(event) = received.event else {
+                continue;
+            };
+            let message = Message::from_event(event, &mut self.message);
+
+            if let (Some(keep_alive), Message::Pong(payload)) = (&mut self.keep_alive, &message) {
+                if keep_alive.pinged && self.rtt.pong(payload, Instant::now()).is_some() {
+                    keep_alive.pinged = false;
+                    continue;
+                }
+            }
+            return Ok(message);
+        }
+    }
+
+    /// Reads more from the stream. With keep-alive on, the peer is pinged
+    /// if it goes quiet for the interval while waiting.
+    fn wait_for_more(&mut self) -> Result<()> {
+        while let Some(keep_alive) = self.keep_alive {
+            let silent = self.last_heard.elapsed();
+            if silent >= keep_alive.interval && !keep_alive.pinged {
+                let payload = self.rtt.ping(Instant::now());
+                self.send(Message::Ping(payload.to_vec()))?;
+                self.keep_alive = Some(KeepAlive {
+                    pinged: true,
+                    ..keep_alive
+                });
+            }
+
+            // Wake up when the ping is due, or else when it's time to give up
+            let wake_at = if silent < keep_alive.interval {
+                keep_alive.interval.min(keep_alive.timeout)
+            } else {
+                keep_alive.timeout
+            };
+            // Bytes may have arrived while nobody was reading, so this always
+            // reads before giving up
+            let wait = wake_at.saturating_sub(silent).max(Duration::from_millis(1));
+            self.stream.set_read_timeout(Some(wait))?;
+
+            match read_more(&mut self.stream, &mut self.buf) {
+                Ok(()) => break,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(err) => return Err(err.into()),
+            }
+
+            let silent = self.last_heard.elapsed();
+            if silent >= keep_alive.timeout {
+                let _ = self.stream.shutdown(Shutdown::Both);
+                return Err(Error::Disconnected(silent));
+            }
+        }
+
+        if self.keep_alive.is_none() {
+            read_more(&mut self.stream, &mut self.buf)?;
+        }
+        self.last_heard = Instant::now();
+        Ok(())
+    }
+
+    /// Sends a message, blocking until it has been written. Fails with
+    /// `Error::Closed` once a close frame has been sent.
+    pub fn send(&mut self, message: Message) -> Result<()> {
+        message.queue(&mut self.conn)?;
+        self.flush()
+    }
+
+    /// Sends a close frame with `code` and `reason`, then waits up to
+    /// `CLOSE_TIMEOUT` for the peer to reply with its own. Messages that
+    /// arrive in the meantime are dropped. Afterwards, sending fails with
+    /// `Error::Closed`.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<()> {
+        if self.conn.is_closed() {
+            return Ok(());
+        }
+
+        self.conn.close(code, reason);
+        self.flush()?;
+
+        let timeout = self.stream.read_timeout()?;
+        self.stream.set_read_timeout(Some(CLOSE_TIMEOUT))?;
+
+        let result = loop {
+            match self.read() {
+                Ok(Message::Close(_)) => break Ok(()),
+                Ok(_) => continue,
+                Err(err) => break Err(err),
+            }
+        };
+
+        self.stream.set_read_timeout(timeout)?;
+        result
+    }
+
+    /// Where the connection is in its lifetime. It is only ever created
+    /// once the handshake is over, so this is never `State::Connecting`.
+    pub fn state(&self) -> State {
+        self.conn.state()
+    }
+
+    /// The underlying stream, e.g. to set timeouts on it.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.conn.output().is_empty() {
+            let mut out = vec![];
+            self.conn.take_output(&mut out);
+            self.stream.write_all(&out)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads whatever is available onto the end of `buf`, treating the end of
+/// the stream as an error.
+fn read_more(stream: &mut TcpStream, buf: &mut Vec<u8>) -> io::Result<()> {
+    let mut chunk = [0; 4096];
+    let n = stream.read(&mut chunk)?;
+
+    if n == 0 {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "connection closed by peer",
+        ));
+    }
+
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(())
+}
+
+fn handshake_error(err: impl Into<String>) -> Error {
+    Error::Handshake(err.into())
+}
+
+/// Random enough for handshake keys and masking keys, which only have to be
+/// unpredictable to scripts, not to attackers with the process's memory.
+/// `RandomState` is seeded from the OS, so this avoids a dependency.
+fn random_u64() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(nanos);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    use crate::codec::CloseFrame;
+
+    #[test]
+    fn client_and_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = accept(stream).unwrap();
+
+            // Echo until the client closes
+            loop {
+                match socket.read().unwrap() {
+                    Message::Close(frame) => return frame,
+                    Message::Ping(_) => {}
+                    message => socket.send(message).unwrap(),
+                }
+            }
+        });
+
+        let mut socket = connect(&format!("ws://{addr}/echo")).unwrap();
+
+        socket.send(Message::Text(String::from("Hello"))).unwrap();
+        assert_eq!(socket.read().unwrap(), Message::Text(String::from("Hello")));
+
+        socket.send(Message::Ping(b"ping".to_vec())).unwrap();
+        assert_eq!(socket.read().unwrap(), Message::Pong(b"ping".to_vec()));
+
+        // A message that comes back before the pong is kept for read
+        socket.send(Message::Binary(b"early".to_vec())).unwrap();
+        socket.ping_rtt().unwrap();
+        assert!(socket.rtt().is_some());
+        assert_eq!(socket.read().unwrap(), Message::Binary(b"early".to_vec()));
+
+        let big = vec![7; 100_000];
+        socket.send(Message::Binary(big.clone())).unwrap();
+        assert_eq!(socket.read().unwrap(), Message::Binary(big));
+
+        // This waits for the server's reply
+        socket.close(1000, "bye").unwrap();
+        assert_eq!(socket.state(), State::Closed);
+        assert!(matches!(
+            socket.send(Message::Close(None)),
+            Err(Error::Closed)
+        ));
+        assert!(matches!(socket.read(), Err(Error::Closed)));
+
+        assert_eq!(
+            server.join().unwrap(),
+            Some(CloseFrame {
+                code: 1000,
+                reason: String::from("bye"),
+            })
+        );
+    }
+
+    #[test]
+    fn keep_alive_notices_a_silent_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = accept(stream).unwrap();
+
+            // Answer pings for a while, then stop reading altogether
+            let until = Instant::now() + Duration::from_millis(300);
+            while Instant::now() < until {
+                assert!(matches!(socket.read().unwrap(), Message::Ping(_)));
+            }
+            socket.send(Message::Text(String::from("late"))).unwrap();
+            thread::sleep(Duration::from_secs(1));
+        });
+
+        let mut socket = connect(&format!("ws://{addr}/")).unwrap();
+        socket.keep_alive(Duration::from_millis(50), Duration::from_millis(200));
+
+        // Only the server's message comes out, but the pongs before it count
+        assert_eq!(socket.read().unwrap(), Message::Text(String::from("late")));
+        assert!(socket.rtt().is_some());
+
+        assert!(matches!(socket.read(), Err(Error::Disconnected(silent))
+            if silent >= Duration::from_millis(200)));
+        server.join().unwrap();
+    }
+}
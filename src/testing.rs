@@ -0,0 +1,180 @@
+//! Helpers for testing a `Server` without binding real sockets.
+//!
+//! `connect_pair` runs a connection over an in-memory pipe and hands back
+//! the client end, so interceptors, inspectors and upgrade hooks can be
+//! exercised from an ordinary `#[tokio::test]`. Everything on the client
+//! side is deterministic, including the handshake key and masking keys.
+
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use crate::codec::{State, WsConnection};
+use crate::handshake;
+use crate::server::{self, Server};
+use crate::{Error, Message, Result};
+
+/// How many bytes each direction of the pipe buffers before writes wait
+/// for the other end to read.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// The key sent with every test handshake; it's the one from RFC 6455.
+const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+/// The address connections from `connect_pair` appear to come from.
+pub const PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1);
+
+/// Two ends of an in-memory byte stream. Whatever is written to one can be
+/// read from the other.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    tokio::io::duplex(PIPE_CAPACITY)
+}
+
+/// The client end of a connection to a test server.
+pub struct Client {
+    stream: DuplexStream,
+    conn: WsConnection,
+    buf: Vec<u8>,
+    message: Vec<u8>,
+}
+
+/// Starts a connection to `server` at `path` over an in-memory pipe and
+/// does the opening handshake. Fails if the server refuses it.
+pub async fn connect_pair(server: Arc<Server>, path: &str) -> Result<Client> {
+    let (mut stream, server_end) = duplex();
+    tokio::spawn(server::handle_client(server_end, PEER, Vec::new(), server));
+
+    stream
+        .write_all(handshake::upgrade_request("localhost", path, KEY).as_bytes())
+        .await?;
+
+    let mut client = Client {
+        stream,
+        conn: WsConnection::client(1),
+        buf: vec![],
+        message: vec![],
+    };
+
+    loop {
+        client.read_more().await?;
+
+        match handshake::check_upgrade_response(&client.buf, KEY) {
+            Ok(Some(len)) => {
+                client.buf.drain(..len);
+                return Ok(client);
+            }
+            Ok(None) => {}
+            Err(err) => return Err(Error::Handshake(err)),
+        }
+    }
+}
+
+impl Client {
+    /// Sends a message to the server. Fails with `Error::Closed` once a
+    /// close frame has been sent.
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        message.queue(&mut self.conn)?;
+        self.flush().await
+    }
+
+    /// Waits for the next message from the server. Returns an
+    /// `UnexpectedEof` error if the server drops the connection first.
+    pub async fn read(&mut self) -> Result<Message> {
+        loop {
+            if self.conn.is_closed() {
+                return Err(Error::Closed);
+            }
+
+            let received = self.conn.receive(&self.buf, &mut self.message);
+            self.flush().await?;
+
+            let received = received.map_err(Error::Protocol)?;
+
+            if received.consumed == 0 {
+                self.read_more().await?;
+                continue;
+            }
+            self.buf.drain(..received.consumed);
+
+            if let Some(event) = received.event {
+                return Ok(Message::from_event(event, &mut self.message));
+            }
+        }
+    }
+
+    /// Where the connection is in its lifetime. It is only ever created
+    /// once the handshake is over, so this is never `State::Connecting`.
+    pub fn state(&self) -> State {
+        self.conn.state()
+    }
+
+    async fn read_more(&mut self) -> io::Result<()> {
+        if self.stream.read_buf(&mut self.buf).await? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "server closed the pipe",
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if !self.conn.output().is_empty() {
+            let mut out = vec![];
+            self.conn.take_output(&mut out);
+            self.stream.write_all(&out).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::codec::CloseFrame;
+    use crate::config::Config;
+    use crate::upgrade::AllowedPaths;
+
+    #[tokio::test]
+    async fn echo_over_a_pipe() {
+        let server = Arc::new(Server::from_config(Config::default()).unwrap());
+        let mut client = connect_pair(server, "/").await.unwrap();
+
+        client
+            .send(Message::Binary(b"Hello".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.read().await.unwrap(),
+            Message::Binary(b"Hello".to_vec())
+        );
+
+        let frame = CloseFrame {
+            code: 1001,
+            reason: String::from("going away"),
+        };
+        client.send(Message::Close(Some(frame))).await.unwrap();
+
+        // The server echoes the status code back
+        match client.read().await.unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, 1001),
+            other => panic!("expected a close, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn refused_upgrade() {
+        let mut server = Server::from_config(Config::default()).unwrap();
+        server
+            .upgrade_hooks
+            .push(Box::new(AllowedPaths(vec![String::from("/chat")])));
+
+        let result = connect_pair(Arc::new(server), "/other").await;
+        assert!(matches!(result, Err(Error::Handshake(_))));
+    }
+}
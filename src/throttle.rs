@@ -0,0 +1,77 @@
+//! Outbound bandwidth caps, so one client pulling a lot of data can't
+//! saturate the uplink for everyone else.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// A token bucket: up to `burst` bytes can be sent at once, refilled at
+/// `rate` bytes per second.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    rate: f64,
+    burst: f64,
+
+    /// Bytes that can be sent right now. Negative while the connection has
+    /// sent more than it was allowed and has to wait for it to be paid off.
+    tokens: f64,
+
+    last_refill: Instant,
+}
+
+impl Throttle {
+    /// A bucket that starts full. Neither `rate` nor `burst` may be zero.
+    pub fn new(rate: usize, burst: usize) -> Self {
+        Throttle {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Most bytes that can be sent at once.
+    pub fn burst(&self) -> usize {
+        self.burst as usize
+    }
+
+    /// Takes `bytes` from the bucket, and returns how long to wait before
+    /// sending them.
+    pub fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_then_waits() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(1000, 500);
+
+        assert_eq!(throttle.take(500, start), Duration::ZERO);
+        assert_eq!(throttle.take(250, start), Duration::from_millis(250));
+
+        // Once the wait is over, the bucket is empty again
+        let later = start + Duration::from_millis(250);
+        assert_eq!(throttle.take(0, later), Duration::ZERO);
+
+        // and it never holds more than the burst
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(throttle.take(600, much_later), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,128 @@
+//! W3C Trace Context from the opening handshake, so traces that start in
+//! the browser can be joined up with whatever the connection leads to.
+
+use crate::headers::Headers;
+use crate::upgrade::{Decision, Request, UpgradeHook};
+
+/// The `traceparent`, `tracestate` and `baggage` headers of an upgrade
+/// request, once `traceparent` has been checked to be well formed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub traceparent: String,
+    pub tracestate: Option<String>,
+    pub baggage: Option<String>,
+}
+
+impl TraceContext {
+    /// Reads the trace context from request headers. Returns `None` if there
+    /// is no `traceparent`, or it isn't valid, in which case the other
+    /// headers mean nothing either.
+    pub fn from_headers(headers: Headers) -> Option<Self> {
+        let traceparent = std::str::from_utf8(headers.get("traceparent")?).ok()?;
+        if !valid_traceparent(traceparent) {
+            return None;
+        }
+
+        let text = |name| {
+            let value = std::str::from_utf8(headers.get(name)?).ok()?;
+            Some(String::from(value))
+        };
+
+        Some(TraceContext {
+            traceparent: String::from(traceparent),
+            tracestate: text("tracestate"),
+            baggage: text("baggage"),
+        })
+    }
+
+    /// The 32 hex digit ID of the whole trace.
+    pub fn trace_id(&self) -> &str {
+        &self.traceparent[3..35]
+    }
+
+    /// The 16 hex digit ID of the client's span that made the request.
+    pub fn parent_id(&self) -> &str {
+        &self.traceparent[36..52]
+    }
+}
+
+/// Checks `version-traceid-parentid-flags`, all lowercase hex. Later
+/// versions may add fields after these, so only version 00 has to end there.
+fn valid_traceparent(value: &str) -> bool {
+    let hex = |field: &str, len| {
+        field.len() == len
+            && field
+                .bytes()
+                .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+    };
+    let zeros = |field: &str| field.bytes().all(|byte| byte == b'0');
+
+    let fields: Vec<&str> = value.splitn(5, '-').collect();
+    let [version, trace_id, parent_id, flags, rest @ ..] = fields.as_slice() else {
+        return false;
+    };
+
+    hex(version, 2)
+        && *version != "ff"
+        && (rest.is_empty() || *version != "00")
+        && hex(trace_id, 32)
+        && !zeros(trace_id)
+        && hex(parent_id, 16)
+        && !zeros(parent_id)
+        && hex(flags, 2)
+}
+
+/// Echoes the client's `traceparent` on the 101 response, for clients that
+/// want to see their trace was picked up.
+pub struct EchoTraceContext;
+
+impl UpgradeHook for EchoTraceContext {
+    fn decide(&self, request: &Request) -> Decision {
+        let headers = match TraceContext::from_headers(request.headers) {
+            Some(trace) => vec![(String::from("traceparent"), trace.traceparent)],
+            None => vec![],
+        };
+
+        Decision::Accept {
+            subprotocol: None,
+            headers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparent_is_checked() {
+        let parsed = [
+            httparse::Header {
+                name: "TraceParent",
+                value: b"00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            },
+            httparse::Header {
+                name: "baggage",
+                value: b"userId=alice",
+            },
+        ];
+        let trace = TraceContext::from_headers(Headers::new(&parsed)).unwrap();
+        assert_eq!(trace.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(trace.parent_id(), "00f067aa0ba902b7");
+        assert_eq!(trace.baggage.as_deref(), Some("userId=alice"));
+        assert_eq!(trace.tracestate, None);
+
+        assert!(valid_traceparent(
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-more"
+        ));
+        for invalid in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-more",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert!(!valid_traceparent(invalid), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::future::{self, Future};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::TcpStream;
+
+/// A byte stream a connection can run over. Connections are generic over
+/// this rather than tied to `TcpStream`, so they can run over other streams
+/// like TLS or in-memory pipes.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {
+    /// Waits until the stream may have data to read, without reading any.
+    /// Connections wait on this before taking a read buffer from the pool.
+    /// Streams that can't tell should return straight away.
+    fn readable(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Makes dropping the stream abort it, e.g. with a TCP RST rather than
+    /// a FIN. Streams that can't do that just close normally.
+    fn reset(&mut self) {}
+}
+
+impl Transport for TcpStream {
+    fn readable(&mut self) -> impl Future<Output = io::Result<()>> + Send {
+        TcpStream::readable(self)
+    }
+
+    fn reset(&mut self) {
+        let _ = self.set_linger(Some(Duration::ZERO));
+    }
+}
+
+/// In-memory pipes, used by `testing`.
+impl Transport for DuplexStream {
+    fn readable(&mut self) -> impl Future<Output = io::Result<()>> + Send {
+        // A read into an empty buffer waits for data without taking any
+        future::poll_fn(move |cx| Pin::new(&mut *self).poll_read(cx, &mut ReadBuf::new(&mut [])))
+    }
+}
+
+/// A transport that counts the bytes going through it.
+#[derive(Debug)]
+pub struct Counted<S> {
+    inner: S,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S) -> Self {
+        Counted {
+            inner,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.bytes_read += (buf.filled().len() - before) as u64;
+        polled
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let polled = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = polled {
+            self.bytes_written += n as u64;
+        }
+        polled
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: Transport> Transport for Counted<S> {
+    fn readable(&mut self) -> impl Future<Output = io::Result<()>> + Send {
+        self.inner.readable()
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset()
+    }
+}
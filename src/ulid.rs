@@ -0,0 +1,110 @@
+//! ULIDs: 128-bit IDs that sort by the time they were made, written as 26
+//! characters of Crockford's base32, e.g. `01HRZ3NDEKTSV4RRFFQ69G5FAV`.
+//!
+//! Every connection gets one when it is accepted. Unlike its
+//! `ConnectionId`, which is only unique within one server's lifetime, it
+//! can be quoted back from a client report and found in any server's logs.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// The last ULID made, so the next one made in the same millisecond sorts
+/// after it.
+static LAST: Mutex<u128> = Mutex::new(0);
+
+/// A millisecond timestamp in the top 48 bits, and 80 random ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ulid(u128);
+
+impl Ulid {
+    pub fn new() -> Self {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let ulid = (u128::from(millis) << 80) | random() >> 48;
+
+        let mut last = LAST.lock().unwrap();
+        *last = ulid.max(*last + 1);
+        Ulid(*last)
+    }
+
+    /// When it was made, in milliseconds since the Unix epoch.
+    pub fn timestamp_ms(self) -> u64 {
+        (self.0 >> 80) as u64
+    }
+}
+
+impl Default for Ulid {
+    fn default() -> Self {
+        Ulid::new()
+    }
+}
+
+/// 128 random bits. Each `RandomState` is keyed differently, which is
+/// random enough for IDs, if not for secrets.
+fn random() -> u128 {
+    let half = || RandomState::new().build_hasher().finish();
+    (u128::from(half()) << 64) | u128::from(half())
+}
+
+impl fmt::Display for Ulid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The first character only has the top 3 bits
+        let mut text = [0; 26];
+        for (i, c) in text.iter_mut().enumerate() {
+            *c = ALPHABET[(self.0 >> (125 - 5 * i)) as usize & 31];
+        }
+        f.write_str(std::str::from_utf8(&text).unwrap())
+    }
+}
+
+impl FromStr for Ulid {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid ULID: {text}");
+        if text.len() != 26 || text.as_bytes()[0] > b'7' {
+            return Err(invalid());
+        }
+
+        let mut ulid = 0;
+        for c in text.bytes() {
+            let digit = ALPHABET
+                .iter()
+                .position(|&a| a == c.to_ascii_uppercase())
+                .ok_or_else(invalid)?;
+            ulid = ulid << 5 | digit as u128;
+        }
+        Ok(Ulid(ulid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ulids_sort_by_when_they_were_made() {
+        let first = Ulid::new();
+        let second = Ulid::new();
+        assert!(first < second);
+        assert!(second.to_string() > first.to_string());
+        assert!(second.timestamp_ms() >= first.timestamp_ms());
+
+        let text = first.to_string();
+        assert_eq!(text.len(), 26);
+        assert_eq!(text.parse::<Ulid>().unwrap(), first);
+        assert_eq!(text.to_lowercase().parse::<Ulid>().unwrap(), first);
+
+        let known = "01ARZ3NDEKTSV4RRFFQ69G5FAV".parse::<Ulid>().unwrap();
+        assert_eq!(known.timestamp_ms(), 1469922850259);
+        assert!("81ARZ3NDEKTSV4RRFFQ69G5FAV".parse::<Ulid>().is_err());
+        assert!("01ARZ3NDEKTSV4RRFFQ69G5FAU!".parse::<Ulid>().is_err());
+    }
+}
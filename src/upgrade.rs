@@ -0,0 +1,504 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::headers::Headers;
+use crate::memory::MemoryUsage;
+use crate::pressure::{Degradation, MemoryPressure};
+
+/// The parts of an upgrade request that hooks get to look at. Decisions
+/// based on the peer's address belong in an `AcceptPolicy`, which runs
+/// before the request is even read.
+pub struct Request<'a> {
+    pub path: &'a str,
+    pub headers: Headers<'a>,
+    reservations: RefCell<Reservations>,
+}
+
+impl<'a> Request<'a> {
+    pub fn new(path: &'a str, headers: Headers<'a>) -> Self {
+        Request {
+            path,
+            headers,
+            reservations: RefCell::default(),
+        }
+    }
+
+    /// Holds on to `reservation` for as long as the connection is open, or
+    /// drops it if the upgrade is refused. Hooks that count connections
+    /// take their slot here in `decide`, so two upgrades decided at once
+    /// can't both get the last one.
+    pub fn reserve(&self, reservation: impl Any + Send) {
+        self.reservations.borrow_mut().0.push(Box::new(reservation));
+    }
+
+    /// What the hooks reserved, for the connection to hold.
+    pub fn into_reservations(self) -> Reservations {
+        self.reservations.into_inner()
+    }
+
+    /// The value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers.get(name)
+    }
+}
+
+/// What hooks set aside for an upgrade with `Request::reserve`, like its
+/// slot under a connection limit. Dropping it gives everything back.
+#[derive(Default)]
+pub struct Reservations(Vec<Box<dyn Any + Send>>);
+
+impl Reservations {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Reservations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reservations({})", self.0.len())
+    }
+}
+
+/// Reservations are only ever held, never looked into, so any two are
+/// alike.
+impl PartialEq for Reservations {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Reservations {}
+
+/// A hook's answer to an upgrade request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// Go ahead with the upgrade, optionally picking one of the client's
+    /// subprotocols and adding headers to the 101 response.
+    Accept {
+        subprotocol: Option<String>,
+        headers: Vec<(String, String)>,
+    },
+
+    /// Refuse the upgrade with this status code and body.
+    Reject { status: u16, body: String },
+
+    /// Refuse the upgrade for now with a 503, telling the client when to try
+    /// again.
+    Defer { retry_after: Duration },
+}
+
+impl Decision {
+    pub fn accept() -> Self {
+        Decision::Accept {
+            subprotocol: None,
+            headers: vec![],
+        }
+    }
+}
+
+/// Runs before a WebSocket upgrade is accepted, once the request has been
+/// checked to be a valid handshake.
+pub trait UpgradeHook: Send + Sync {
+    fn decide(&self, request: &Request) -> Decision;
+}
+
+/// Asks each hook in turn. The first one that doesn't accept decides the
+/// outcome; otherwise the accepts are merged, with the first subprotocol
+/// picked winning.
+pub fn decide_all(hooks: &[Box<dyn UpgradeHook>], request: &Request) -> Decision {
+    let mut subprotocol = None;
+    let mut headers = vec![];
+
+    for hook in hooks {
+        match hook.decide(request) {
+            Decision::Accept {
+                subprotocol: picked,
+                headers: more_headers,
+            } => {
+                subprotocol = subprotocol.or(picked);
+                headers.extend(more_headers);
+            }
+            other => return other,
+        }
+    }
+
+    Decision::Accept {
+        subprotocol,
+        headers,
+    }
+}
+
+/// The part of a request path that picks an endpoint, without the query
+/// string.
+pub fn route(path: &str) -> &str {
+    path.split('?').next().unwrap_or_default()
+}
+
+/// Rejects upgrades to paths that aren't in the list with a 404. The query
+/// string is ignored.
+pub struct AllowedPaths(pub Vec<String>);
+
+impl UpgradeHook for AllowedPaths {
+    fn decide(&self, request: &Request) -> Decision {
+        let path = route(request.path);
+
+        if self.0.iter().any(|allowed| allowed == path) {
+            Decision::accept()
+        } else {
+            Decision::Reject {
+                status: 404,
+                body: String::from("no WebSocket endpoint at this path"),
+            }
+        }
+    }
+}
+
+/// Rejects upgrades whose `Origin` isn't in the list, so browsers on other
+/// sites can't open connections.
+pub struct AllowedOrigins(pub Vec<String>);
+
+impl UpgradeHook for AllowedOrigins {
+    fn decide(&self, request: &Request) -> Decision {
+        let allowed = request.header("Origin").is_some_and(|origin| {
+            self.0
+                .iter()
+                .any(|allowed| allowed.as_bytes().eq_ignore_ascii_case(origin))
+        });
+
+        if allowed {
+            Decision::accept()
+        } else {
+            Decision::Reject {
+                status: 403,
+                body: String::from("origin not allowed"),
+            }
+        }
+    }
+}
+
+/// Picks the first subprotocol offered by the client that is in the list.
+pub struct Subprotocols(pub Vec<String>);
+
+impl UpgradeHook for Subprotocols {
+    fn decide(&self, request: &Request) -> Decision {
+        // Clients may offer them in one header or spread over several
+        let subprotocol = request
+            .headers
+            .tokens("Sec-WebSocket-Protocol")
+            .find(|offered| self.0.iter().any(|supported| supported == offered))
+            .map(String::from);
+
+        Decision::Accept {
+            subprotocol,
+            headers: vec![],
+        }
+    }
+}
+
+/// Defers upgrades while `max` WebSocket connections are already open, and
+/// counts each one it lets through in `active` until it closes.
+pub struct ConnectionLimit {
+    pub max: usize,
+    pub active: Arc<AtomicUsize>,
+    pub retry_after: Duration,
+}
+
+impl UpgradeHook for ConnectionLimit {
+    fn decide(&self, request: &Request) -> Decision {
+        match ConnectionSlot::take(&self.active, self.max) {
+            Some(slot) => {
+                request.reserve(slot);
+                Decision::accept()
+            }
+            None => Decision::Defer {
+                retry_after: self.retry_after,
+            },
+        }
+    }
+}
+
+/// One of the connections counted in an `active` count, until dropped.
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// Takes a slot, unless `max` are already taken.
+    pub fn take(active: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        active
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |taken| {
+                (taken < max).then_some(taken + 1)
+            })
+            .ok()?;
+        Some(ConnectionSlot(Arc::clone(active)))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Open WebSocket connections on each path.
+#[derive(Debug, Default)]
+pub struct RouteCounts(Mutex<HashMap<String, usize>>);
+
+impl RouteCounts {
+    pub fn get(&self, path: &str) -> usize {
+        let counts = self.0.lock().unwrap();
+        counts.get(route(path)).copied().unwrap_or(0)
+    }
+
+    /// Counts a connection on `path` as open until the guard is dropped.
+    pub fn open(self: &Arc<Self>, path: &str) -> RouteGuard {
+        self.try_open(path, usize::MAX).unwrap()
+    }
+
+    /// Like `open`, unless `path` already has `max` connections open. The
+    /// check and the count happen under one lock, so concurrent upgrades
+    /// can't overshoot.
+    pub fn try_open(self: &Arc<Self>, path: &str, max: usize) -> Option<RouteGuard> {
+        let path = String::from(route(path));
+        let mut counts = self.0.lock().unwrap();
+        let count = counts.entry(path.clone()).or_insert(0);
+        if *count >= max {
+            if *count == 0 {
+                counts.remove(&path);
+            }
+            return None;
+        }
+        *count += 1;
+
+        Some(RouteGuard {
+            counts: Arc::clone(self),
+            path,
+        })
+    }
+}
+
+pub struct RouteGuard {
+    counts: Arc<RouteCounts>,
+    path: String,
+}
+
+impl Drop for RouteGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.0.lock().unwrap();
+
+        if let Some(count) = counts.get_mut(&self.path) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.path);
+            }
+        }
+    }
+}
+
+/// Defers upgrades to a path while it already has as many connections open
+/// as its limit, so one busy endpoint can't use up the server. Paths
+/// without a limit are never deferred, but are still counted in `counts`.
+pub struct RouteLimits {
+    pub limits: Vec<(String, usize)>,
+    pub counts: Arc<RouteCounts>,
+    pub retry_after: Duration,
+}
+
+impl UpgradeHook for RouteLimits {
+    fn decide(&self, request: &Request) -> Decision {
+        let path = route(request.path);
+        let max = self
+            .limits
+            .iter()
+            .find(|(limited, _)| limited == path)
+            .map_or(usize::MAX, |&(_, max)| max);
+
+        match self.counts.try_open(path, max) {
+            Some(guard) => {
+                request.reserve(guard);
+                Decision::accept()
+            }
+            None => Decision::Defer {
+                retry_after: self.retry_after,
+            },
+        }
+    }
+}
+
+/// Defers every upgrade while the server is draining.
+pub struct Draining {
+    pub draining: watch::Receiver<bool>,
+    pub retry_after: Duration,
+}
+
+impl UpgradeHook for Draining {
+    fn decide(&self, _request: &Request) -> Decision {
+        if *self.draining.borrow() {
+            Decision::Defer {
+                retry_after: self.retry_after,
+            }
+        } else {
+            Decision::accept()
+        }
+    }
+}
+
+/// Defers every upgrade while the server is the standby of a failover
+/// pair.
+pub struct Standby {
+    pub standby: watch::Receiver<bool>,
+    pub retry_after: Duration,
+}
+
+impl UpgradeHook for Standby {
+    fn decide(&self, _request: &Request) -> Decision {
+        if *self.standby.borrow() {
+            Decision::Defer {
+                retry_after: self.retry_after,
+            }
+        } else {
+            Decision::accept()
+        }
+    }
+}
+
+/// Defers upgrades while the server is close to its memory cap.
+pub struct MemoryLimit {
+    pub usage: Arc<MemoryUsage>,
+    pub retry_after: Duration,
+}
+
+impl UpgradeHook for MemoryLimit {
+    fn decide(&self, _request: &Request) -> Decision {
+        if self.usage.nearly_full() {
+            Decision::Defer {
+                retry_after: self.retry_after,
+            }
+        } else {
+            Decision::accept()
+        }
+    }
+}
+
+/// Defers upgrades while the process is close to its soft memory limit.
+pub struct MemoryPressureLimit {
+    pub pressure: Arc<MemoryPressure>,
+    pub retry_after: Duration,
+}
+
+impl UpgradeHook for MemoryPressureLimit {
+    fn decide(&self, _request: &Request) -> Decision {
+        if self.pressure.level() >= Degradation::StopAccepting {
+            Decision::Defer {
+                retry_after: self.retry_after,
+            }
+        } else {
+            Decision::accept()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_refusal_wins() {
+        let headers = [
+            httparse::Header {
+                name: "origin",
+                value: b"https://example.com",
+            },
+            httparse::Header {
+                name: "Sec-WebSocket-Protocol",
+                value: b"chat, superchat",
+            },
+        ];
+        let request = Request::new("/", Headers::new(&headers));
+
+        let hooks: Vec<Box<dyn UpgradeHook>> = vec![
+            Box::new(AllowedPaths(vec![String::from("/")])),
+            Box::new(Subprotocols(vec![String::from("superchat")])),
+            Box::new(AllowedOrigins(vec![String::from("https://example.com")])),
+        ];
+        assert_eq!(
+            decide_all(&hooks, &request),
+            Decision::Accept {
+                subprotocol: Some(String::from("superchat")),
+                headers: vec![],
+            }
+        );
+
+        let hooks: Vec<Box<dyn UpgradeHook>> = vec![
+            Box::new(AllowedOrigins(vec![String::from("https://other.com")])),
+            Box::new(ConnectionLimit {
+                max: 0,
+                active: Arc::new(AtomicUsize::new(0)),
+                retry_after: Duration::from_secs(1),
+            }),
+        ];
+        assert_eq!(
+            decide_all(&hooks, &request),
+            Decision::Reject {
+                status: 403,
+                body: String::from("origin not allowed"),
+            }
+        );
+    }
+
+    #[test]
+    fn route_limits() {
+        let counts = Arc::new(RouteCounts::default());
+        let hook = RouteLimits {
+            limits: vec![(String::from("/busy"), 1)],
+            counts: Arc::clone(&counts),
+            retry_after: Duration::from_secs(1),
+        };
+
+        let request = |path| Request::new(path, Headers::default());
+
+        // The first upgrade holds its slot from the moment it is decided
+        let first = request("/busy?page=2");
+        assert_eq!(hook.decide(&first), Decision::accept());
+        assert!(matches!(
+            hook.decide(&request("/busy")),
+            Decision::Defer { .. }
+        ));
+        assert_eq!(hook.decide(&request("/quiet")), Decision::accept());
+        assert_eq!(counts.get("/quiet"), 0);
+
+        let reservations = first.into_reservations();
+        assert_eq!(counts.get("/busy"), 1);
+        drop(reservations);
+        assert_eq!(counts.get("/busy"), 0);
+        assert_eq!(hook.decide(&request("/busy")), Decision::accept());
+    }
+
+    #[test]
+    fn connection_limit_counts_upgrades_as_they_are_decided() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let hook = ConnectionLimit {
+            max: 1,
+            active: Arc::clone(&active),
+            retry_after: Duration::from_secs(1),
+        };
+
+        let first = Request::new("/", Headers::default());
+        let second = Request::new("/", Headers::default());
+        assert_eq!(hook.decide(&first), Decision::accept());
+        assert!(matches!(hook.decide(&second), Decision::Defer { .. }));
+        assert_eq!(active.load(Ordering::Relaxed), 1);
+
+        drop(first);
+        assert_eq!(active.load(Ordering::Relaxed), 0);
+    }
+}
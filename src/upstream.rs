@@ -0,0 +1,183 @@
+//! A pool of blocking client connections to one upstream server, for
+//! backends that fan RPCs out over WebSockets.
+//!
+//! Requests are spread round-robin over the connections, skipping ones
+//! busy with another request where possible. A connection that fails is
+//! dropped, and a new one is opened in its place the next time its slot is
+//! used, so one bad connection costs one failed request rather than every
+//! request routed to it. `check_health` pings idle connections to find
+//! dead ones before a request does.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::sync::{self, WebSocket};
+use crate::{Error, Message, Result};
+
+/// Connections to one `ws://` URL.
+pub struct WsPool {
+    url: String,
+    slots: Vec<Mutex<Option<WebSocket>>>,
+    next: AtomicUsize,
+    timeout: Option<Duration>,
+}
+
+impl WsPool {
+    /// A pool of `size` connections to `url`. Nothing is connected until
+    /// the connections are first used, or `check_health` is called.
+    pub fn new(url: &str, size: usize) -> Self {
+        WsPool {
+            url: String::from(url),
+            slots: (0..size.max(1)).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+            timeout: None,
+        }
+    }
+
+    /// Fails requests and health checks that get no answer within
+    /// `timeout`, and replaces their connections.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sends `message` on one of the connections, and waits for the next
+    /// data message to come back on it. Pings and pongs in between are
+    /// skipped.
+    ///
+    /// If the connection fails, it is dropped and the error returned. The
+    /// request may or may not have reached the server, so retrying is up to
+    /// the caller.
+    pub fn request(&self, message: Message) -> Result<Message> {
+        let mut slot = self.checkout();
+        let socket = self.connected(&mut slot)?;
+
+        let reply = socket.send(message).and_then(|()| loop {
+            match socket.read()? {
+                Message::Ping(_) | Message::Pong(_) => continue,
+                Message::Close(_) => break Err(Error::Closed),
+                reply => break Ok(reply),
+            }
+        });
+
+        if reply.is_err() {
+            *slot = None;
+        }
+        reply
+    }
+
+    /// Pings every connection that isn't busy, opening the ones that aren't
+    /// connected, and replaces any that don't answer. Returns how many
+    /// connections are healthy.
+    pub fn check_health(&self) -> usize {
+        let mut healthy = 0;
+
+        for slot in &self.slots {
+            let Ok(mut slot) = slot.try_lock() else {
+                // In the middle of a request, so it was fine just now
+                healthy += 1;
+                continue;
+            };
+
+            let alive = slot
+                .as_mut()
+                .is_some_and(|socket| socket.ping_rtt().is_ok());
+            if !alive {
+                *slot = None;
+            }
+
+            if self.connected(&mut slot).is_ok() {
+                healthy += 1;
+            }
+        }
+
+        healthy
+    }
+
+    /// Number of connections, open or not.
+    pub fn size(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Takes the next slot round-robin, or the first idle one after it if
+    /// it is busy. Waits for it if they all are.
+    fn checkout(&self) -> MutexGuard<'_, Option<WebSocket>> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = self.slots.len();
+
+        for i in 0..len {
+            if let Ok(slot) = self.slots[(start + i) % len].try_lock() {
+                return slot;
+            }
+        }
+
+        // A request that panicked can't have left the slot half changed
+        let slot = &self.slots[start % len];
+        slot.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The slot's connection, opened first if there isn't one.
+    fn connected<'a>(&self, slot: &'a mut Option<WebSocket>) -> Result<&'a mut WebSocket> {
+        if slot.is_none() {
+            let socket = sync::connect(&self.url)?;
+            socket.get_ref().set_read_timeout(self.timeout)?;
+            *slot = Some(socket);
+        }
+
+        Ok(slot.as_mut().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    use crate::config::Config;
+    use crate::server::{self, Server};
+
+    #[test]
+    fn kicked_connections_are_replaced() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        let server = Arc::new(Server::from_config(Config::default()).unwrap());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn({
+            let server = Arc::clone(&server);
+            async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                loop {
+                    let (socket, peer) = listener.accept().await.unwrap();
+                    tokio::spawn(server::handle_client(
+                        socket,
+                        peer,
+                        Vec::new(),
+                        Arc::clone(&server),
+                    ));
+                }
+            }
+        });
+
+        let pool = WsPool::new(&format!("ws://{addr}/"), 2).with_timeout(Duration::from_secs(5));
+        for _ in 0..4 {
+            let reply = pool.request(Message::Binary(b"rpc".to_vec())).unwrap();
+            assert_eq!(reply, Message::Binary(b"rpc".to_vec()));
+        }
+        assert_eq!(server.connections.list().len(), 2);
+
+        for (id, _) in server.connections.list() {
+            server.kick(id, 1012, "restarting");
+        }
+        // Give the closes time to arrive, or a ping could still be answered
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(pool.check_health(), 2);
+
+        let reply = pool.request(Message::Binary(b"again".to_vec())).unwrap();
+        assert_eq!(reply, Message::Binary(b"again".to_vec()));
+    }
+}
@@ -0,0 +1,134 @@
+//! Receive path throughput, run with `cargo bench -p wocket-codec`.
+//!
+//! Compares copying binary messages out of the input with unmasking them
+//! where they are, and checking fragmented text as each frame arrives with
+//! checking it once the message is complete.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use wocket_codec::{opcode, write_frame, WsConnection};
+
+const MASK: Option<[u8; 4]> = Some([0x12, 0x34, 0xab, 0xcd]);
+
+/// Roughly how much data each measurement handles.
+const BYTES_PER_RUN: usize = 256 << 20;
+
+fn main() {
+    for size in [4 << 10, 16 << 10, 64 << 10] {
+        let mut frame = vec![];
+        write_frame(true, opcode::BINARY, &vec![7; size], MASK, &mut frame);
+
+        let mut input = frame.clone();
+        let copied = measure(size, || {
+            let mut conn = WsConnection::new();
+            let mut message = Vec::with_capacity(size);
+            move || {
+                message.clear();
+                conn.receive(black_box(&frame), &mut message).unwrap();
+                black_box(&message);
+            }
+        });
+
+        let in_place = measure(size, || {
+            let mut conn = WsConnection::new();
+            let mut message = vec![];
+            move || {
+                // Unmasking twice gives back the masked frame for next time
+                let received = conn.receive_in_place(&mut input, &mut message).unwrap();
+                black_box(&input[received.in_place.unwrap()]);
+            }
+        });
+
+        report("binary", size, "copied", copied, "in place", in_place);
+    }
+
+    for size in [4 << 10, 16 << 10, 64 << 10] {
+        let text = "wocket é ".repeat(size / 10 + 1);
+        let fragments = fragments(&text.as_bytes()[..size], opcode::BINARY);
+        let text_fragments = fragments_of_text(&text.as_bytes()[..size]);
+
+        let at_end = measure(size, || {
+            let mut conn = WsConnection::new();
+            let mut message = Vec::with_capacity(size);
+            move || {
+                message.clear();
+                receive_all(&mut conn, &fragments, &mut message);
+                assert!(std::str::from_utf8(&message).is_ok());
+            }
+        });
+
+        let per_frame = measure(size, || {
+            let mut conn = WsConnection::new();
+            let mut message = Vec::with_capacity(size);
+            move || {
+                message.clear();
+                receive_all(&mut conn, &text_fragments, &mut message);
+            }
+        });
+
+        report(
+            "text",
+            size,
+            "checked at end",
+            at_end,
+            "per frame",
+            per_frame,
+        );
+    }
+}
+
+/// Splits `payload` into 1KB frames of a message starting with `first`.
+fn fragments(payload: &[u8], first: u8) -> Vec<u8> {
+    let mut frames = vec![];
+    let chunks: Vec<_> = payload.chunks(1 << 10).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let opcode = if i == 0 { first } else { opcode::CONTINUATION };
+        write_frame(i == chunks.len() - 1, opcode, chunk, MASK, &mut frames);
+    }
+    frames
+}
+
+fn fragments_of_text(payload: &[u8]) -> Vec<u8> {
+    // Cut on a character boundary, so the whole message is valid
+    let end = (0..=payload.len())
+        .rev()
+        .find(|&end| std::str::from_utf8(&payload[..end]).is_ok())
+        .unwrap();
+    fragments(&payload[..end], opcode::TEXT)
+}
+
+fn receive_all(conn: &mut WsConnection, mut input: &[u8], message: &mut Vec<u8>) {
+    while !input.is_empty() {
+        let received = conn.receive(input, message).unwrap();
+        input = &input[received.consumed..];
+    }
+}
+
+/// Runs the closure made by `setup` over about `BYTES_PER_RUN` bytes of
+/// `size` byte messages, and returns how long it took.
+fn measure<F: FnMut()>(size: usize, setup: impl FnOnce() -> F) -> Duration {
+    let mut run = setup();
+    let iterations = BYTES_PER_RUN / size;
+
+    for _ in 0..iterations / 10 {
+        run();
+    }
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        run();
+    }
+    start.elapsed()
+}
+
+fn report(kind: &str, size: usize, a: &str, a_took: Duration, b: &str, b_took: Duration) {
+    let rate = |took: Duration| BYTES_PER_RUN as f64 / took.as_secs_f64() / f64::from(1 << 30);
+    println!(
+        "{kind:6} {:>3}KB  {a} {:6.2} GiB/s  {b} {:6.2} GiB/s  ({:+.0}%)",
+        size >> 10,
+        rate(a_took),
+        rate(b_took),
+        (a_took.as_secs_f64() / b_took.as_secs_f64() - 1.0) * 100.0,
+    );
+}
@@ -0,0 +1,88 @@
+//! Many small messages packed into one WebSocket message, to save the
+//! per-frame overhead on high-frequency updates.
+//!
+//! A batch is any number of entries back to back, each a 4 byte big-endian
+//! length followed by that many bytes of message. Both ends have to agree
+//! to use batches, e.g. with the `PROTOCOL` subprotocol; once they have,
+//! every binary message is a batch, even one holding a single message.
+
+use alloc::vec::Vec;
+
+/// The subprotocol a client offers to receive batches.
+pub const PROTOCOL: &str = "wocket.batch.v1";
+
+/// Length of the prefix on each entry.
+pub const PREFIX_LEN: usize = 4;
+
+/// Appends `message` to `batch`. Fails if it is too long for its length
+/// prefix.
+pub fn push(batch: &mut Vec<u8>, message: &[u8]) -> Result<(), &'static str> {
+    let len = u32::try_from(message.len()).map_err(|_| "Message too long for a batch")?;
+    batch.reserve(PREFIX_LEN + message.len());
+    batch.extend_from_slice(&len.to_be_bytes());
+    batch.extend_from_slice(message);
+    Ok(())
+}
+
+/// The messages in `batch`, in order. Yields an error, then stops, if an
+/// entry runs past the end.
+pub fn unpack(batch: &[u8]) -> Unpack<'_> {
+    Unpack { rest: batch }
+}
+
+/// Iterator over the messages in a batch, from `unpack`.
+#[derive(Debug, Clone)]
+pub struct Unpack<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Unpack<'a> {
+    type Item = Result<&'a [u8], &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+
+        let entry = self
+            .rest
+            .split_first_chunk::<PREFIX_LEN>()
+            .and_then(|(len, rest)| {
+                let len = usize::try_from(u32::from_be_bytes(*len)).ok()?;
+                (len <= rest.len()).then(|| rest.split_at(len))
+            });
+
+        match entry {
+            Some((message, rest)) => {
+                self.rest = rest;
+                Some(Ok(message))
+            }
+            None => {
+                self.rest = &[];
+                Some(Err("Truncated batch entry"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut batch = Vec::new();
+        push(&mut batch, b"tick 1").unwrap();
+        push(&mut batch, b"").unwrap();
+        push(&mut batch, b"tick 2").unwrap();
+        assert_eq!(batch.len(), 3 * PREFIX_LEN + 12);
+
+        let messages: Result<Vec<_>, _> = unpack(&batch).collect();
+        assert_eq!(messages.unwrap(), [&b"tick 1"[..], b"", b"tick 2"]);
+
+        let mut truncated = unpack(&batch[..batch.len() - 1]);
+        assert_eq!(truncated.nth(2), Some(Err("Truncated batch entry")));
+        assert_eq!(truncated.next(), None);
+        assert_eq!(unpack(&[0, 0]).next(), Some(Err("Truncated batch entry")));
+    }
+}
@@ -0,0 +1,975 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::extension::{ExtensionFrame, WsExtension};
+use crate::mask::{MaskRng, SeededRng};
+use crate::{
+    fit_close_reason, is_valid_close_code, opcode, parse_frame_header, unmask_in_place,
+    unmask_into, write_frame, write_frame_with_rsv,
+};
+
+/// Something that happened on a connection, returned by `WsConnection::receive`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A whole binary message has been unmasked into the message buffer,
+    /// or left in the input at `Received::in_place`.
+    Binary,
+
+    /// A whole text message has been unmasked into the message buffer. It
+    /// has already been checked to be valid UTF-8.
+    Text,
+
+    /// The peer sent a ping. A pong with the same payload has already been
+    /// queued, unless automatic pongs are turned off, in which case the
+    /// caller should answer with `send_pong`.
+    Ping(Vec<u8>),
+
+    /// The peer sent a pong.
+    Pong(Vec<u8>),
+
+    /// The peer sent a close frame, with its status code and reason if it
+    /// gave one. If we hadn't already sent one, a reply has been queued, and
+    /// the connection is now closed.
+    Close(Option<CloseFrame>),
+}
+
+/// The status code and reason from a close frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+/// The result of feeding bytes to `WsConnection::receive`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Received {
+    /// How many bytes of the input were used. Zero means a whole frame
+    /// hasn't arrived yet, and `receive` should be called again with more.
+    pub consumed: usize,
+
+    pub event: Option<Event>,
+
+    /// Where in the input the message is, if `receive_in_place` unmasked it
+    /// there instead of copying it into the message buffer.
+    pub in_place: Option<Range<usize>>,
+}
+
+/// Where a connection is in its lifetime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// The opening handshake is still going on. `WsConnection` starts once
+    /// the handshake is over, so it never reports this, but code that owns
+    /// the handshake can.
+    Connecting,
+
+    Open,
+
+    /// We sent a close frame and are waiting for the peer's reply. Frames
+    /// from the peer are still received, but nothing more can be sent.
+    ClosingLocal,
+
+    /// The peer sent a close frame. Our reply is queued, but hasn't been
+    /// taken from the output yet.
+    ClosingRemote,
+
+    /// Close frames have gone both ways and been written out.
+    Closed,
+}
+
+/// What happens to a ping or pong longer than the 125 bytes RFC 6455 allows
+/// a control frame. Some devices send longer ones anyway, e.g. to carry
+/// telemetry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizedControl {
+    /// Close with 1002 (Protocol Error), as the RFC says to.
+    Reject,
+
+    /// Take payloads of up to this many bytes, but only keep the first 125.
+    Truncate(usize),
+
+    /// Take payloads of up to this many bytes whole. Automatic pongs still
+    /// only echo the first 125.
+    Allow(usize),
+}
+
+/// Which end of the connection we are. Clients mask every frame they send,
+/// and servers never do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+/// One end of a WebSocket connection as a sans-IO state machine.
+///
+/// It doesn't do any IO itself. Bytes read from the peer go into `receive`,
+/// which turns them into events. Anything that has to be sent back, such as
+/// data frames from `send_binary` and automatic pongs, collects in an output
+/// buffer that the caller drains with `take_output` and writes to the peer.
+/// This makes it usable from any event loop, and lets protocol behaviour be
+/// tested without sockets.
+#[derive(Debug)]
+pub struct WsConnection {
+    role: Role,
+
+    /// Where client masking keys come from. Servers don't have one.
+    mask_rng: Option<Box<dyn MaskRng + Send>>,
+
+    output: Vec<u8>,
+    max_message_size: usize,
+    auto_pong: bool,
+    oversized_control: OversizedControl,
+
+    /// Extensions data frames go through, in the order they were added, and
+    /// the RSV bits they have claimed between them.
+    extensions: Vec<Box<dyn WsExtension>>,
+    rsv_bits: u8,
+
+    /// Opcode of the fragmented message being received, if there is one.
+    fragmented: Option<u8>,
+
+    /// How much of the text message being received has been checked to be
+    /// UTF-8. Each frame is checked as it is unmasked, while it is still in
+    /// cache, rather than the whole message once it is complete.
+    utf8_checked: usize,
+
+    close_sent: bool,
+    close_received: bool,
+
+    /// Whether the peer's close frame arrived before we sent ours.
+    closed_by_peer: bool,
+}
+
+impl Default for WsConnection {
+    fn default() -> Self {
+        WsConnection::new()
+    }
+}
+
+impl WsConnection {
+    /// Creates the server end of a connection.
+    pub fn new() -> Self {
+        WsConnection {
+            role: Role::Server,
+            mask_rng: None,
+            output: Vec::new(),
+            max_message_size: usize::MAX,
+            auto_pong: true,
+            oversized_control: OversizedControl::Reject,
+            extensions: Vec::new(),
+            rsv_bits: 0,
+            fragmented: None,
+            utf8_checked: 0,
+            close_sent: false,
+            close_received: false,
+            closed_by_peer: false,
+        }
+    }
+
+    /// Creates the client end of a connection, with masking keys from a
+    /// `SeededRng`. The seed should itself be random, except in tests that
+    /// want the same frames every time.
+    pub fn client(seed: u64) -> Self {
+        WsConnection::client_with_rng(SeededRng::new(seed))
+    }
+
+    /// Creates the client end of a connection, with masking keys from `rng`.
+    pub fn client_with_rng(rng: impl MaskRng + Send + 'static) -> Self {
+        WsConnection {
+            role: Role::Client,
+            mask_rng: Some(Box::new(rng)),
+            ..WsConnection::new()
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Sets the largest message, after reassembling fragments, the peer may
+    /// send. Bigger ones close the connection with 1009 (Message Too Big).
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Sets whether pings are answered with a pong as soon as they are
+    /// received, which is the default. Without that, `Event::Ping` leaves the
+    /// reply to the caller, e.g. to put its own heartbeat data in the pong.
+    pub fn with_auto_pong(mut self, auto_pong: bool) -> Self {
+        self.auto_pong = auto_pong;
+        self
+    }
+
+    /// Sets what happens to pings and pongs longer than 125 bytes. By
+    /// default they close the connection. Close frames can never be longer.
+    pub fn with_oversized_control(mut self, oversized_control: OversizedControl) -> Self {
+        self.oversized_control = oversized_control;
+        self
+    }
+
+    /// Adds an extension that both ends have agreed on. Fails if it wants
+    /// RSV bits that an extension added earlier already uses.
+    pub fn with_extension(
+        mut self,
+        extension: impl WsExtension + 'static,
+    ) -> Result<Self, &'static str> {
+        let bits = extension.rsv_bits() & 0b111;
+        if bits & self.rsv_bits != 0 {
+            return Err("RSV bit already used by another extension");
+        }
+
+        self.rsv_bits |= bits;
+        self.extensions.push(Box::new(extension));
+        Ok(self)
+    }
+
+    /// Handles the frame at the start of `input`.
+    ///
+    /// Data frames are unmasked straight into `message`. A message may be
+    /// split over several frames, so keep passing the same buffer until an
+    /// `Event::Binary` or `Event::Text` says it is complete, then clear it
+    /// before the next one.
+    ///
+    /// Protocol errors queue a close frame with a matching status code and
+    /// return `Err`. The caller should write out the output and then drop
+    /// the connection.
+    pub fn receive(
+        &mut self,
+        input: &[u8],
+        message: &mut Vec<u8>,
+    ) -> Result<Received, &'static str> {
+        if self.is_closed() {
+            return Err("Connection is closed");
+        }
+
+        let header = match parse_frame_header(input) {
+            Ok(Some(header)) => header,
+            Ok(None) => return Ok(Received::incomplete()),
+            Err(err) => return Err(self.fail(1002, err)),
+        };
+
+        if header.rsv & !self.rsv_bits != 0 {
+            return Err(self.fail(1002, "RSV bits set without a negotiated extension"));
+        }
+
+        match (self.role, header.mask) {
+            (Role::Server, None) => {
+                return Err(self.fail(1002, "Frames from clients must be masked"))
+            }
+            (Role::Client, Some(_)) => {
+                return Err(self.fail(1002, "Frames from servers must not be masked"))
+            }
+            _ => {}
+        }
+
+        let is_control = opcode::is_control(header.opcode);
+
+        let max_control_len = match (header.opcode, self.oversized_control) {
+            (opcode::CLOSE, _) | (_, OversizedControl::Reject) => 125,
+            (_, OversizedControl::Truncate(max) | OversizedControl::Allow(max)) => max.max(125),
+        };
+
+        if is_control && (!header.fin || header.payload_len > max_control_len) {
+            return Err(self.fail(1002, "Control frames must be unfragmented and short"));
+        }
+
+        if !is_control && message.len().saturating_add(header.payload_len) > self.max_message_size {
+            return Err(self.fail(1009, "Message too big"));
+        }
+
+        let frame_len = header.frame_len();
+
+        if input.len() < frame_len {
+            return Ok(Received::incomplete());
+        }
+
+        let payload = &input[header.header_len..frame_len];
+
+        let event = match header.opcode {
+            opcode::CONTINUATION | opcode::TEXT | opcode::BINARY => {
+                let message_opcode = match (header.opcode, self.fragmented) {
+                    (opcode::CONTINUATION, Some(message_opcode)) => message_opcode,
+                    (opcode::CONTINUATION, None) => {
+                        return Err(self.fail(1002, "Continuation frame without a message"))
+                    }
+                    (_, Some(_)) => {
+                        return Err(self.fail(1002, "New message before the last one finished"))
+                    }
+                    (data_opcode, None) => {
+                        self.utf8_checked = 0;
+                        data_opcode
+                    }
+                };
+
+                if self.extensions.is_empty() {
+                    unmask_into(payload, header.mask, message);
+                } else {
+                    let mut frame = ExtensionFrame {
+                        fin: header.fin,
+                        rsv: header.rsv,
+                        opcode: header.opcode,
+                        payload: Vec::with_capacity(payload.len()),
+                    };
+                    unmask_into(payload, header.mask, &mut frame.payload);
+
+                    for extension in self.extensions.iter_mut().rev() {
+                        if let Err(err) = extension.on_receive_frame(&mut frame) {
+                            return Err(self.fail(1002, err));
+                        }
+                    }
+
+                    // Extensions like compression can make the payload bigger
+                    if message.len().saturating_add(frame.payload.len()) > self.max_message_size {
+                        return Err(self.fail(1009, "Message too big"));
+                    }
+                    message.extend_from_slice(&frame.payload);
+                }
+
+                if message_opcode == opcode::TEXT {
+                    match core::str::from_utf8(&message[self.utf8_checked..]) {
+                        Ok(_) => self.utf8_checked = message.len(),
+                        // A character split between this frame and the next
+                        Err(err) if !header.fin && err.error_len().is_none() => {
+                            self.utf8_checked += err.valid_up_to();
+                        }
+                        Err(_) => return Err(self.fail(1007, "Text message is not valid UTF-8")),
+                    }
+                }
+
+                if !header.fin {
+                    self.fragmented = Some(message_opcode);
+                    None
+                } else {
+                    self.fragmented = None;
+
+                    if message_opcode == opcode::TEXT {
+                        Some(Event::Text)
+                    } else {
+                        Some(Event::Binary)
+                    }
+                }
+            }
+            opcode::PING => {
+                let data = self.control_payload(payload, header.mask);
+
+                if self.auto_pong && !self.close_sent {
+                    self.write(opcode::PONG, &data[..data.len().min(125)]);
+                }
+
+                Some(Event::Ping(data))
+            }
+            opcode::PONG => Some(Event::Pong(self.control_payload(payload, header.mask))),
+            opcode::CLOSE => {
+                let mut data = Vec::with_capacity(payload.len());
+                unmask_into(payload, header.mask, &mut data);
+
+                let frame = match data.len() {
+                    0 => None,
+                    1 => return Err(self.fail(1002, "Close frame payload is one byte long")),
+                    _ => {
+                        let code = u16::from_be_bytes([data[0], data[1]]);
+                        if !is_valid_close_code(code) {
+                            return Err(self.fail(1002, "Invalid close code"));
+                        }
+                        match core::str::from_utf8(&data[2..]) {
+                            Ok(reason) => Some(CloseFrame {
+                                code,
+                                reason: String::from(reason),
+                            }),
+                            Err(_) => {
+                                return Err(self.fail(1007, "Close reason is not valid UTF-8"))
+                            }
+                        }
+                    }
+                };
+
+                self.close_received = true;
+
+                if !self.close_sent {
+                    self.closed_by_peer = true;
+                    // Reply with the same status code, as RFC 6455 suggests.
+                    // It has been checked, so reserved codes aren't echoed
+                    self.write(opcode::CLOSE, &data[..data.len().min(2)]);
+                    self.close_sent = true;
+                }
+
+                Some(Event::Close(frame))
+            }
+            _ => return Err(self.fail(1002, "Unknown opcode")),
+        };
+
+        Ok(Received {
+            consumed: frame_len,
+            event,
+            in_place: None,
+        })
+    }
+
+    /// Like `receive`, but an unfragmented binary message that needs no
+    /// extension is unmasked where it is in `input`, and left there, instead
+    /// of being copied into `message`. `Received::in_place` says where it
+    /// is. Everything else is handled as `receive` would.
+    pub fn receive_in_place(
+        &mut self,
+        input: &mut [u8],
+        message: &mut Vec<u8>,
+    ) -> Result<Received, &'static str> {
+        let header = match parse_frame_header(input) {
+            Ok(Some(header)) => header,
+            _ => return self.receive(input, message),
+        };
+
+        let frame_len = header.frame_len();
+        let masked_as_expected = match self.role {
+            Role::Server => header.mask.is_some(),
+            Role::Client => header.mask.is_none(),
+        };
+
+        let unfragmented_binary = header.fin
+            && header.opcode == opcode::BINARY
+            && header.rsv == 0
+            && self.fragmented.is_none()
+            && self.extensions.is_empty()
+            && message.is_empty();
+
+        if !unfragmented_binary
+            || !masked_as_expected
+            || self.is_closed()
+            || header.payload_len > self.max_message_size
+            || input.len() < frame_len
+        {
+            return self.receive(input, message);
+        }
+
+        let payload = header.header_len..frame_len;
+        if let Some(key) = header.mask {
+            unmask_in_place(&mut input[payload.clone()], key);
+        }
+
+        Ok(Received {
+            consumed: frame_len,
+            event: Some(Event::Binary),
+            in_place: Some(payload),
+        })
+    }
+
+    /// Unmasks a ping or pong payload, cut short if oversized ones are
+    /// truncated.
+    fn control_payload(&self, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut data = Vec::with_capacity(payload.len());
+        unmask_into(payload, mask, &mut data);
+
+        if let OversizedControl::Truncate(_) = self.oversized_control {
+            data.truncate(125);
+        }
+        data
+    }
+
+    /// Queues a binary message.
+    pub fn send_binary(&mut self, message: &[u8]) -> Result<(), &'static str> {
+        self.send(opcode::BINARY, message)
+    }
+
+    /// Queues a frame the caller has already encoded, e.g. once for many
+    /// connections with `write_frame`. Fails if an extension would have
+    /// changed it, or on a client, whose frames each need their own mask.
+    pub fn send_encoded(&mut self, frame: &[u8]) -> Result<(), &'static str> {
+        if self.role == Role::Client {
+            return Err("Clients can't send pre-encoded frames");
+        }
+
+        match self.state() {
+            State::Connecting | State::Open => {}
+            State::ClosingLocal => return Err("Close frame already sent"),
+            State::ClosingRemote => return Err("Peer is closing the connection"),
+            State::Closed => return Err("Connection is closed"),
+        }
+
+        if !self.extensions.is_empty() {
+            return Err("Encoded frames can't go through extensions");
+        }
+
+        self.output.extend_from_slice(frame);
+        Ok(())
+    }
+
+    /// Queues a text message.
+    pub fn send_text(&mut self, message: &str) -> Result<(), &'static str> {
+        self.send(opcode::TEXT, message.as_bytes())
+    }
+
+    /// Queues a ping.
+    pub fn send_ping(&mut self, payload: &[u8]) -> Result<(), &'static str> {
+        if payload.len() > 125 {
+            return Err("Ping payload longer than 125 bytes");
+        }
+
+        self.send(opcode::PING, payload)
+    }
+
+    /// Queues an unsolicited pong, which peers treat as a heartbeat.
+    pub fn send_pong(&mut self, payload: &[u8]) -> Result<(), &'static str> {
+        if payload.len() > 125 {
+            return Err("Pong payload longer than 125 bytes");
+        }
+
+        self.send(opcode::PONG, payload)
+    }
+
+    fn send(&mut self, opcode: u8, payload: &[u8]) -> Result<(), &'static str> {
+        match self.state() {
+            State::Connecting | State::Open => {}
+            State::ClosingLocal => return Err("Close frame already sent"),
+            State::ClosingRemote => return Err("Peer is closing the connection"),
+            State::Closed => return Err("Connection is closed"),
+        }
+
+        if opcode::is_control(opcode) || self.extensions.is_empty() {
+            self.write(opcode, payload);
+            return Ok(());
+        }
+
+        let mut frame = ExtensionFrame {
+            fin: true,
+            rsv: 0,
+            opcode,
+            payload: payload.to_vec(),
+        };
+        for extension in &mut self.extensions {
+            extension.on_send_frame(&mut frame)?;
+        }
+
+        let mask = self.next_mask();
+        write_frame_with_rsv(
+            frame.fin,
+            frame.rsv,
+            frame.opcode,
+            &frame.payload,
+            mask,
+            &mut self.output,
+        );
+        Ok(())
+    }
+
+    /// Queues a frame, masked if we are the client.
+    fn write(&mut self, opcode: u8, payload: &[u8]) {
+        let mask = self.next_mask();
+        write_frame(true, opcode, payload, mask, &mut self.output);
+    }
+
+    fn next_mask(&mut self) -> Option<[u8; 4]> {
+        match self.role {
+            Role::Server => None,
+            Role::Client => {
+                let rng = self.mask_rng.as_mut().expect("clients have a mask RNG");
+                Some(rng.next_mask())
+            }
+        }
+    }
+
+    /// Queues a close frame with a status code and reason. After this,
+    /// nothing more can be sent, but frames from the peer are still handled
+    /// until it replies with its own close frame.
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if self.close_sent {
+            return;
+        }
+
+        let reason = fit_close_reason(reason.as_bytes());
+
+        let mut payload = [0; 125];
+        payload[..2].copy_from_slice(&code.to_be_bytes());
+        payload[2..2 + reason.len()].copy_from_slice(reason);
+
+        self.write(opcode::CLOSE, &payload[..2 + reason.len()]);
+        self.close_sent = true;
+    }
+
+    /// Where the connection is in the close handshake. A connection only
+    /// counts as `Closed` once the last close frame has been taken from the
+    /// output.
+    pub fn state(&self) -> State {
+        match (self.close_sent, self.close_received) {
+            (false, _) => State::Open,
+            (true, false) => State::ClosingLocal,
+            (true, true) if self.output.is_empty() => State::Closed,
+            (true, true) if self.closed_by_peer => State::ClosingRemote,
+            (true, true) => State::ClosingLocal,
+        }
+    }
+
+    /// Whether we have sent a close frame, so nothing more can be sent.
+    pub fn is_closing(&self) -> bool {
+        self.close_sent
+    }
+
+    /// Whether close frames have gone both ways, so the connection should be
+    /// dropped once the output has been written.
+    pub fn is_closed(&self) -> bool {
+        self.close_sent && self.close_received
+    }
+
+    /// Bytes waiting to be written to the peer.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Moves the bytes waiting to be written to the peer onto the end of
+    /// `out`.
+    pub fn take_output(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.output);
+    }
+
+    /// Queues a close frame for a protocol error and hands back the error.
+    fn fail(&mut self, code: u16, err: &'static str) -> &'static str {
+        self.close(code, err);
+        self.close_received = true;
+        err
+    }
+}
+
+impl Received {
+    fn incomplete() -> Self {
+        Received {
+            consumed: 0,
+            event: None,
+            in_place: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::vec;
+
+    use crate::parse_frame_header;
+
+    /// Builds a masked frame like a client would send.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0xab, 0xcd];
+
+        let mut frame = vec![((fin as u8) << 7) | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// Splits the output of `conn` into (opcode, payload) pairs.
+    fn output_frames(conn: &mut WsConnection) -> Vec<(u8, Vec<u8>)> {
+        let mut out = vec![];
+        conn.take_output(&mut out);
+
+        let mut frames = vec![];
+        let mut rest = &out[..];
+        while let Some(header) = parse_frame_header(rest).unwrap() {
+            let payload = rest[header.header_len..header.frame_len()].to_vec();
+            frames.push((header.opcode, payload));
+            rest = &rest[header.frame_len()..];
+        }
+        frames
+    }
+
+    #[test]
+    fn fragmented_message_with_ping_in_between() {
+        let mut conn = WsConnection::new();
+        let mut message = vec![];
+
+        let mut input = client_frame(false, opcode::TEXT, b"Hel");
+        input.extend(client_frame(true, opcode::PING, b"hi"));
+        input.extend(client_frame(true, opcode::CONTINUATION, b"lo"));
+
+        let mut events = vec![];
+        let mut rest = &input[..];
+        while !rest.is_empty() {
+            let received = conn.receive(rest, &mut message).unwrap();
+            assert_ne!(received.consumed, 0);
+            events.push(received.event);
+            rest = &rest[received.consumed..];
+        }
+
+        assert_eq!(
+            events,
+            [None, Some(Event::Ping(b"hi".to_vec())), Some(Event::Text)]
+        );
+        assert_eq!(message, b"Hello");
+        assert_eq!(output_frames(&mut conn), [(opcode::PONG, b"hi".to_vec())]);
+    }
+
+    #[test]
+    fn characters_split_between_fragments() {
+        let mut conn = WsConnection::new();
+        let mut message = vec![];
+
+        // "é" is two bytes, one in each frame
+        let first = client_frame(false, opcode::TEXT, b"caf\xc3");
+        assert_eq!(conn.receive(&first, &mut message).unwrap().event, None);
+        let last = client_frame(true, opcode::CONTINUATION, b"\xa9!");
+        let received = conn.receive(&last, &mut message).unwrap();
+        assert_eq!(received.event, Some(Event::Text));
+        assert_eq!(message, "café!".as_bytes());
+
+        // A bad byte fails the frame it arrives in, not the last one
+        let mut conn = WsConnection::new();
+        let first = client_frame(false, opcode::TEXT, b"caf\xff");
+        assert!(conn.receive(&first, &mut vec![]).is_err());
+        assert_eq!(output_frames(&mut conn)[0].1[..2], 1007u16.to_be_bytes());
+    }
+
+    #[test]
+    fn binary_messages_unmasked_in_place() {
+        let mut conn = WsConnection::new();
+        let mut message = vec![];
+
+        let mut input = client_frame(true, opcode::BINARY, b"left where it is");
+        input.extend(client_frame(false, opcode::BINARY, b"frag"));
+        input.extend(client_frame(true, opcode::CONTINUATION, b"ments"));
+
+        let received = conn.receive_in_place(&mut input, &mut message).unwrap();
+        assert_eq!(received.event, Some(Event::Binary));
+        let range = received.in_place.unwrap();
+        assert_eq!(&input[range], b"left where it is");
+        assert!(message.is_empty());
+
+        // Fragmented messages are still put together in the message buffer
+        let mut rest = &mut input[received.consumed..];
+        let mut events = vec![];
+        while !rest.is_empty() {
+            let received = conn.receive_in_place(rest, &mut message).unwrap();
+            assert_eq!(received.in_place, None);
+            events.push(received.event);
+            rest = &mut rest[received.consumed..];
+        }
+        assert_eq!(events, [None, Some(Event::Binary)]);
+        assert_eq!(message, b"fragments");
+    }
+
+    #[test]
+    fn pings_left_to_the_caller() {
+        let mut conn = WsConnection::new().with_auto_pong(false);
+
+        let ping = client_frame(true, opcode::PING, b"hi");
+        let received = conn.receive(&ping, &mut vec![]).unwrap();
+        assert_eq!(received.event, Some(Event::Ping(b"hi".to_vec())));
+        assert!(conn.output().is_empty());
+
+        conn.send_pong(b"hi 42").unwrap();
+        assert_eq!(
+            output_frames(&mut conn),
+            [(opcode::PONG, b"hi 42".to_vec())]
+        );
+    }
+
+    #[test]
+    fn oversized_pings() {
+        let mask = Some([0x12, 0x34, 0xab, 0xcd]);
+        let long = [7; 200];
+        let mut ping = vec![];
+        write_frame(true, opcode::PING, &long, mask, &mut ping);
+
+        let mut conn = WsConnection::new();
+        assert!(conn.receive(&ping, &mut vec![]).is_err());
+
+        let mut conn = WsConnection::new().with_oversized_control(OversizedControl::Truncate(200));
+        let received = conn.receive(&ping, &mut vec![]).unwrap();
+        assert_eq!(received.event, Some(Event::Ping(long[..125].to_vec())));
+        assert_eq!(
+            output_frames(&mut conn),
+            [(opcode::PONG, long[..125].to_vec())]
+        );
+
+        let mut conn = WsConnection::new().with_oversized_control(OversizedControl::Allow(200));
+        let received = conn.receive(&ping, &mut vec![]).unwrap();
+        assert_eq!(received.event, Some(Event::Ping(long.to_vec())));
+        assert_eq!(
+            output_frames(&mut conn),
+            [(opcode::PONG, long[..125].to_vec())]
+        );
+
+        let mut too_long = vec![];
+        write_frame(true, opcode::PING, &[7; 201], mask, &mut too_long);
+        assert!(conn.receive(&too_long, &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn incomplete_frame() {
+        let mut conn = WsConnection::new();
+        let frame = client_frame(true, opcode::BINARY, b"Hello");
+
+        let received = conn
+            .receive(&frame[..frame.len() - 1], &mut vec![])
+            .unwrap();
+        assert_eq!(received.consumed, 0);
+        assert_eq!(received.event, None);
+    }
+
+    #[test]
+    fn close_handshake_started_by_peer() {
+        let mut conn = WsConnection::new();
+
+        let mut payload = 1000u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        let frame = client_frame(true, opcode::CLOSE, &payload);
+        let received = conn.receive(&frame, &mut vec![]).unwrap();
+
+        assert_eq!(
+            received.event,
+            Some(Event::Close(Some(CloseFrame {
+                code: 1000,
+                reason: String::from("bye"),
+            })))
+        );
+        assert!(conn.is_closed());
+        assert_eq!(conn.state(), State::ClosingRemote);
+        assert_eq!(
+            conn.send_binary(b"too late"),
+            Err("Peer is closing the connection")
+        );
+
+        assert_eq!(
+            output_frames(&mut conn),
+            [(opcode::CLOSE, 1000u16.to_be_bytes().to_vec())]
+        );
+        assert_eq!(conn.state(), State::Closed);
+        assert!(conn.send_binary(b"too late").is_err());
+    }
+
+    #[test]
+    fn close_handshake_started_by_us() {
+        let mut conn = WsConnection::new();
+        assert_eq!(conn.state(), State::Open);
+
+        conn.close(1001, "going away");
+        output_frames(&mut conn);
+        assert_eq!(conn.state(), State::ClosingLocal);
+        assert_eq!(
+            conn.send_binary(b"too late"),
+            Err("Close frame already sent")
+        );
+
+        // Messages still arrive until the peer replies
+        let frame = client_frame(true, opcode::BINARY, b"in flight");
+        let received = conn.receive(&frame, &mut vec![]).unwrap();
+        assert_eq!(received.event, Some(Event::Binary));
+
+        let frame = client_frame(true, opcode::CLOSE, &1001u16.to_be_bytes());
+        conn.receive(&frame, &mut vec![]).unwrap();
+        assert_eq!(conn.state(), State::Closed);
+        assert!(output_frames(&mut conn).is_empty());
+    }
+
+    #[test]
+    fn only_servers_send_encoded_frames() {
+        let mut frame = vec![];
+        crate::write_ws_frame(b"shared", None, &mut frame);
+
+        let mut server = WsConnection::new();
+        server.send_encoded(&frame).unwrap();
+        assert_eq!(server.output(), &frame[..]);
+
+        let mut client = WsConnection::client_with_rng(|| 0x1234_5678);
+        assert!(client.send_encoded(&frame).is_err());
+        assert!(client.output().is_empty());
+    }
+
+    #[test]
+    fn long_close_reasons_keep_whole_characters() {
+        // The é takes up bytes 122 and 123, so it doesn't fit
+        let reason = "a".repeat(122) + "é and more";
+        let mut server = WsConnection::new();
+        server.close(4000, &reason);
+
+        let mut frame = vec![];
+        server.take_output(&mut frame);
+        let mut client = WsConnection::client_with_rng(|| 0x1234_5678);
+        let received = client.receive(&frame, &mut vec![]).unwrap();
+        assert_eq!(
+            received.event,
+            Some(Event::Close(Some(CloseFrame {
+                code: 4000,
+                reason: "a".repeat(122),
+            })))
+        );
+
+        let mut frame = vec![];
+        crate::write_close_frame(4000, reason.as_bytes(), &mut frame);
+        assert_eq!(frame.len(), 2 + 2 + 122);
+
+        // Nothing but continuation bytes has no boundary to step back to
+        let mut frame = vec![];
+        crate::write_close_frame(4000, &[0x80; 200], &mut frame);
+        assert_eq!(frame, [0x88, 2, 0x0f, 0xa0]);
+    }
+
+    #[test]
+    fn protocol_errors_close_the_connection() {
+        let mut conn = WsConnection::new();
+        let frame = client_frame(true, opcode::CONTINUATION, b"orphan");
+        assert!(conn.receive(&frame, &mut vec![]).is_err());
+        assert_eq!(output_frames(&mut conn)[0].1[..2], 1002u16.to_be_bytes());
+
+        let mut conn = WsConnection::new();
+        let frame = client_frame(true, opcode::TEXT, &[0xff, 0xfe]);
+        assert!(conn.receive(&frame, &mut vec![]).is_err());
+        assert_eq!(output_frames(&mut conn)[0].1[..2], 1007u16.to_be_bytes());
+
+        // Codes kept for reporting closes locally can't come from the peer
+        for reserved in [0, 999, 1005, 1006, 1015] {
+            let mut conn = WsConnection::new();
+            let frame = client_frame(true, opcode::CLOSE, &u16::to_be_bytes(reserved));
+            assert!(conn.receive(&frame, &mut vec![]).is_err());
+            assert_eq!(output_frames(&mut conn)[0].1[..2], 1002u16.to_be_bytes());
+        }
+
+        let mut conn = WsConnection::new().with_max_message_size(4);
+        let frame = client_frame(true, opcode::BINARY, b"Hello");
+        assert!(conn.receive(&frame, &mut vec![]).is_err());
+        assert_eq!(output_frames(&mut conn)[0].1[..2], 1009u16.to_be_bytes());
+    }
+
+    #[test]
+    fn client_masks_and_server_unmasks() {
+        let mut client = WsConnection::client(42);
+        let mut server = WsConnection::new();
+
+        client.send_binary(b"Hello").unwrap();
+        let mut sent = vec![];
+        client.take_output(&mut sent);
+        assert!(parse_frame_header(&sent).unwrap().unwrap().mask.is_some());
+
+        let mut message = vec![];
+        let received = server.receive(&sent, &mut message).unwrap();
+        assert_eq!(received.event, Some(Event::Binary));
+        assert_eq!(message, b"Hello");
+
+        // And clients refuse masked frames from the server
+        assert!(client.receive(&sent, &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn injected_mask_rng() {
+        let mut client = WsConnection::client_with_rng(|| 0x1234_5678);
+        client.send_binary(b"Hi").unwrap();
+
+        let mut sent = vec![];
+        client.take_output(&mut sent);
+        assert_eq!(
+            sent,
+            [0x82, 0x82, 0x12, 0x34, 0x56, 0x78, b'H' ^ 0x12, b'i' ^ 0x34]
+        );
+
+        // Seeded clients send the same bytes every time
+        let frames = || {
+            let mut client = WsConnection::client(7);
+            client.send_binary(b"Hello").unwrap();
+            let mut sent = vec![];
+            client.take_output(&mut sent);
+            sent
+        };
+        assert_eq!(frames(), frames());
+    }
+}
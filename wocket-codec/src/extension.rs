@@ -0,0 +1,122 @@
+//! Hooks for WebSocket extensions, such as custom compression or
+//! encryption, that rewrite data frames on their way in and out.
+//!
+//! An extension is added to a `WsConnection` once both ends have agreed on
+//! it, e.g. through `Sec-WebSocket-Extensions` in the opening handshake; the
+//! codec doesn't negotiate anything itself. Each extension claims the RSV
+//! bits it uses, so frames with other RSV bits set are still refused, and
+//! two extensions can't both use the same bit.
+//!
+//! Only data frames go through extensions. Frames being sent pass through
+//! them in the order they were added, and received frames in the reverse
+//! order, so each one undoes its own change.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+/// RSV bits as they appear in `FrameHeader::rsv`.
+pub mod rsv {
+    pub const RSV1: u8 = 0b100;
+    pub const RSV2: u8 = 0b010;
+    pub const RSV3: u8 = 0b001;
+}
+
+/// A data frame, as an extension sees it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionFrame {
+    pub fin: bool,
+
+    /// The RSV bits, in the low bits.
+    pub rsv: u8,
+
+    pub opcode: u8,
+
+    /// The unmasked payload.
+    pub payload: Vec<u8>,
+}
+
+/// An extension to the framing. The frame hooks default to leaving frames
+/// alone.
+pub trait WsExtension: Send {
+    /// The RSV bits this extension may set, e.g. `rsv::RSV1`.
+    fn rsv_bits(&self) -> u8;
+
+    /// Rewrites a frame before it is masked and written. An error stops the
+    /// message from being sent.
+    fn on_send_frame(&mut self, _frame: &mut ExtensionFrame) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Rewrites a received frame before its payload is added to the
+    /// message, typically clearing the RSV bits it set. An error closes the
+    /// connection with 1002 (Protocol Error).
+    fn on_receive_frame(&mut self, _frame: &mut ExtensionFrame) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+impl fmt::Debug for dyn WsExtension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WsExtension(rsv {:03b})", self.rsv_bits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::connection::{Event, WsConnection};
+
+    /// Flips every payload bit of the frames it marks with RSV1.
+    struct Invert;
+
+    impl WsExtension for Invert {
+        fn rsv_bits(&self) -> u8 {
+            rsv::RSV1
+        }
+
+        fn on_send_frame(&mut self, frame: &mut ExtensionFrame) -> Result<(), &'static str> {
+            frame.payload.iter_mut().for_each(|byte| *byte = !*byte);
+            frame.rsv |= rsv::RSV1;
+            Ok(())
+        }
+
+        fn on_receive_frame(&mut self, frame: &mut ExtensionFrame) -> Result<(), &'static str> {
+            if frame.rsv & rsv::RSV1 == 0 {
+                return Err("Frame not inverted");
+            }
+            frame.payload.iter_mut().for_each(|byte| *byte = !*byte);
+            frame.rsv &= !rsv::RSV1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn frames_round_trip_through_an_extension() {
+        let mut client = WsConnection::client(1).with_extension(Invert).unwrap();
+        let mut server = WsConnection::new().with_extension(Invert).unwrap();
+
+        client.send_binary(b"secret").unwrap();
+        let mut out = Vec::new();
+        client.take_output(&mut out);
+        assert_eq!(out[0], 0x80 | 0x40 | crate::opcode::BINARY);
+
+        let mut message = Vec::new();
+        let received = server.receive(&out, &mut message).unwrap();
+        assert_eq!(received.event, Some(Event::Binary));
+        assert_eq!(message, b"secret");
+
+        // Without the extension, RSV1 is still a protocol error
+        assert!(WsConnection::new().receive(&out, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn rsv_bits_are_claimed_once() {
+        let conn = WsConnection::new().with_extension(Invert).unwrap();
+        assert_eq!(
+            conn.with_extension(Invert).err(),
+            Some("RSV bit already used by another extension")
+        );
+    }
+}
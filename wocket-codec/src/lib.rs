@@ -0,0 +1,407 @@
+//! WebSocket frame encoding and decoding.
+//!
+//! This crate is `no_std` and only needs `alloc`, so the same framing code
+//! can be used by the server and by clients on embedded targets.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+pub mod batch;
+pub mod connection;
+pub mod extension;
+pub mod mask;
+pub mod mux;
+
+pub use connection::{CloseFrame, Event, OversizedControl, Role, State, WsConnection};
+pub use extension::{ExtensionFrame, WsExtension};
+pub use mask::{MaskRng, SeededRng};
+pub use mux::{Mux, MuxEvent};
+
+/// Frame opcodes.
+pub mod opcode {
+    pub const CONTINUATION: u8 = 0x0;
+    pub const TEXT: u8 = 0x1;
+    pub const BINARY: u8 = 0x2;
+    pub const CLOSE: u8 = 0x8;
+    pub const PING: u8 = 0x9;
+    pub const PONG: u8 = 0xA;
+
+    /// Control frames (close, ping, pong) have the high bit of the opcode set.
+    pub fn is_control(opcode: u8) -> bool {
+        opcode & 0x8 != 0
+    }
+}
+
+/// The part of a frame that comes before the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub fin: bool,
+
+    /// The three RSV bits, in the low bits.
+    pub rsv: u8,
+
+    pub opcode: u8,
+    pub mask: Option<[u8; 4]>,
+    pub payload_len: usize,
+
+    /// Length of the header itself, i.e. where the payload starts.
+    pub header_len: usize,
+}
+
+impl FrameHeader {
+    /// Length of the whole frame, header and payload.
+    pub fn frame_len(&self) -> usize {
+        self.header_len.saturating_add(self.payload_len)
+    }
+}
+
+/// Parses the header of the frame at the start of `frame_buf`. Returns
+/// `Ok(None)` if the header hasn't fully arrived yet.
+pub fn parse_frame_header(frame_buf: &[u8]) -> Result<Option<FrameHeader>, &'static str> {
+    if frame_buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = frame_buf[0] >> 7 == 1;
+    let rsv = (frame_buf[0] >> 4) & 0b111;
+    let opcode = frame_buf[0] & 0xF;
+    let masked = frame_buf[1] >> 7 == 1;
+
+    let mut payload_len = (frame_buf[1] & 0b0111_1111) as u64;
+    let mut mask_idx = 2;
+
+    if payload_len == 126 {
+        // This means the real length does not fit in 7 bits, and it is a 16 bit
+        // unsigned integer starting at frame_buf[2]
+        if frame_buf.len() < 4 {
+            return Ok(None);
+        }
+
+        payload_len = u16::from_be_bytes([frame_buf[2], frame_buf[3]]) as u64;
+        mask_idx = 4;
+    } else if payload_len == 127 {
+        // Same again but with a 64 bit length starting at frame_buf[2]
+        if frame_buf.len() < 10 {
+            return Ok(None);
+        }
+
+        let mut len_bytes = [0; 8];
+        len_bytes.copy_from_slice(&frame_buf[2..10]);
+        payload_len = u64::from_be_bytes(len_bytes);
+        mask_idx = 10;
+
+        if payload_len >> 63 != 0 {
+            return Err("Most significant bit of 64 bit length is set");
+        }
+    }
+
+    let payload_len: usize = match payload_len.try_into() {
+        Ok(len) => len,
+        Err(_) => return Err("Payload length doesn't fit in memory"),
+    };
+
+    let mut header_len = mask_idx;
+    let mut mask = None;
+
+    if masked {
+        if frame_buf.len() < mask_idx + 4 {
+            return Ok(None);
+        }
+
+        let mut key = [0; 4];
+        key.copy_from_slice(&frame_buf[mask_idx..mask_idx + 4]);
+        mask = Some(key);
+        header_len += 4;
+    }
+
+    Ok(Some(FrameHeader {
+        fin,
+        rsv,
+        opcode,
+        mask,
+        payload_len,
+        header_len,
+    }))
+}
+
+/// Appends `payload` to `out`, unmasking it with `mask` if there is one.
+pub fn unmask_into(payload: &[u8], mask: Option<[u8; 4]>, out: &mut Vec<u8>) {
+    match mask {
+        None => out.extend_from_slice(payload),
+        Some(key) => extend_masked(payload, key, out),
+    }
+}
+
+/// Unmasks `payload` where it is, eight bytes at a time like
+/// `unmask_into`.
+pub fn unmask_in_place(payload: &mut [u8], key: [u8; 4]) {
+    let mut key8 = [0; 8];
+    key8[..4].copy_from_slice(&key);
+    key8[4..].copy_from_slice(&key);
+    let key8 = u64::from_ne_bytes(key8);
+
+    let mut chunks = payload.chunks_exact_mut(8);
+
+    for chunk in &mut chunks {
+        let word = u64::from_ne_bytes((*chunk).try_into().unwrap()) ^ key8;
+        chunk.copy_from_slice(&word.to_ne_bytes());
+    }
+
+    for (byte, key_byte) in chunks.into_remainder().iter_mut().zip(key.iter().cycle()) {
+        *byte ^= key_byte;
+    }
+}
+
+/// Appends `payload` XORed with the repeating `key` to `out`. Masking and
+/// unmasking are the same operation. This works eight bytes at a time,
+/// since copying and XORing a byte at a time is slow for big messages.
+fn extend_masked(payload: &[u8], key: [u8; 4], out: &mut Vec<u8>) {
+    out.reserve(payload.len());
+
+    // Each chunk starts at a multiple of eight, so it lines up with the key
+    // repeated twice
+    let mut key8 = [0; 8];
+    key8[..4].copy_from_slice(&key);
+    key8[4..].copy_from_slice(&key);
+    let key8 = u64::from_ne_bytes(key8);
+
+    let mut chunks = payload.chunks_exact(8);
+
+    for chunk in &mut chunks {
+        let word = u64::from_ne_bytes(chunk.try_into().unwrap()) ^ key8;
+        out.extend_from_slice(&word.to_ne_bytes());
+    }
+
+    for (byte, key_byte) in chunks.remainder().iter().zip(key.iter().cycle()) {
+        out.push(byte ^ key_byte);
+    }
+}
+
+/// Unmasks the payload of the frame at the start of `frame_buf` and appends
+/// it to `message`, returning the length of the frame. Returns `Ok(None)` if
+/// the frame hasn't fully arrived yet.
+///
+/// This only accepts single, masked, binary frames. `WsConnection` handles
+/// everything else the protocol allows.
+pub fn parse_ws_frame(
+    frame_buf: &[u8],
+    message: &mut Vec<u8>,
+) -> Result<Option<usize>, &'static str> {
+    let header = match parse_frame_header(frame_buf)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    if !header.fin {
+        return Err("FIN bit not 1, payload is fragmented which we don't support");
+    }
+
+    if header.opcode != opcode::BINARY {
+        return Err("Opcode field is not 0x2 which means the message is not binary");
+    }
+
+    if header.mask.is_none() {
+        return Err("Mask bit not 1, should never happen");
+    }
+
+    let frame_len = header.frame_len();
+
+    if frame_buf.len() < frame_len {
+        return Ok(None);
+    }
+
+    unmask_into(
+        &frame_buf[header.header_len..frame_len],
+        header.mask,
+        message,
+    );
+
+    Ok(Some(frame_len))
+}
+
+/// Appends a frame to `frame`. If there is a `mask`, the payload is masked
+/// with it as it is copied in, which clients must do for every frame.
+pub fn write_frame(
+    fin: bool,
+    opcode: u8,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+    frame: &mut Vec<u8>,
+) {
+    write_frame_with_rsv(fin, 0, opcode, payload, mask, frame);
+}
+
+/// Like `write_frame`, with the RSV bits an extension asked for, in the low
+/// three bits of `rsv`.
+pub fn write_frame_with_rsv(
+    fin: bool,
+    rsv: u8,
+    opcode: u8,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+    frame: &mut Vec<u8>,
+) {
+    frame.push(((fin as u8) << 7) | ((rsv & 0b111) << 4) | opcode);
+
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+
+    // Push payload length
+    if payload.len() <= 125 {
+        frame.push(mask_bit | payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        frame.push(mask_bit | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(mask_bit | 127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+
+    match mask {
+        None => frame.extend_from_slice(payload),
+        Some(key) => {
+            frame.extend_from_slice(&key);
+            extend_masked(payload, key, frame);
+        }
+    }
+}
+
+/// Appends a binary frame carrying `message` to `frame`, masked if there
+/// is a `mask`.
+pub fn write_ws_frame(message: &[u8], mask: Option<[u8; 4]>, frame: &mut Vec<u8>) {
+    write_frame(true, opcode::BINARY, message, mask, frame);
+}
+
+/// Appends a close frame with a status code and reason to `frame`. The
+/// reason is cut short if it doesn't fit in a control frame.
+pub fn write_close_frame(code: u16, reason: &[u8], frame: &mut Vec<u8>) {
+    let reason = fit_close_reason(reason);
+
+    let mut payload = [0; 125];
+    payload[..2].copy_from_slice(&code.to_be_bytes());
+    payload[2..2 + reason.len()].copy_from_slice(reason);
+
+    write_frame(
+        true,
+        opcode::CLOSE,
+        &payload[..2 + reason.len()],
+        None,
+        frame,
+    );
+}
+
+/// Whether `code` may go in a close frame. RFC 6455 keeps some codes, like
+/// 1005 and 1006, for reporting a close locally, and leaves others
+/// unassigned, so a peer that sends one is broken.
+pub fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// As much of `reason` as fits in a close frame. It is cut before any
+/// character that would be split, since half a character isn't valid UTF-8
+/// and the peer would fail the connection with 1007 instead.
+pub(crate) fn fit_close_reason(reason: &[u8]) -> &[u8] {
+    let mut len = reason.len().min(123);
+
+    // UTF-8 continuation bytes all start with 0b10. A reason that is
+    // nothing else isn't UTF-8 anyway, and is cut to nothing
+    while len > 0 && len < reason.len() && reason[len] & 0xC0 == 0x80 {
+        len -= 1;
+    }
+    &reason[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::vec;
+
+    #[test]
+    fn parse_short() {
+        let buf = vec![
+            0b1000_0010, // FIN bit 1, opcode type binary (0x2)
+            0b1000_0101, // Mask bit 1, length 5
+            // 4 byte masking key (random)
+            0x12,
+            0x34,
+            0xab,
+            0xcd,
+            b'H' ^ 0x12,
+            b'e' ^ 0x34,
+            b'l' ^ 0xab,
+            b'l' ^ 0xcd,
+            b'o' ^ 0x12,
+        ];
+
+        let mut message = vec![];
+        assert_eq!(parse_ws_frame(&buf, &mut message), Ok(Some(11)));
+        assert_eq!(message, b"Hello");
+    }
+
+    #[test]
+    fn parse_incomplete() {
+        // Same frame as above with the last payload byte missing
+        let buf = vec![
+            0b1000_0010,
+            0b1000_0101,
+            0x12,
+            0x34,
+            0xab,
+            0xcd,
+            b'H' ^ 0x12,
+            b'e' ^ 0x34,
+            b'l' ^ 0xab,
+            b'l' ^ 0xcd,
+        ];
+
+        let mut message = vec![];
+        assert_eq!(parse_ws_frame(&buf, &mut message), Ok(None));
+        assert_eq!(parse_ws_frame(&buf[..1], &mut message), Ok(None));
+    }
+
+    #[test]
+    fn write_lengths() {
+        for len in [0, 125, 126, 65535, 65536] {
+            let payload = vec![7; len];
+            let mut frame = vec![];
+            write_ws_frame(&payload, None, &mut frame);
+
+            let header = parse_frame_header(&frame).unwrap().unwrap();
+            assert_eq!(header.payload_len, len);
+            assert_eq!(header.frame_len(), frame.len());
+            assert_eq!(header.mask, None);
+        }
+    }
+
+    #[test]
+    fn masked_round_trip() {
+        let key = [0x12, 0x34, 0xab, 0xcd];
+
+        for len in [0, 3, 8, 13, 126, 70000] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut frame = vec![];
+            write_ws_frame(&payload, Some(key), &mut frame);
+
+            let header = parse_frame_header(&frame).unwrap().unwrap();
+            assert_eq!(header.mask, Some(key));
+
+            let masked: Vec<u8> = payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ key[i % 4])
+                .collect();
+            assert_eq!(frame[header.header_len..], masked);
+
+            let mut message = vec![];
+            assert_eq!(parse_ws_frame(&frame, &mut message), Ok(Some(frame.len())));
+            assert_eq!(message, payload);
+
+            let mut in_place = frame[header.header_len..].to_vec();
+            unmask_in_place(&mut in_place, key);
+            assert_eq!(in_place, payload);
+        }
+    }
+}
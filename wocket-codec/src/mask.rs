@@ -0,0 +1,51 @@
+//! Sources of the masking keys clients put on every frame they send.
+//!
+//! Keys should be unpredictable to whatever runs on the client, since
+//! predictable keys let scripts line up frame bytes to poison proxy caches.
+//! `SeededRng` is fine for that as long as its seed is random, and gives the
+//! same keys every time for a given seed, which test fixtures want. Devices
+//! with a hardware RNG can plug it in as a closure.
+
+use core::fmt;
+
+/// Something that makes masking keys.
+pub trait MaskRng {
+    fn next_mask(&mut self) -> [u8; 4];
+}
+
+impl fmt::Debug for dyn MaskRng + Send {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MaskRng")
+    }
+}
+
+/// Any `FnMut() -> u32`, such as a hardware RNG's read function, can make
+/// masking keys.
+impl<F: FnMut() -> u32> MaskRng for F {
+    fn next_mask(&mut self) -> [u8; 4] {
+        self().to_be_bytes()
+    }
+}
+
+/// Xorshift64 from a seed.
+#[derive(Debug, Clone)]
+pub struct SeededRng(u64);
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        // Xorshift gets stuck at zero
+        SeededRng(seed | 1)
+    }
+}
+
+impl MaskRng for SeededRng {
+    fn next_mask(&mut self) -> [u8; 4] {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+
+        ((x >> 32) as u32).to_be_bytes()
+    }
+}
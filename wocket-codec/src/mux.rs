@@ -0,0 +1,239 @@
+//! Independent logical channels carried over one WebSocket connection.
+//!
+//! Every mux message is a single binary WebSocket message. It starts with a
+//! kind byte and the channel ID as a 4 byte big-endian number:
+//!
+//! - `DATA`, followed by the payload.
+//! - `OPEN`, which opens the channel.
+//! - `CLOSE`, which closes it. Nothing more is sent on it in either
+//!   direction, and data already on its way is dropped.
+//! - `CREDIT`, followed by a 4 byte big-endian count of further bytes the
+//!   sender of the credit is willing to receive on the channel.
+//!
+//! Flow control is per channel. Each end may send `INITIAL_WINDOW` bytes of
+//! data on a new channel, and after that only as much as the other end has
+//! granted with `CREDIT`, so a slow stream doesn't hold up the others.
+//! Clients open odd channel IDs and servers even ones, so both ends can
+//! open channels without their IDs clashing.
+//!
+//! Like `WsConnection`, `Mux` doesn't do any IO: its methods return the
+//! messages to send, and `receive` takes messages that arrived.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use crate::connection::Role;
+
+/// Bytes of data either end may send on a channel before it is granted
+/// more.
+pub const INITIAL_WINDOW: u32 = 64 * 1024;
+
+/// The first byte of each mux message.
+pub mod kind {
+    pub const DATA: u8 = 0;
+    pub const OPEN: u8 = 1;
+    pub const CLOSE: u8 = 2;
+    pub const CREDIT: u8 = 3;
+}
+
+/// A mux message received from the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MuxEvent<'a> {
+    /// The peer opened a channel.
+    Opened(u32),
+
+    Data(u32, &'a [u8]),
+
+    /// The peer closed a channel.
+    Closed(u32),
+
+    /// The peer will take this many more bytes on a channel.
+    Credit(u32, u32),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    /// Bytes we may still send.
+    send_window: u32,
+
+    /// Bytes the peer may still send.
+    receive_window: u32,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Channel {
+            send_window: INITIAL_WINDOW,
+            receive_window: INITIAL_WINDOW,
+        }
+    }
+}
+
+/// One end's view of the channels on a connection.
+#[derive(Debug)]
+pub struct Mux {
+    channels: BTreeMap<u32, Channel>,
+    next_id: u32,
+}
+
+impl Mux {
+    pub fn new(role: Role) -> Self {
+        Mux {
+            channels: BTreeMap::new(),
+            next_id: match role {
+                Role::Client => 1,
+                Role::Server => 2,
+            },
+        }
+    }
+
+    /// Opens a new channel. Returns its ID and the message that tells the
+    /// peer.
+    pub fn open(&mut self) -> (u32, Vec<u8>) {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(2);
+
+        self.channels.insert(id, Channel::default());
+        (id, message(kind::OPEN, id, &[]))
+    }
+
+    /// Encodes `data` to send on `channel`. Fails if the channel isn't
+    /// open, or `data` is bigger than its send window.
+    pub fn send(&mut self, channel: u32, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        let open = self.channels.get_mut(&channel).ok_or("Unknown channel")?;
+
+        let len = u32::try_from(data.len()).map_err(|_| "Channel window exhausted")?;
+        if len > open.send_window {
+            return Err("Channel window exhausted");
+        }
+        open.send_window -= len;
+
+        Ok(message(kind::DATA, channel, data))
+    }
+
+    /// Lets the peer send `bytes` more on `channel`, typically once data
+    /// received on it has been handled.
+    pub fn grant(&mut self, channel: u32, bytes: u32) -> Result<Vec<u8>, &'static str> {
+        let open = self.channels.get_mut(&channel).ok_or("Unknown channel")?;
+        open.receive_window = open.receive_window.saturating_add(bytes);
+
+        Ok(message(kind::CREDIT, channel, &bytes.to_be_bytes()))
+    }
+
+    /// Closes `channel`.
+    pub fn close(&mut self, channel: u32) -> Result<Vec<u8>, &'static str> {
+        self.channels.remove(&channel).ok_or("Unknown channel")?;
+        Ok(message(kind::CLOSE, channel, &[]))
+    }
+
+    /// How many bytes can be sent on `channel` right now, or `None` if it
+    /// isn't open.
+    pub fn send_window(&self, channel: u32) -> Option<u32> {
+        self.channels.get(&channel).map(|open| open.send_window)
+    }
+
+    /// Handles a mux message from the peer. Returns `Ok(None)` for messages
+    /// on channels that have already been closed.
+    pub fn receive<'a>(&mut self, message: &'a [u8]) -> Result<Option<MuxEvent<'a>>, &'static str> {
+        if message.len() < 5 {
+            return Err("Mux message too short");
+        }
+
+        let channel = u32::from_be_bytes([message[1], message[2], message[3], message[4]]);
+        let body = &message[5..];
+
+        if message[0] == kind::OPEN {
+            if self.channels.contains_key(&channel) {
+                return Err("Channel already open");
+            }
+            self.channels.insert(channel, Channel::default());
+            return Ok(Some(MuxEvent::Opened(channel)));
+        }
+
+        let Some(open) = self.channels.get_mut(&channel) else {
+            return match message[0] {
+                kind::DATA | kind::CLOSE | kind::CREDIT => Ok(None),
+                _ => Err("Unknown mux message kind"),
+            };
+        };
+
+        match message[0] {
+            kind::DATA => {
+                let len = u32::try_from(body.len()).map_err(|_| "Channel window exceeded")?;
+                if len > open.receive_window {
+                    return Err("Channel window exceeded");
+                }
+                open.receive_window -= len;
+
+                Ok(Some(MuxEvent::Data(channel, body)))
+            }
+            kind::CLOSE => {
+                self.channels.remove(&channel);
+                Ok(Some(MuxEvent::Closed(channel)))
+            }
+            kind::CREDIT => {
+                let bytes: [u8; 4] = body.try_into().map_err(|_| "Invalid credit message")?;
+                let bytes = u32::from_be_bytes(bytes);
+                open.send_window = open.send_window.saturating_add(bytes);
+
+                Ok(Some(MuxEvent::Credit(channel, bytes)))
+            }
+            _ => Err("Unknown mux message kind"),
+        }
+    }
+}
+
+fn message(kind: u8, channel: u32, body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(5 + body.len());
+    message.push(kind);
+    message.extend_from_slice(&channel.to_be_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_is_limited_by_credit() {
+        let mut client = Mux::new(Role::Client);
+        let mut server = Mux::new(Role::Server);
+
+        let (id, open) = client.open();
+        assert_eq!(server.receive(&open), Ok(Some(MuxEvent::Opened(id))));
+
+        let big = alloc::vec![0; INITIAL_WINDOW as usize];
+        let data = client.send(id, &big).unwrap();
+        assert_eq!(client.send(id, b"more"), Err("Channel window exhausted"));
+        assert_eq!(
+            server.receive(&data),
+            Ok(Some(MuxEvent::Data(id, &big[..])))
+        );
+
+        let credit = server.grant(id, 4).unwrap();
+        assert_eq!(client.receive(&credit), Ok(Some(MuxEvent::Credit(id, 4))));
+        let data = client.send(id, b"more").unwrap();
+        assert_eq!(
+            server.receive(&data),
+            Ok(Some(MuxEvent::Data(id, &b"more"[..])))
+        );
+    }
+
+    #[test]
+    fn closed_channels_drop_late_data() {
+        let mut client = Mux::new(Role::Client);
+        let mut server = Mux::new(Role::Server);
+
+        let (id, open) = client.open();
+        server.receive(&open).unwrap();
+        let (server_id, _) = server.open();
+        assert_ne!(id, server_id);
+
+        let data = server.send(id, b"late").unwrap();
+        let close = client.close(id).unwrap();
+        assert_eq!(client.receive(&data), Ok(None));
+        assert_eq!(server.receive(&close), Ok(Some(MuxEvent::Closed(id))));
+        assert_eq!(server.send_window(id), None);
+    }
+}
@@ -0,0 +1,49 @@
+/* C bindings for the wocket WebSocket client. Link against the
+ * wocket_ffi static or shared library. */
+
+#ifndef WOCKET_H
+#define WOCKET_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define WOCKET_TEXT 1
+#define WOCKET_BINARY 2
+
+#define WOCKET_OK 0
+#define WOCKET_CLOSED 1
+#define WOCKET_ERROR -1
+
+typedef struct WocketClient WocketClient;
+
+/* `data` and `reason` are only valid during the call, and `reason` is not
+ * NUL terminated. `code` is 1005 if the server didn't send one. */
+typedef void (*wocket_message_cb)(void *user_data, int kind, const uint8_t *data, size_t len);
+typedef void (*wocket_close_cb)(void *user_data, uint16_t code, const uint8_t *reason, size_t len);
+
+/* Returns NULL if the connection or handshake fails. */
+WocketClient *wocket_connect(const char *url);
+
+void wocket_set_callbacks(WocketClient *client, wocket_message_cb on_message,
+                          wocket_close_cb on_close, void *user_data);
+
+int wocket_send(WocketClient *client, int kind, const uint8_t *data, size_t len);
+
+/* Blocks until a message arrives and passes it to the message callback.
+ * Returns WOCKET_OK, WOCKET_CLOSED or WOCKET_ERROR. */
+int wocket_receive(WocketClient *client);
+
+/* Waits a few seconds for the server's reply. `reason` may be NULL. */
+int wocket_close(WocketClient *client, uint16_t code, const char *reason);
+
+void wocket_free(WocketClient *client);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,280 @@
+//! C bindings for the blocking client in `wocket::sync`, so C and C++
+//! programs can embed it. The matching header is `include/wocket.h`.
+//!
+//! A client is created with `wocket_connect` and freed with `wocket_free`.
+//! Messages are delivered by calling `wocket_receive` from the thread that
+//! owns the client, which blocks until something arrives and passes it to
+//! the registered callbacks.
+
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::ptr;
+use std::slice;
+
+use wocket::sync::{self, WebSocket};
+use wocket::{Error, Message};
+
+pub const WOCKET_TEXT: c_int = 1;
+pub const WOCKET_BINARY: c_int = 2;
+
+pub const WOCKET_OK: c_int = 0;
+pub const WOCKET_CLOSED: c_int = 1;
+pub const WOCKET_ERROR: c_int = -1;
+
+/// Called with each message received. `kind` is `WOCKET_TEXT` or
+/// `WOCKET_BINARY`, and `data` is only valid during the call.
+pub type MessageCallback =
+    extern "C" fn(user_data: *mut c_void, kind: c_int, data: *const u8, len: usize);
+
+/// Called once the peer has closed the connection, with its status code,
+/// or 1005 (No Status Received) if it didn't send one, and its UTF-8
+/// reason. `reason` is not NUL terminated and is only valid during the
+/// call.
+pub type CloseCallback =
+    extern "C" fn(user_data: *mut c_void, code: u16, reason: *const u8, len: usize);
+
+pub struct WocketClient {
+    socket: WebSocket,
+    on_message: Option<MessageCallback>,
+    on_close: Option<CloseCallback>,
+    user_data: *mut c_void,
+}
+
+/// Connects to a `ws://` URL. Returns null if the URL is invalid or the
+/// connection or handshake fails.
+///
+/// # Safety
+///
+/// `url` must be a valid, NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn wocket_connect(url: *const c_char) -> *mut WocketClient {
+    if url.is_null() {
+        return ptr::null_mut();
+    }
+
+    let Ok(url) = CStr::from_ptr(url).to_str() else {
+        return ptr::null_mut();
+    };
+
+    match sync::connect(url) {
+        Ok(socket) => Box::into_raw(Box::new(WocketClient {
+            socket,
+            on_message: None,
+            on_close: None,
+            user_data: ptr::null_mut(),
+        })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Registers the callbacks `wocket_receive` delivers to. Either may be null.
+/// `user_data` is passed back to them untouched.
+///
+/// # Safety
+///
+/// `client` must have come from `wocket_connect` and not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn wocket_set_callbacks(
+    client: *mut WocketClient,
+    on_message: Option<MessageCallback>,
+    on_close: Option<CloseCallback>,
+    user_data: *mut c_void,
+) {
+    if let Some(client) = client.as_mut() {
+        client.on_message = on_message;
+        client.on_close = on_close;
+        client.user_data = user_data;
+    }
+}
+
+/// Sends a message of `kind` `WOCKET_TEXT` or `WOCKET_BINARY`. Text must be
+/// valid UTF-8. Returns `WOCKET_OK` or `WOCKET_ERROR`.
+///
+/// # Safety
+///
+/// `client` must have come from `wocket_connect` and not been freed, and
+/// `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wocket_send(
+    client: *mut WocketClient,
+    kind: c_int,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    let Some(client) = client.as_mut() else {
+        return WOCKET_ERROR;
+    };
+
+    let data = if len == 0 {
+        &[]
+    } else if data.is_null() {
+        return WOCKET_ERROR;
+    } else {
+        slice::from_raw_parts(data, len)
+    };
+
+    let message = match kind {
+        WOCKET_TEXT => match std::str::from_utf8(data) {
+            Ok(text) => Message::Text(String::from(text)),
+            Err(_) => return WOCKET_ERROR,
+        },
+        WOCKET_BINARY => Message::Binary(data.to_vec()),
+        _ => return WOCKET_ERROR,
+    };
+
+    status(client.socket.send(message).is_ok())
+}
+
+/// Blocks until a message arrives and passes it to the message callback.
+/// Pings are answered automatically and don't count. Returns `WOCKET_OK`
+/// after a message, `WOCKET_CLOSED` once the close handshake has finished,
+/// after calling the close callback if the server started it, or
+/// `WOCKET_ERROR`.
+///
+/// # Safety
+///
+/// `client` must have come from `wocket_connect` and not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn wocket_receive(client: *mut WocketClient) -> c_int {
+    let Some(client) = client.as_mut() else {
+        return WOCKET_ERROR;
+    };
+
+    loop {
+        let (kind, data) = match client.socket.read() {
+            Ok(Message::Text(text)) => (WOCKET_TEXT, text.into_bytes()),
+            Ok(Message::Binary(data)) => (WOCKET_BINARY, data),
+            Ok(Message::Ping(_) | Message::Pong(_)) => continue,
+            Ok(Message::Close(frame)) => {
+                if let Some(on_close) = client.on_close {
+                    let (code, reason) = match &frame {
+                        Some(frame) => (frame.code, frame.reason.as_str()),
+                        None => (1005, ""),
+                    };
+                    on_close(client.user_data, code, reason.as_ptr(), reason.len());
+                }
+                return WOCKET_CLOSED;
+            }
+            Err(Error::Closed) => return WOCKET_CLOSED,
+            Err(_) => return WOCKET_ERROR,
+        };
+
+        if let Some(on_message) = client.on_message {
+            on_message(client.user_data, kind, data.as_ptr(), data.len());
+        }
+        return WOCKET_OK;
+    }
+}
+
+/// Sends a close frame and waits a few seconds for the server's reply,
+/// dropping any messages that arrive first. After this, `wocket_receive`
+/// returns `WOCKET_CLOSED`. `reason` may be null.
+///
+/// # Safety
+///
+/// `client` must have come from `wocket_connect` and not been freed, and
+/// `reason` must be null or a valid, NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn wocket_close(
+    client: *mut WocketClient,
+    code: u16,
+    reason: *const c_char,
+) -> c_int {
+    let Some(client) = client.as_mut() else {
+        return WOCKET_ERROR;
+    };
+
+    let reason = if reason.is_null() {
+        ""
+    } else {
+        match CStr::from_ptr(reason).to_str() {
+            Ok(reason) => reason,
+            Err(_) => return WOCKET_ERROR,
+        }
+    };
+
+    status(client.socket.close(code, reason).is_ok())
+}
+
+/// Drops the connection and frees the client. Null is ignored.
+///
+/// # Safety
+///
+/// `client` must have come from `wocket_connect`, and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn wocket_free(client: *mut WocketClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+fn status(ok: bool) -> c_int {
+    if ok {
+        WOCKET_OK
+    } else {
+        WOCKET_ERROR
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::CString;
+    use std::net::TcpListener;
+    use std::thread;
+
+    extern "C" fn collect(user_data: *mut c_void, kind: c_int, data: *const u8, len: usize) {
+        let received = unsafe { &mut *(user_data as *mut Vec<(c_int, Vec<u8>)>) };
+        received.push((kind, unsafe { slice::from_raw_parts(data, len) }.to_vec()));
+    }
+
+    #[test]
+    fn echo_through_the_c_api() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = sync::accept(stream).unwrap();
+
+            loop {
+                match socket.read().unwrap() {
+                    Message::Close(_) => break,
+                    message => socket.send(message).unwrap(),
+                }
+            }
+        });
+
+        let url = CString::new(format!("ws://{addr}/")).unwrap();
+        let mut received: Vec<(c_int, Vec<u8>)> = vec![];
+
+        unsafe {
+            let client = wocket_connect(url.as_ptr());
+            assert!(!client.is_null());
+
+            let user_data = &mut received as *mut _ as *mut c_void;
+            wocket_set_callbacks(client, Some(collect), None, user_data);
+
+            assert_eq!(
+                wocket_send(client, WOCKET_TEXT, b"hi".as_ptr(), 2),
+                WOCKET_OK
+            );
+            assert_eq!(wocket_receive(client), WOCKET_OK);
+            assert_eq!(
+                wocket_send(client, WOCKET_BINARY, [1, 2].as_ptr(), 2),
+                WOCKET_OK
+            );
+            assert_eq!(wocket_receive(client), WOCKET_OK);
+
+            assert_eq!(wocket_close(client, 1000, ptr::null()), WOCKET_OK);
+            assert_eq!(wocket_receive(client), WOCKET_CLOSED);
+            wocket_free(client);
+        }
+
+        assert_eq!(
+            received,
+            vec![(WOCKET_TEXT, b"hi".to_vec()), (WOCKET_BINARY, vec![1, 2])]
+        );
+        server.join().unwrap();
+    }
+}